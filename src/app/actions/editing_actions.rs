@@ -11,17 +11,21 @@ pub enum EditingAction {
     RemoveChar,
     Validate,
     Dismiss,
+    NextField,
+    ForgotMasterKey,
     WriteChar,
 }
 
 impl EditingAction {
     /// All available EditingActions
     pub fn iterator() -> Iter<'static, EditingAction> {
-        static EDITING_ACTIONS: [EditingAction; 4] = [
+        static EDITING_ACTIONS: [EditingAction; 6] = [
             EditingAction::Quit,
             EditingAction::RemoveChar,
             EditingAction::Validate,
             EditingAction::Dismiss,
+            EditingAction::NextField,
+            EditingAction::ForgotMasterKey,
         ];
         EDITING_ACTIONS.iter()
     }
@@ -33,6 +37,8 @@ impl EditingAction {
             EditingAction::RemoveChar => &[Key::Backspace],
             EditingAction::Validate => &[Key::Enter],
             EditingAction::Dismiss => &[Key::Esc],
+            EditingAction::NextField => &[Key::Tab],
+            EditingAction::ForgotMasterKey => &[Key::Ctrl('r')],
             _ => &[Key::Null], // EditingAction::WriteChar => &[Key::Null],
         }
     }
@@ -44,6 +50,8 @@ impl Display for EditingAction {
         let str = match self {
             EditingAction::Quit => "Quit",
             EditingAction::Dismiss => "Dismiss",
+            EditingAction::NextField => "Next field",
+            EditingAction::ForgotMasterKey => "Forgot the master key",
             _ => "",
         };
         write!(f, "{str}")
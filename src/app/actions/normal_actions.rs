@@ -13,18 +13,46 @@ pub enum Action {
     MoveDown,
     CreateKey,
     CopyPassword,
+    ChangeMasterKey,
+    ReEncrypt,
+    OpenBackup,
+    Quarantine,
+    NewVault,
+    KeySlots,
+    AddSlot,
+    RevokeSlot,
+    RecoveryKey,
+    NewRecoveryKey,
+    VerifyVault,
+    PasswordHistory,
+    RestorePassword,
+    EditKey,
 }
 
 impl Action {
     /// All available actions
     pub fn iterator() -> Iter<'static, Action> {
-        static ACTIONS: [Action; 6] = [
+        static ACTIONS: [Action; 20] = [
             Action::Quit,
             Action::Help,
             Action::MoveUp,
             Action::MoveDown,
             Action::CreateKey,
             Action::CopyPassword,
+            Action::ChangeMasterKey,
+            Action::ReEncrypt,
+            Action::OpenBackup,
+            Action::Quarantine,
+            Action::NewVault,
+            Action::KeySlots,
+            Action::AddSlot,
+            Action::RevokeSlot,
+            Action::RecoveryKey,
+            Action::NewRecoveryKey,
+            Action::VerifyVault,
+            Action::PasswordHistory,
+            Action::RestorePassword,
+            Action::EditKey,
         ];
         ACTIONS.iter()
     }
@@ -38,6 +66,20 @@ impl Action {
             Action::CreateKey => &[Key::Char('c')],
             Action::CopyPassword => &[Key::Char('y')],
             Action::Help => &[Key::Char('?')],
+            Action::ChangeMasterKey => &[Key::Char('m')],
+            Action::ReEncrypt => &[Key::Char('e')],
+            Action::OpenBackup => &[Key::Char('b')],
+            Action::Quarantine => &[Key::Char('x')],
+            Action::NewVault => &[Key::Char('n')],
+            Action::KeySlots => &[Key::Char('s')],
+            Action::AddSlot => &[Key::Char('a')],
+            Action::RevokeSlot => &[Key::Char('r')],
+            Action::RecoveryKey => &[Key::Char('g')],
+            Action::NewRecoveryKey => &[Key::Char('n')],
+            Action::VerifyVault => &[Key::Char('v')],
+            Action::PasswordHistory => &[Key::Char('h')],
+            Action::RestorePassword => &[Key::Char('r')],
+            Action::EditKey => &[Key::Char('e')],
         }
     }
 }
@@ -52,6 +94,20 @@ impl Display for Action {
             Action::MoveDown => "Move down",
             Action::CopyPassword => "Copy password",
             Action::CreateKey => "Create a new key",
+            Action::ChangeMasterKey => "Change the master key",
+            Action::ReEncrypt => "Re-encrypt the plaintext database",
+            Action::OpenBackup => "Open the latest backup",
+            Action::Quarantine => "Quarantine the plaintext database",
+            Action::NewVault => "Start a new vault",
+            Action::KeySlots => "Manage the key slots",
+            Action::AddSlot => "Add a key slot",
+            Action::RevokeSlot => "Revoke the selected key slot",
+            Action::RecoveryKey => "Recovery key",
+            Action::NewRecoveryKey => "Generate a new recovery key",
+            Action::VerifyVault => "Verify the vault and its backups",
+            Action::PasswordHistory => "Previous passwords of the selected key",
+            Action::RestorePassword => "Restore the selected password",
+            Action::EditKey => "Edit the selected key",
         };
         write!(f, "{str}")
     }
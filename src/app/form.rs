@@ -0,0 +1,168 @@
+use zeroize::Zeroize;
+
+use super::INPUT_CAPACITY;
+use crate::models::key::Key;
+
+/// A single text input of a form
+pub struct Field {
+    label: &'static str,
+    value: String,
+    masked: bool,
+    button: bool,
+}
+
+impl Field {
+    pub fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            value: String::with_capacity(INPUT_CAPACITY),
+            masked: false,
+            button: false,
+        }
+    }
+
+    /// A field holding `value` until it is edited
+    pub fn filled(label: &'static str, value: &str) -> Self {
+        let mut field = Self::new(label);
+        field.value.push_str(value);
+        field
+    }
+
+    /// A button, validating the form with it does what its label says
+    pub fn button(label: &'static str) -> Self {
+        let mut field = Self::new(label);
+        field.button = true;
+        field
+    }
+
+    /// A field whose content is never displayed
+    pub fn masked(label: &'static str) -> Self {
+        let mut field = Self::new(label);
+        field.masked = true;
+        field
+    }
+
+    pub fn label(&self) -> &str {
+        self.label
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_button(&self) -> bool {
+        self.button
+    }
+
+    /// The value as it should be displayed
+    pub fn display_value(&self) -> String {
+        if self.masked {
+            self.value.chars().map(|_| '*').collect()
+        } else {
+            self.value.clone()
+        }
+    }
+}
+
+/// Fields may hold master keys, so they are all wiped
+impl Drop for Field {
+    fn drop(&mut self) {
+        self.value.zeroize();
+    }
+}
+
+/// Several fields edited one at a time
+pub struct Form {
+    fields: Vec<Field>,
+    focus: usize,
+}
+
+impl Form {
+    pub fn new(fields: Vec<Field>) -> Self {
+        Self { fields, focus: 0 }
+    }
+
+    /// Form used to unlock a vault bound to a keyfile
+    pub fn unlock() -> Self {
+        Self::new(vec![Field::masked("Master key"), Field::new("Keyfile")])
+    }
+
+    /// Form used to change the master key
+    pub fn master_key_change() -> Self {
+        Self::new(vec![
+            Field::masked("Current master key"),
+            Field::masked("New master key"),
+            Field::masked("Confirm new master key"),
+        ])
+    }
+
+    /// Form used to open the vault with its recovery key, which sets a new
+    /// master key at the same time
+    pub fn recovery_key() -> Self {
+        Self::new(vec![
+            Field::new("Recovery key or shares"),
+            Field::masked("New master key"),
+            Field::masked("Confirm new master key"),
+        ])
+    }
+
+    /// Form used to add a key slot
+    pub fn key_slot() -> Self {
+        Self::new(vec![
+            Field::new("Label"),
+            Field::masked("Passphrase"),
+            Field::masked("Confirm passphrase"),
+        ])
+    }
+
+    /// Form used to edit a key, its password is kept unless a new one is
+    /// typed or generated
+    pub fn edit_key(key: &Key) -> Self {
+        Self::new(vec![
+            Field::filled("Name", key.name()),
+            Field::filled("Username", key.username()),
+            Field::filled("URLs", &key.urls().join(" ")),
+            Field::filled("Notes", key.notes()),
+            Field::masked("New password"),
+            Field::masked("Confirm new password"),
+            Field::button("Regenerate the password"),
+        ])
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn focus(&self) -> usize {
+        self.focus
+    }
+
+    pub fn value(&self, index: usize) -> &str {
+        self.fields[index].value()
+    }
+
+    /// The button is focused, there is nothing to type in
+    pub fn is_button_focused(&self) -> bool {
+        self.fields[self.focus].is_button()
+    }
+
+    /// The value of the field currently edited
+    pub fn input(&mut self) -> &mut String {
+        &mut self.fields[self.focus].value
+    }
+
+    /// Wipe the value of a field
+    pub fn clear(&mut self, index: usize) {
+        self.fields[index].value.zeroize();
+    }
+
+    pub fn next_field(&mut self) {
+        self.focus = (self.focus + 1) % self.fields.len();
+    }
+}
+
+impl Default for Form {
+    fn default() -> Self {
+        Self::new(vec![Field::new("")])
+    }
+}
@@ -1,18 +1,35 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
 use arboard::Clipboard;
 use log::{debug, error, info};
+use zeroize::Zeroize;
 
 use self::actions::editing_actions::EditingActions;
 use self::actions::normal_actions::Actions;
-use self::state::{AppData, AppState};
+use self::form::Form;
+use self::state::{AppData, AppState, StatefulList};
 use crate::app::actions::editing_actions::EditingAction;
 use crate::app::actions::normal_actions::Action;
+use crate::crypto::error::UnlockError;
+use crate::crypto::secret::SecretString;
 use crate::inputs::key::Key;
-use crate::io::IoEvent;
+use crate::io::{IoEvent, KeyEdit, PasswordChange, Recovery};
+use crate::storage::recovery::VaultIssue;
 
 pub mod actions;
+pub mod form;
 pub mod state;
 pub mod ui;
 
+/// Room reserved for the inputs, so typing a master key never leaves copies
+/// of it behind a reallocation
+const INPUT_CAPACITY: usize = 256;
+/// Wrong master keys allowed before having to wait between attempts
+const FREE_ATTEMPTS: u32 = 3;
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum AppReturn {
     Exit,
@@ -34,27 +51,30 @@ pub struct App {
     state: AppState,
     input_mode: InputMode,
     input_buffer: String,
+    form: Form,
+    lock_timeout: Option<Duration>,
+    last_activity: Instant,
+    unlock_error: Option<String>,
+    failed_attempts: u32,
+    retry_at: Option<Instant>,
+    keyfile_required: bool,
+    recovering: bool,
     pub data: AppData,
     pub clipboard: Clipboard,
 }
 
 impl App {
-    pub fn new(io_tx: tokio::sync::mpsc::Sender<IoEvent>) -> Self {
+    pub fn new(io_tx: tokio::sync::mpsc::Sender<IoEvent>, lock_timeout: Option<Duration>) -> Self {
         let actions = vec![Action::Quit].into();
-        let editing_actions = vec![
-            EditingAction::Quit,
-            EditingAction::RemoveChar,
-            EditingAction::Dismiss,
-            EditingAction::Validate,
-            EditingAction::WriteChar,
-        ]
-        .into();
+        let editing_actions = unlock_editing_actions();
         let is_loading = false;
         let state = AppState::default();
         let data = AppData::default();
         let input_mode = InputMode::Normal;
-        let input_buffer = String::new();
+        let input_buffer = String::with_capacity(INPUT_CAPACITY);
+        let form = Form::default();
         let clipboard = Clipboard::new().unwrap();
+        let last_activity = Instant::now();
 
         Self {
             io_tx,
@@ -65,6 +85,14 @@ impl App {
             data,
             input_mode,
             input_buffer,
+            form,
+            lock_timeout,
+            last_activity,
+            unlock_error: None,
+            failed_attempts: 0,
+            retry_at: None,
+            keyfile_required: false,
+            recovering: false,
             clipboard,
         }
     }
@@ -73,12 +101,79 @@ impl App {
         &self.input_buffer
     }
 
+    /// Empty the input buffer, wiping what may be a master key
     pub fn clear_input_buffer(&mut self) {
-        self.input_buffer.clear();
+        self.input_buffer.zeroize();
+        if self.is_unlock_form() {
+            self.form.clear(0);
+        } else if self.is_recovery_key_form() {
+            (0..3).for_each(|index| self.form.clear(index));
+        }
+    }
+
+    /// The master key typed to unlock the vault, and the path of its keyfile
+    /// if the vault is bound to one
+    pub fn unlock_input(&self) -> (SecretString, Option<PathBuf>) {
+        if !self.is_unlock_form() {
+            return (SecretString::from(self.get_input_buffer()), None);
+        }
+
+        let keyfile = Some(self.form.value(1))
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        (SecretString::from(self.form.value(0)), keyfile)
+    }
+
+    /// Ask for the path of a keyfile along with the master key
+    pub fn require_keyfile(&mut self) {
+        self.keyfile_required = true;
+        self.form = Form::unlock();
+        self.editing_actions = unlock_editing_actions();
+    }
+
+    /// The vault is unlocked with a form holding the master key and the
+    /// keyfile, instead of the input buffer
+    pub fn is_unlock_form(&self) -> bool {
+        self.keyfile_required && !self.recovering && matches!(self.state, AppState::Init)
+    }
+
+    /// The vault is opened with its recovery key, and a new master key
+    pub fn is_recovery_key_form(&self) -> bool {
+        self.recovering && matches!(self.state, AppState::Init)
+    }
+
+    /// Back from the recovery key to the master key prompt
+    fn stop_recovering(&mut self) {
+        self.recovering = false;
+        if self.keyfile_required {
+            self.require_keyfile();
+        } else {
+            self.form = Form::default();
+        }
+    }
+
+    /// The text currently edited, either in a form or in the input buffer
+    fn active_input(&mut self) -> &mut String {
+        if self.state.is_master_key_popup()
+            || self.state.is_slot_form()
+            || self.state.is_edit_form()
+            || self.is_unlock_form()
+            || self.is_recovery_key_form()
+        {
+            self.form.input()
+        } else {
+            &mut self.input_buffer
+        }
+    }
+
+    /// A button of a form is focused, typing does nothing
+    fn is_on_button(&self) -> bool {
+        self.state.is_edit_form() && self.form.is_button_focused()
     }
 
     /// Handle a user action
     pub async fn do_action(&mut self, key: Key) -> AppReturn {
+        self.last_activity = Instant::now();
         match self.input_mode {
             InputMode::Normal => match self.actions.find(key) {
                 Some(action) => self.do_normal_action(*action).await,
@@ -102,16 +197,156 @@ impl App {
                 AppReturn::Exit
             }
             EditingAction::RemoveChar => {
-                self.input_buffer.pop();
+                if !self.is_on_button() {
+                    self.active_input().pop();
+                }
+                AppReturn::Continue
+            }
+            EditingAction::Dismiss if self.is_recovery_key_form() => {
+                self.stop_recovering();
                 AppReturn::Continue
             }
             EditingAction::Dismiss => {
                 self.toggle_input_mode();
-                self.state.toggle_creation_popup();
-                self.input_buffer.clear();
+                if self.state.is_recovery_prompt() {
+                    self.state.toggle_recovery_prompt();
+                    self.clear_input_buffer();
+                } else if self.state.is_master_key_popup() {
+                    self.state.toggle_master_key_popup();
+                    self.form = Form::default();
+                } else if self.state.is_slot_form() {
+                    self.state.toggle_slot_form();
+                    self.form = Form::default();
+                } else if self.state.is_edit_form() {
+                    self.state.toggle_edit_form();
+                    self.form = Form::default();
+                } else {
+                    self.state.toggle_creation_popup();
+                    self.clear_input_buffer();
+                }
+                AppReturn::Continue
+            }
+            EditingAction::NextField => {
+                self.form.next_field();
+                AppReturn::Continue
+            }
+            EditingAction::ForgotMasterKey => {
+                if matches!(self.state, AppState::Init) && !self.recovering {
+                    self.recovering = true;
+                    self.unlock_error = None;
+                    self.input_buffer.zeroize();
+                    self.form = Form::recovery_key();
+                }
+                AppReturn::Continue
+            }
+            EditingAction::Validate if self.is_recovery_key_form() => {
+                let recovery_key = SecretString::from(self.form.value(0));
+                let password = SecretString::from(self.form.value(1));
+
+                if recovery_key.expose().trim().is_empty() {
+                    error!("Type the recovery key, or its shares");
+                } else if password.is_empty() {
+                    error!("The new master key cannot be empty");
+                } else if password.expose() != self.form.value(2) {
+                    error!("The new master keys do not match");
+                } else {
+                    self.unlock_error = None;
+                    self.dispatch(IoEvent::UnlockWithRecoveryKey {
+                        recovery_key,
+                        password,
+                    })
+                    .await;
+                }
+
                 AppReturn::Continue
             }
 
+            EditingAction::Validate if self.state.is_master_key_popup() => {
+                let current = SecretString::from(self.form.value(0));
+                let new = SecretString::from(self.form.value(1));
+
+                if new.is_empty() {
+                    error!("The new master key cannot be empty");
+                } else if new.expose() != self.form.value(2) {
+                    error!("The new master keys do not match");
+                } else {
+                    self.dispatch(IoEvent::ChangeMasterKey { current, new })
+                        .await;
+                    self.toggle_input_mode();
+                    self.state.toggle_master_key_popup();
+                    self.form = Form::default();
+                }
+
+                AppReturn::Continue
+            }
+            EditingAction::Validate if self.state.is_slot_form() => {
+                let label = self.form.value(0).trim().to_string();
+                let password = SecretString::from(self.form.value(1));
+
+                if label.is_empty() {
+                    error!("The label of a key slot cannot be empty");
+                } else if password.is_empty() {
+                    error!("The passphrase cannot be empty");
+                } else if password.expose() != self.form.value(2) {
+                    error!("The passphrases do not match");
+                } else {
+                    self.dispatch(IoEvent::AddSlot { label, password }).await;
+                    self.toggle_input_mode();
+                    self.state.toggle_slot_form();
+                    self.form = Form::default();
+                }
+
+                AppReturn::Continue
+            }
+            EditingAction::Validate if self.state.is_edit_form() => {
+                let Some(id) = self.data.selected_key().map(|key| key.id()) else {
+                    error!("The key edited is not selected anymore");
+                    return AppReturn::Continue;
+                };
+                let name = self.form.value(0).trim().to_string();
+                let new = SecretString::from(self.form.value(4));
+
+                let password = if self.form.is_button_focused() {
+                    Some(PasswordChange::Generate)
+                } else if new.is_empty() {
+                    Some(PasswordChange::Keep)
+                } else if new.expose() != self.form.value(5) {
+                    error!("The new passwords do not match");
+                    None
+                } else {
+                    Some(PasswordChange::Set(new))
+                };
+
+                if name.is_empty() {
+                    error!("The name of a key cannot be empty");
+                } else if let Some(password) = password {
+                    let edit = KeyEdit {
+                        id,
+                        name,
+                        username: self.form.value(1).trim().to_string(),
+                        urls: self
+                            .form
+                            .value(2)
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect(),
+                        notes: self.form.value(3).to_string(),
+                        password,
+                    };
+                    self.dispatch(IoEvent::EditKey(edit)).await;
+                    self.toggle_input_mode();
+                    self.state.toggle_edit_form();
+                    self.form = Form::default();
+                }
+
+                AppReturn::Continue
+            }
+            EditingAction::Validate if self.state.is_recovery_prompt() => {
+                let password = SecretString::from(self.input_buffer.as_str());
+                self.dispatch(IoEvent::Recover(Recovery::ReEncrypt(password)))
+                    .await;
+                AppReturn::Continue
+            }
             EditingAction::Validate => {
                 let key = crate::models::key::Key::new(None, self.input_buffer.clone());
 
@@ -120,9 +355,15 @@ impl App {
                     self.dispatch(IoEvent::Refresh).await;
                     self.toggle_input_mode();
                     self.state.toggle_creation_popup();
-                    self.input_buffer.clear();
+                    self.clear_input_buffer();
+                } else if let Some(wait) = self.time_before_retry() {
+                    error!(
+                        "⏳ Too many wrong master keys, try again in {}s",
+                        wait.as_secs() + 1
+                    );
                 } else {
                     debug!("Initialization");
+                    self.unlock_error = None;
                     self.dispatch(IoEvent::Initialize).await;
                     // self.state.set_initialization(self.input_buffer.clone());
                 }
@@ -130,7 +371,9 @@ impl App {
                 AppReturn::Continue
             }
             EditingAction::WriteChar => {
-                self.input_buffer.push(key.to_char());
+                if !self.is_on_button() {
+                    self.active_input().push(key.to_char());
+                }
                 AppReturn::Continue
             } // _ => {
               //     warn!("No action accociated to {}", key);
@@ -150,10 +393,30 @@ impl App {
                 self.state.toggle_help();
                 AppReturn::Continue
             }
+            Action::MoveUp if self.state.is_history_popup() => {
+                if !self.data.history.items.is_empty() {
+                    self.data.history.previous();
+                }
+                AppReturn::Continue
+            }
+            Action::MoveUp if self.state.is_slots_popup() => {
+                self.data.slots.previous();
+                AppReturn::Continue
+            }
             Action::MoveUp => {
                 self.data.keys.previous();
                 AppReturn::Continue
             }
+            Action::MoveDown if self.state.is_history_popup() => {
+                if !self.data.history.items.is_empty() {
+                    self.data.history.next();
+                }
+                AppReturn::Continue
+            }
+            Action::MoveDown if self.state.is_slots_popup() => {
+                self.data.slots.next();
+                AppReturn::Continue
+            }
             Action::MoveDown => {
                 self.data.keys.next();
                 AppReturn::Continue
@@ -163,33 +426,189 @@ impl App {
                 self.state.toggle_creation_popup();
                 AppReturn::Continue
             }
+            Action::CopyPassword if self.state.is_history_popup() => {
+                match self.data.selected_previous_password() {
+                    Some(password) => {
+                        let id = password.id();
+                        self.dispatch(IoEvent::CopyPreviousPassword(id)).await;
+                    }
+                    None => error!("Select the password to copy first"),
+                }
+                AppReturn::Continue
+            }
             Action::CopyPassword => {
                 let key = self.data.keys.state.selected();
                 if let Some(key) = key {
-                    if let Some(item) = self.data.keys.items.get_mut(key) {
-                        item.update_last_used_at();
-                        let updated = item.update_in_database();
-                        if let Err(err) = updated {
-                            error!("Cannot update key: {:?}", err);
-                        } else {
-                            info!("🔑 Key updated");
-                        }
-                        let item = item.clone();
-                        self.dispatch(IoEvent::Copy(item)).await;
+                    if let Some(item) = self.data.keys.items.get(key) {
+                        let id = item.id();
+                        self.dispatch(IoEvent::Copy(id)).await;
                     }
                 }
                 AppReturn::Continue
             }
+            Action::EditKey => {
+                match self.data.selected_key() {
+                    Some(key) => {
+                        self.form = Form::edit_key(key);
+                        self.toggle_input_mode();
+                        self.state.toggle_edit_form();
+                    }
+                    None => error!("Select the key to edit first"),
+                }
+                AppReturn::Continue
+            }
+            Action::ChangeMasterKey => {
+                self.toggle_input_mode();
+                self.state.toggle_master_key_popup();
+                self.form = Form::master_key_change();
+                AppReturn::Continue
+            }
+            Action::ReEncrypt => {
+                self.toggle_input_mode();
+                self.state.toggle_recovery_prompt();
+                AppReturn::Continue
+            }
+            Action::OpenBackup => {
+                self.dispatch(IoEvent::Recover(Recovery::OpenBackup)).await;
+                AppReturn::Continue
+            }
+            Action::Quarantine => {
+                self.dispatch(IoEvent::Recover(Recovery::Quarantine)).await;
+                AppReturn::Continue
+            }
+            Action::NewVault => {
+                self.ask_master_key();
+                AppReturn::Continue
+            }
+            Action::KeySlots => {
+                self.state.toggle_slots_popup();
+                self.actions = if self.state.is_slots_popup() {
+                    vec![
+                        Action::Quit,
+                        Action::Help,
+                        Action::MoveUp,
+                        Action::MoveDown,
+                        Action::KeySlots,
+                        Action::AddSlot,
+                        Action::RevokeSlot,
+                    ]
+                    .into()
+                } else {
+                    vault_actions()
+                };
+                AppReturn::Continue
+            }
+            Action::AddSlot => {
+                self.toggle_input_mode();
+                self.state.toggle_slot_form();
+                self.form = Form::key_slot();
+                AppReturn::Continue
+            }
+            Action::RecoveryKey => {
+                self.toggle_recovery_key_popup();
+                AppReturn::Continue
+            }
+            Action::NewRecoveryKey => {
+                self.dispatch(IoEvent::NewRecoveryKey).await;
+                AppReturn::Continue
+            }
+            Action::VerifyVault => {
+                if !self.state.is_verify_popup() {
+                    self.dispatch(IoEvent::VerifyVault).await;
+                }
+                self.toggle_verify_popup();
+                AppReturn::Continue
+            }
+            Action::PasswordHistory if self.state.is_history_popup() => {
+                self.toggle_history_popup();
+                AppReturn::Continue
+            }
+            Action::PasswordHistory => {
+                match self.data.selected_key() {
+                    Some(key) => {
+                        let id = key.id();
+                        self.dispatch(IoEvent::PasswordHistory(id)).await;
+                        self.toggle_history_popup();
+                    }
+                    None => error!("Select a key first"),
+                }
+                AppReturn::Continue
+            }
+            Action::RestorePassword => {
+                match self.data.selected_previous_password() {
+                    Some(password) => {
+                        let id = password.id();
+                        self.dispatch(IoEvent::RestorePassword(id)).await;
+                    }
+                    None => error!("Select the password to restore first"),
+                }
+                AppReturn::Continue
+            }
+            Action::RevokeSlot => {
+                match self.data.selected_slot() {
+                    Some(slot) => {
+                        let label = slot.label.clone();
+                        self.dispatch(IoEvent::RevokeSlot(label)).await;
+                    }
+                    None => error!("Select the key slot to revoke first"),
+                }
+                AppReturn::Continue
+            }
         }
     }
 
     /// We could update the app or dispatch event on tick
     pub async fn update_on_tick(&mut self) -> AppReturn {
-        // here we just increment a counter
         self.state.incr_tick();
+
+        if self.time_before_lock() == Some(Duration::ZERO) {
+            info!(
+                "💤 Locked after {:?} of inactivity",
+                self.last_activity.elapsed()
+            );
+            self.ask_master_key();
+            self.dispatch(IoEvent::Lock).await;
+        }
         AppReturn::Continue
     }
 
+    /// Report why the vault could not be unlocked, and slow down the next
+    /// attempts after too many wrong master keys
+    pub fn unlock_failed(&mut self, err: &UnlockError) {
+        self.unlock_error = Some(err.to_string());
+        if !err.is_wrong_password() {
+            return;
+        }
+
+        self.failed_attempts += 1;
+        if self.failed_attempts >= FREE_ATTEMPTS {
+            let exponent = (self.failed_attempts - FREE_ATTEMPTS).min(6);
+            let backoff = Duration::from_secs(1 << exponent).min(MAX_BACKOFF);
+            self.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    /// Why the last attempt to unlock the vault failed
+    pub fn unlock_error(&self) -> Option<&str> {
+        self.unlock_error.as_deref()
+    }
+
+    /// Time left before another master key can be tried
+    pub fn time_before_retry(&self) -> Option<Duration> {
+        self.retry_at
+            .map(|retry_at| retry_at.saturating_duration_since(Instant::now()))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Time left before the vault is locked for inactivity, if it is unlocked
+    pub fn time_before_lock(&self) -> Option<Duration> {
+        if !self.state.is_initialized() {
+            return None;
+        }
+        let timeout = self.lock_timeout?;
+        Some(timeout.saturating_sub(self.last_activity.elapsed()))
+    }
+
     /// Send a network event to the IO thread
     pub async fn dispatch(&mut self, action: IoEvent) {
         // `is_loading` will be set to false again after the async action has finished in io/handler.rs
@@ -212,27 +631,97 @@ impl App {
     }
 
     pub fn initialized(&mut self) {
+        self.recovering = false;
+        self.form = Form::default();
         // Update contextual actions
-        self.actions = vec![
-            Action::Quit,
-            Action::Help,
-            Action::MoveUp,
-            Action::MoveDown,
-            Action::CreateKey,
-            Action::CopyPassword,
-        ]
-        .into();
+        self.actions = vault_actions();
         self.editing_actions = vec![
             EditingAction::Quit,
             EditingAction::Validate,
             EditingAction::RemoveChar,
             EditingAction::WriteChar,
             EditingAction::Dismiss,
+            EditingAction::NextField,
         ]
         .into();
+        self.last_activity = Instant::now();
+        self.unlock_error = None;
+        self.failed_attempts = 0;
+        self.retry_at = None;
         self.state = AppState::initialized()
     }
 
+    /// Go back to the master key prompt
+    pub fn ask_master_key(&mut self) {
+        self.actions = vec![Action::Quit].into();
+        self.editing_actions = unlock_editing_actions();
+        self.input_mode = InputMode::Editing;
+        self.clear_input_buffer();
+        self.state = AppState::Init;
+        self.stop_recovering();
+    }
+
+    /// Show or hide the recovery key, forgetting it once hidden
+    pub fn toggle_recovery_key_popup(&mut self) {
+        self.state.toggle_recovery_key_popup();
+        if self.state.is_recovery_key_popup() {
+            self.actions = vec![Action::Quit, Action::RecoveryKey, Action::NewRecoveryKey].into();
+        } else {
+            self.data.recovery_key = None;
+            self.actions = vault_actions();
+        }
+    }
+
+    /// Show or hide the report of the verification, forgetting it once
+    /// hidden
+    pub fn toggle_verify_popup(&mut self) {
+        self.state.toggle_verify_popup();
+        if self.state.is_verify_popup() {
+            self.actions = vec![Action::Quit, Action::VerifyVault].into();
+        } else {
+            self.data.verify_report = None;
+            self.actions = vault_actions();
+        }
+    }
+
+    /// Show or hide the previous passwords of the selected key, forgetting
+    /// them once hidden
+    pub fn toggle_history_popup(&mut self) {
+        self.state.toggle_history_popup();
+        if self.state.is_history_popup() {
+            self.actions = vec![
+                Action::Quit,
+                Action::Help,
+                Action::MoveUp,
+                Action::MoveDown,
+                Action::CopyPassword,
+                Action::PasswordHistory,
+                Action::RestorePassword,
+            ]
+            .into();
+        } else {
+            self.data.history = StatefulList::with_items(Vec::new());
+            self.actions = vault_actions();
+        }
+    }
+
+    /// Ask the user what to do with an inconsistent vault, before anything
+    /// else can touch it, `plaintext` being where its plaintext database is
+    pub fn recovery(&mut self, issue: VaultIssue, plaintext: String) {
+        let mut actions = vec![Action::Quit];
+        if issue.has_plaintext() {
+            actions.extend([Action::ReEncrypt, Action::Quarantine]);
+        } else {
+            actions.push(Action::NewVault);
+        }
+        actions.push(Action::OpenBackup);
+
+        self.actions = actions.into();
+        self.input_mode = InputMode::Normal;
+        self.clear_input_buffer();
+        self.state = AppState::recovery(issue, plaintext);
+    }
+
     pub fn loaded(&mut self) {
         self.is_loading = false;
     }
@@ -244,3 +733,36 @@ impl App {
         }
     }
 }
+
+/// Actions available once the vault is opened
+fn vault_actions() -> Actions {
+    vec![
+        Action::Quit,
+        Action::Help,
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::CreateKey,
+        Action::CopyPassword,
+        Action::ChangeMasterKey,
+        Action::KeySlots,
+        Action::RecoveryKey,
+        Action::VerifyVault,
+        Action::PasswordHistory,
+        Action::EditKey,
+    ]
+    .into()
+}
+
+/// Actions available while typing the master key
+fn unlock_editing_actions() -> EditingActions {
+    vec![
+        EditingAction::Quit,
+        EditingAction::RemoveChar,
+        EditingAction::Dismiss,
+        EditingAction::Validate,
+        EditingAction::WriteChar,
+        EditingAction::NextField,
+        EditingAction::ForgotMasterKey,
+    ]
+    .into()
+}
@@ -1,12 +1,26 @@
 use std::fmt::Display;
 
+use log::error;
 use ratatui::widgets::ListState;
 
+use crate::crypto::secret::SecretString;
+use crate::crypto::slot::{KeySlot, VaultKeys};
 use crate::models::key::Key;
+use crate::models::password::Password;
+use crate::repository::KeyRepository;
+use crate::storage::recovery::VaultIssue;
+use crate::storage::verify::VerifyReport;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum AppState {
+    #[default]
     Init,
+    Recovery {
+        issue: VaultIssue,
+        /// Where a plaintext database would be left
+        plaintext: String,
+        show_password_prompt: bool,
+    },
     // Initialization {
     //     password: String,
     // },
@@ -15,6 +29,13 @@ pub enum AppState {
         counter_tick: u64,
         show_help: bool,
         show_creation_popup: bool,
+        show_master_key_popup: bool,
+        show_slots_popup: bool,
+        show_slot_form: bool,
+        show_recovery_key_popup: bool,
+        show_verify_popup: bool,
+        show_history_popup: bool,
+        show_edit_form: bool,
     },
 }
 
@@ -27,6 +48,13 @@ impl AppState {
             counter_tick,
             show_help: false,
             show_creation_popup: false,
+            show_master_key_popup: false,
+            show_slots_popup: false,
+            show_slot_form: false,
+            show_recovery_key_popup: false,
+            show_verify_popup: false,
+            show_history_popup: false,
+            show_edit_form: false,
         }
     }
 
@@ -50,6 +78,54 @@ impl AppState {
     //     *self = Self::Initialization { password };
     // }
 
+    pub fn recovery(issue: VaultIssue, plaintext: String) -> Self {
+        Self::Recovery {
+            issue,
+            plaintext,
+            show_password_prompt: false,
+        }
+    }
+
+    /// The inconsistency found at startup, if it has not been solved yet
+    pub fn recovery_issue(&self) -> Option<VaultIssue> {
+        if let Self::Recovery { issue, .. } = self {
+            Some(*issue)
+        } else {
+            None
+        }
+    }
+
+    /// Where the plaintext database of the vault being recovered is
+    pub fn recovery_plaintext(&self) -> Option<&str> {
+        if let Self::Recovery { plaintext, .. } = self {
+            Some(plaintext)
+        } else {
+            None
+        }
+    }
+
+    pub fn toggle_recovery_prompt(&mut self) {
+        if let Self::Recovery {
+            show_password_prompt,
+            ..
+        } = self
+        {
+            *show_password_prompt = !*show_password_prompt;
+        }
+    }
+
+    pub fn is_recovery_prompt(&self) -> bool {
+        if let Self::Recovery {
+            show_password_prompt,
+            ..
+        } = self
+        {
+            *show_password_prompt
+        } else {
+            false
+        }
+    }
+
     pub fn toggle_help(&mut self) {
         if let Self::Initialized { show_help, .. } = self {
             *show_help = !*show_help;
@@ -78,6 +154,138 @@ impl AppState {
         }
     }
 
+    pub fn toggle_master_key_popup(&mut self) {
+        if let Self::Initialized {
+            show_master_key_popup,
+            ..
+        } = self
+        {
+            *show_master_key_popup = !*show_master_key_popup;
+        }
+    }
+
+    pub fn is_master_key_popup(&self) -> bool {
+        if let Self::Initialized {
+            show_master_key_popup,
+            ..
+        } = self
+        {
+            *show_master_key_popup
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_slots_popup(&mut self) {
+        if let Self::Initialized {
+            show_slots_popup, ..
+        } = self
+        {
+            *show_slots_popup = !*show_slots_popup;
+        }
+    }
+
+    pub fn is_slots_popup(&self) -> bool {
+        if let Self::Initialized {
+            show_slots_popup, ..
+        } = self
+        {
+            *show_slots_popup
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_slot_form(&mut self) {
+        if let Self::Initialized { show_slot_form, .. } = self {
+            *show_slot_form = !*show_slot_form;
+        }
+    }
+
+    pub fn is_slot_form(&self) -> bool {
+        if let Self::Initialized { show_slot_form, .. } = self {
+            *show_slot_form
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_recovery_key_popup(&mut self) {
+        if let Self::Initialized {
+            show_recovery_key_popup,
+            ..
+        } = self
+        {
+            *show_recovery_key_popup = !*show_recovery_key_popup;
+        }
+    }
+
+    pub fn is_recovery_key_popup(&self) -> bool {
+        if let Self::Initialized {
+            show_recovery_key_popup,
+            ..
+        } = self
+        {
+            *show_recovery_key_popup
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_verify_popup(&mut self) {
+        if let Self::Initialized {
+            show_verify_popup, ..
+        } = self
+        {
+            *show_verify_popup = !*show_verify_popup;
+        }
+    }
+
+    pub fn is_verify_popup(&self) -> bool {
+        if let Self::Initialized {
+            show_verify_popup, ..
+        } = self
+        {
+            *show_verify_popup
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_history_popup(&mut self) {
+        if let Self::Initialized {
+            show_history_popup, ..
+        } = self
+        {
+            *show_history_popup = !*show_history_popup;
+        }
+    }
+
+    pub fn is_history_popup(&self) -> bool {
+        if let Self::Initialized {
+            show_history_popup, ..
+        } = self
+        {
+            *show_history_popup
+        } else {
+            false
+        }
+    }
+
+    pub fn toggle_edit_form(&mut self) {
+        if let Self::Initialized { show_edit_form, .. } = self {
+            *show_edit_form = !*show_edit_form;
+        }
+    }
+
+    pub fn is_edit_form(&self) -> bool {
+        if let Self::Initialized { show_edit_form, .. } = self {
+            *show_edit_form
+        } else {
+            false
+        }
+    }
+
     pub fn is_help(&self) -> bool {
         if let Self::Initialized { show_help, .. } = self {
             *show_help
@@ -105,12 +313,6 @@ impl AppState {
     }
 }
 
-impl Default for AppState {
-    fn default() -> AppState {
-        Self::Init
-    }
-}
-
 pub struct StatefulList<T> {
     pub state: ListState,
     pub items: Vec<T>,
@@ -159,17 +361,91 @@ impl<T> StatefulList<T> {
 
 pub struct AppData {
     pub keys: StatefulList<Key>,
+    /// The decrypted vault, only kept in memory
+    pub repository: Option<Box<dyn KeyRepository>>,
+    /// The key slots of the vault
+    pub slots: StatefulList<KeySlot>,
+    /// Label of the slot the vault has been opened with
+    pub opened_slot: Option<String>,
+    /// A recovery key just generated, shown until its popup is closed
+    pub recovery_key: Option<SecretString>,
+    /// What verifying the vault found, shown until its popup is closed
+    pub verify_report: Option<VerifyReport>,
+    /// The previous passwords of the selected key, shown until their popup
+    /// is closed
+    pub history: StatefulList<Password>,
 }
 
 impl AppData {
     pub fn load_key_list(&mut self) {
-        let keys = Key::retrive_keys_from_db().unwrap();
-        // let keys = retrive_keys_from_db().unwrap();
-        self.keys = StatefulList::with_items(keys);
+        let Some(repository) = &self.repository else {
+            return;
+        };
+        match repository.keys() {
+            Ok(keys) => self.keys = StatefulList::with_items(keys),
+            Err(err) => error!("Cannot retrieve keys: {:?}", err),
+        }
+    }
+
+    /// Show the slots of `keys`, keeping the selection when possible
+    pub fn set_slots(&mut self, keys: &VaultKeys) {
+        self.show_slots(keys.slots().to_vec(), &keys.opened().label);
+    }
+
+    /// Show `slots`, the vault being opened with the one labeled `opened`
+    pub fn show_slots(&mut self, slots: Vec<KeySlot>, opened: &str) {
+        let selected = self.slots.state.selected();
+        self.slots = StatefulList::with_items(slots);
+        self.slots
+            .state
+            .select(selected.map(|i| i.min(self.slots.items.len().saturating_sub(1))));
+        self.opened_slot = Some(opened.to_string());
+    }
+
+    /// The key currently selected in the list
+    pub fn selected_key(&self) -> Option<&Key> {
+        self.keys
+            .state
+            .selected()
+            .and_then(|i| self.keys.items.get(i))
+    }
+
+    /// The previous password currently selected in the history
+    pub fn selected_previous_password(&self) -> Option<&Password> {
+        self.history
+            .state
+            .selected()
+            .and_then(|i| self.history.items.get(i))
+    }
+
+    /// The slot currently selected in the list
+    pub fn selected_slot(&self) -> Option<&KeySlot> {
+        self.slots
+            .state
+            .selected()
+            .and_then(|i| self.slots.items.get(i))
+    }
+
+    /// The vault can be opened with a recovery key
+    pub fn has_recovery_key(&self) -> bool {
+        self.slots.items.iter().any(KeySlot::is_recovery)
+    }
+
+    /// Forget everything that has been decrypted
+    pub fn wipe(&mut self) {
+        self.keys = StatefulList::with_items(Vec::new());
+        self.repository = None;
+        self.slots = StatefulList::with_items(Vec::new());
+        self.opened_slot = None;
+        self.recovery_key = None;
+        self.verify_report = None;
+        self.history = StatefulList::with_items(Vec::new());
     }
 
     pub fn create_key(&mut self, key: Key) {
-        key.persist().unwrap();
+        if let Some(repository) = &mut self.repository {
+            repository.insert(&key).unwrap();
+        }
         // insert_key_to_db(&key).unwrap();
         self.keys.items.push(key);
     }
@@ -189,6 +465,12 @@ impl Default for AppData {
     fn default() -> Self {
         Self {
             keys: StatefulList::with_items(Vec::new()),
+            repository: None,
+            slots: StatefulList::with_items(Vec::new()),
+            opened_slot: None,
+            recovery_key: None,
+            verify_report: None,
+            history: StatefulList::with_items(Vec::new()),
         }
     }
 }
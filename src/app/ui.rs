@@ -1,17 +1,22 @@
+use std::time::Duration;
+
 use ratatui::backend::Backend;
 use ratatui::layout::{Alignment, Constraint, Direction, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::{
-    Block, BorderType, Borders, Cell, Clear, List, ListItem, Paragraph, Row, Table,
+    Block, BorderType, Borders, Cell, Clear, List, ListItem, Paragraph, Row, Table, Wrap,
 };
 use ratatui::Frame;
 use tui_logger::TuiLoggerWidget;
 
 use super::actions::normal_actions::Actions;
+use super::form::Form;
 use super::state::{AppData, AppState};
 use crate::app::App;
 use crate::models::key::Key;
+use crate::storage::recovery::VaultIssue;
+use crate::storage::verify::Finding;
 
 pub fn draw<B>(rect: &mut Frame<B>, app: &mut App)
 where
@@ -20,9 +25,30 @@ where
     let size = rect.size();
     check_size(&size);
 
-    if !app.state.is_initialized() {
-        let input = draw_master_key_form(app);
-        let area = centered_rect(60, 10, size);
+    if let Some(issue) = app.state.recovery_issue() {
+        draw_recovery(rect, app, issue, size);
+    } else if app.is_unlock_form() {
+        let input = draw_master_key_form(app, "Master Key and Keyfile");
+        let area = centered_rect(60, 20, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(input, area);
+
+        set_form_cursor(rect, &app.form, area);
+    } else if app.is_recovery_key_form() {
+        let input = draw_master_key_form(app, "Recovery Key and New Master Key");
+        let area = centered_rect(80, 20, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(input, area);
+
+        let helper = draw_form_helper();
+        let t = Rect::new(0, 0, 60, 10);
+        rect.render_widget(Clear, t); //this clears out the background
+        rect.render_widget(helper, t);
+
+        set_form_cursor(rect, &app.form, area);
+    } else if !app.state.is_initialized() {
+        let input = draw_master_key_form(app, "Master Key");
+        let area = centered_rect(60, 20, size);
         rect.render_widget(Clear, area); //this clears out the background
         rect.render_widget(input, area);
 
@@ -38,6 +64,81 @@ where
     }
 }
 
+fn draw_recovery<B>(rect: &mut Frame<B>, app: &App, issue: VaultIssue, size: Rect)
+where
+    B: Backend,
+{
+    let plaintext = app.state.recovery_plaintext().unwrap_or_default();
+    let dialog = draw_recovery_dialog(issue, plaintext, app.actions());
+    let area = centered_rect(80, 50, size);
+    rect.render_widget(Clear, area); //this clears out the background
+    rect.render_widget(dialog, area);
+
+    if app.state.is_recovery_prompt() {
+        let input = draw_master_key_form(app, "Original Master Key");
+        let area = centered_rect(60, 10, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(input, area);
+
+        let helper = draw_creation_helper();
+        let t = Rect::new(0, 0, 60, 10);
+        rect.render_widget(Clear, t); //this clears out the background
+        rect.render_widget(helper, t);
+
+        rect.set_cursor(area.x + app.input_buffer.len() as u16 + 1, area.y + 1)
+    }
+
+    // The outcome of the recovery is only reported in the logs
+    let logs = draw_logs();
+    let height = size.height.min(12);
+    let t = Rect::new(0, size.height - height, size.width, height);
+    rect.render_widget(Clear, t); //this clears out the background
+    rect.render_widget(logs, t);
+}
+
+fn draw_recovery_dialog<'a>(
+    issue: VaultIssue,
+    plaintext: &str,
+    actions: &'a Actions,
+) -> Paragraph<'a> {
+    let key_style = Style::default().fg(Color::LightCyan);
+
+    let mut text = vec![Line::from(Span::raw(issue.to_string())), Line::from("")];
+    if issue.has_plaintext() {
+        text.push(Line::from(Span::raw(format!(
+            "Plaintext database: {plaintext}"
+        ))));
+        text.push(Line::from(""));
+    }
+    for action in actions.actions() {
+        let keys = action
+            .keys()
+            .iter()
+            .map(|key| key.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        text.push(Line::from(vec![
+            Span::styled(format!("{keys:>11} "), key_style),
+            Span::raw(action.to_string()),
+        ]));
+    }
+
+    Paragraph::new(text)
+        .style(Style::default().fg(Color::White))
+        .alignment(Alignment::Left)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .title(Span::styled(
+                    "Vault recovery",
+                    Style::default().fg(Color::Yellow),
+                ))
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .border_type(BorderType::Rounded),
+        )
+}
+
 fn draw_app<B>(rect: &mut Frame<B>, app: &mut App, size: Rect)
 where
     B: Backend,
@@ -57,7 +158,7 @@ where
         .split(size);
 
     // Title
-    let title = draw_title(app.state());
+    let title = draw_title(app.state(), app.time_before_lock());
     rect.render_widget(title, chunks[0]);
 
     // Body & Help
@@ -95,6 +196,112 @@ where
 
         rect.set_cursor(area.x + app.input_buffer.len() as u16 + 1, area.y + 2)
     }
+
+    if app.state.is_master_key_popup() {
+        let form = draw_form(&app.form, "Change the master key");
+        let area = centered_rect(60, 20, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(form, area);
+
+        let helper = draw_form_helper();
+        let t = Rect::new(0, 0, 60, 10);
+        rect.render_widget(Clear, t); //this clears out the background
+        rect.render_widget(helper, t);
+
+        set_form_cursor(rect, &app.form, area);
+    }
+
+    if app.state.is_slots_popup() {
+        let area = centered_rect(70, 40, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        draw_slots(&mut app.data, area, rect);
+    }
+
+    if app.state.is_history_popup() {
+        let area = centered_rect(80, 40, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        draw_history(&mut app.data, area, rect);
+    }
+
+    if app.state.is_recovery_key_popup() {
+        let popup = draw_recovery_key(&app.data);
+        let area = centered_rect(70, 40, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(popup, area);
+    }
+
+    if app.state.is_verify_popup() {
+        let popup = draw_verify_report(&app.data);
+        let area = centered_rect(80, 60, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(popup, area);
+    }
+
+    if app.state.is_edit_form() {
+        let form = draw_form(&app.form, "Edit the key");
+        let area = centered_rect(60, 30, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(form, area);
+
+        let helper = draw_edit_helper();
+        let t = Rect::new(0, 0, 60, 10);
+        rect.render_widget(Clear, t); //this clears out the background
+        rect.render_widget(helper, t);
+
+        set_form_cursor(rect, &app.form, area);
+    }
+
+    if app.state.is_slot_form() {
+        let form = draw_form(&app.form, "Add a key slot");
+        let area = centered_rect(60, 20, size);
+        rect.render_widget(Clear, area); //this clears out the background
+        rect.render_widget(form, area);
+
+        let helper = draw_form_helper();
+        let t = Rect::new(0, 0, 60, 10);
+        rect.render_widget(Clear, t); //this clears out the background
+        rect.render_widget(helper, t);
+
+        set_form_cursor(rect, &app.form, area);
+    }
+}
+
+/// Put the cursor at the end of the focused field of a form drawn in `area`
+fn set_form_cursor<B: Backend>(rect: &mut Frame<B>, form: &Form, area: Rect) {
+    let focus = form.focus();
+    let field = &form.fields()[focus];
+    let offset = if field.is_button() {
+        1
+    } else {
+        field.label().len() + field.display_value().chars().count() + 2
+    };
+    rect.set_cursor(area.x + offset as u16 + 1, area.y + focus as u16 + 1)
+}
+
+fn draw_form_helper() -> Paragraph<'static> {
+    let text = vec![
+        Line::from(Span::raw("Press 'Tab' to go to the next field")),
+        Line::from(Span::raw("Press 'Enter' to validate")),
+        Line::from(Span::raw("Press 'Esc' to cancel")),
+    ];
+    Paragraph::new(text)
+        .block(Block::default().borders(Borders::ALL).title("Helper"))
+        .style(Style::default().fg(Color::White))
+        .alignment(Alignment::Left)
+}
+
+fn draw_edit_helper() -> Paragraph<'static> {
+    let text = vec![
+        Line::from(Span::raw("Press 'Tab' to go to the next field")),
+        Line::from(Span::raw("Press 'Enter' to save, the password is kept")),
+        Line::from(Span::raw("unless a new one is typed, or 'Enter' is")),
+        Line::from(Span::raw("pressed on the button to generate one")),
+        Line::from(Span::raw("Press 'Esc' to cancel")),
+    ];
+    Paragraph::new(text)
+        .block(Block::default().borders(Borders::ALL).title("Helper"))
+        .style(Style::default().fg(Color::White))
+        .alignment(Alignment::Left)
 }
 
 fn draw_creation_helper() -> Paragraph<'static> {
@@ -138,8 +345,11 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
         .split(popup_layout[1])[1]
 }
 
-fn draw_title<'a>(state: &AppState) -> Paragraph<'a> {
-    let tick_text = if let Some(ticks) = state.count_tick() {
+fn draw_title<'a>(state: &AppState, time_before_lock: Option<Duration>) -> Paragraph<'a> {
+    let tick_text = if let Some(left) = time_before_lock {
+        let secs = left.as_secs();
+        format!("🔒 Locks in {}:{:02}", secs / 60, secs % 60)
+    } else if let Some(ticks) = state.count_tick() {
         format!("Tick count: {ticks}")
     } else {
         String::default()
@@ -193,42 +403,42 @@ fn draw_body<'a>(_loading: bool, _state: &AppState, data: &'a AppData) -> Table<
     let selected_key = data.keys.state.selected();
     match selected_key {
         Some(idx) => {
-            let normal_style = Style::default().bg(Color::DarkGray).fg(Color::White);
+            let label_style = Style::default().fg(Color::LightCyan);
 
-            let header_cells = [
+            // One field per row, the notes and the URLs may span several lines
+            let labels = [
                 "id",
                 "name",
+                "username",
+                "urls",
                 "password",
+                "notes",
                 "created at",
                 "updated at",
                 "last used at",
                 "last changed at",
-            ]
-            .iter()
-            .map(|h| Cell::from(*h).style(Style::default()));
-            let header = Row::new(header_cells)
-                .style(normal_style)
-                .height(1)
-                .bottom_margin(1);
-
+            ];
             let rows = data
                 .keys
                 .items
                 .get(idx)
-                .map(|item| Row::new(item.to_vec()).bottom_margin(1));
+                .map(|item| item.to_vec())
+                .unwrap_or_default()
+                .into_iter()
+                .zip(labels)
+                .map(|(value, label)| {
+                    let height = value.lines().count().max(1) as u16;
+                    Row::new(vec![
+                        Cell::from(label).style(label_style),
+                        Cell::from(value),
+                    ])
+                    .height(height)
+                    .bottom_margin(1)
+                });
 
             Table::new(rows)
-                .header(header)
                 .block(Block::default().borders(Borders::ALL).title("Table"))
-                .widths(&[
-                    Constraint::Percentage(15),
-                    Constraint::Percentage(10),
-                    Constraint::Percentage(15),
-                    Constraint::Percentage(15),
-                    Constraint::Percentage(15),
-                    Constraint::Percentage(15),
-                    Constraint::Percentage(15),
-                ])
+                .widths(&[Constraint::Percentage(20), Constraint::Percentage(80)])
         }
         None => Table::new(vec![Row::new(vec![Cell::from("No data")])])
             .block(Block::default().borders(Borders::ALL).title("Table"))
@@ -277,7 +487,85 @@ fn draw_keys<B: Backend>(data: &mut AppData, body_chunk: Rect, rect: &mut Frame<
     rect.render_stateful_widget(items, body_chunk, &mut data.keys.state);
 }
 
-fn draw_master_key_form(app: &App) -> Paragraph {
+fn draw_slots<B: Backend>(data: &mut AppData, area: Rect, rect: &mut Frame<B>) {
+    let slot_style = Style::default().fg(Color::LightCyan);
+
+    let items: Vec<ListItem> = data
+        .slots
+        .items
+        .iter()
+        .map(|slot| {
+            let text = if data.opened_slot.as_deref() == Some(slot.label.as_str()) {
+                format!("🔓 {slot}")
+            } else {
+                format!("   {slot}")
+            };
+            ListItem::new(Span::from(text)).style(slot_style)
+        })
+        .collect();
+
+    let items = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded)
+                .title("Key slots: 'a' to add, 'r' to revoke, 's' to close"),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(Color::DarkGray)
+                .fg(Color::White)
+                .add_modifier(Modifier::BOLD),
+        );
+
+    rect.render_stateful_widget(items, area, &mut data.slots.state);
+}
+
+/// The previous passwords of the selected key, never shown in clear
+fn draw_history<B: Backend>(data: &mut AppData, area: Rect, rect: &mut Frame<B>) {
+    let password_style = Style::default().fg(Color::LightCyan);
+
+    let items: Vec<ListItem> = if data.history.items.is_empty() {
+        vec![ListItem::new(Span::from("No previous password")).style(password_style)]
+    } else {
+        data.history
+            .items
+            .iter()
+            .map(|password| {
+                let text = format!(
+                    "********  used from {} to {}",
+                    password.created_at(),
+                    password.last_changed_at()
+                );
+                ListItem::new(Span::from(text)).style(password_style)
+            })
+            .collect()
+    };
+
+    let name = data
+        .selected_key()
+        .map(|key| key.name())
+        .unwrap_or_default();
+    let items = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(BorderType::Rounded)
+                .title(format!(
+                    "Previous passwords of {name}: 'y' to copy, 'r' to restore, 'h' to close"
+                )),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(Color::DarkGray)
+                .fg(Color::White)
+                .add_modifier(Modifier::BOLD),
+        );
+
+    rect.render_stateful_widget(items, area, &mut data.history.state);
+}
+
+fn draw_master_key_form<'a>(app: &App, title: &'a str) -> Paragraph<'a> {
     // let text = vec![
     //     Line::from(Span::styled(
     //         "Master key password: ",
@@ -292,13 +580,90 @@ fn draw_master_key_form(app: &App) -> Paragraph {
         .chars()
         .map(|_| '*')
         .collect::<String>();
-    Paragraph::new(Line::from(Span::raw(obfuscate_text)))
+
+    let mut text = if app.is_unlock_form() || app.is_recovery_key_form() {
+        form_lines(&app.form)
+    } else {
+        vec![Line::from(Span::raw(obfuscate_text))]
+    };
+    if let Some(err) = app.unlock_error() {
+        text.push(Line::from(Span::styled(
+            format!("❌ {err}"),
+            Style::default().fg(Color::Red),
+        )));
+    }
+    if let Some(wait) = app.time_before_retry() {
+        text.push(Line::from(Span::styled(
+            format!("⏳ Try again in {}s", wait.as_secs() + 1),
+            Style::default().fg(Color::Yellow),
+        )));
+    }
+    if app.unlock_error().is_some() && !app.is_recovery_key_form() {
+        text.push(Line::from(Span::styled(
+            "Forgot the master key? Press 'Ctrl+r' to use the recovery key",
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+
+    Paragraph::new(text)
         .style(Style::default().fg(Color::LightCyan))
         .alignment(Alignment::Left)
+        .block(
+            Block::default()
+                .title(Span::styled(title, Style::default().fg(Color::LightCyan)))
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .border_type(BorderType::Rounded),
+        )
+}
+
+/// The recovery key just generated, or what it is for
+fn draw_recovery_key(data: &AppData) -> Paragraph<'_> {
+    let text = match &data.recovery_key {
+        Some(recovery_key) => vec![
+            Line::from(Span::raw(
+                "Write this recovery key down, or print it, and keep it away from the computer:",
+            )),
+            Line::from(""),
+            Line::from(Span::styled(
+                recovery_key.expose(),
+                Style::default()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+            )),
+            Line::from(""),
+            Line::from(Span::raw(
+                "It opens the vault without its master key, and is not shown again.",
+            )),
+        ],
+        None => {
+            let status = if data.has_recovery_key() {
+                "The vault has a recovery key. A new one replaces it, the current one stops working."
+            } else {
+                "Without its master key, nobody can open the vault, not even you."
+            };
+            vec![
+                Line::from(Span::raw(status)),
+                Line::from(""),
+                Line::from(Span::raw(
+                    "A recovery key opens the vault when the master key is forgotten: it is \
+                     printed once, to be kept somewhere safe.",
+                )),
+                Line::from(Span::raw(
+                    "Run `recovery create --shares N --threshold K` to split it between people instead.",
+                )),
+            ]
+        }
+    };
+
+    Paragraph::new(text)
+        .style(Style::default().fg(Color::LightCyan))
+        .alignment(Alignment::Left)
+        .wrap(Wrap { trim: false })
         .block(
             Block::default()
                 .title(Span::styled(
-                    "Master Key",
+                    "Recovery key: 'n' for a new one, 'g' to close",
                     Style::default().fg(Color::LightCyan),
                 ))
                 .borders(Borders::ALL)
@@ -307,7 +672,62 @@ fn draw_master_key_form(app: &App) -> Paragraph {
         )
 }
 
-fn draw_creation_form(app: &App) -> Paragraph {
+/// What verifying the vault found, one line per generation checked
+fn draw_verify_report(data: &AppData) -> Paragraph<'_> {
+    let text = match &data.verify_report {
+        Some(report) => {
+            let mut lines = report
+                .findings
+                .iter()
+                .map(|finding| {
+                    let color = match finding {
+                        Finding::Passed(_) => Color::LightGreen,
+                        Finding::Skipped(_) => Color::Yellow,
+                        Finding::Problem(_) => Color::LightRed,
+                    };
+                    Line::from(Span::styled(
+                        finding.to_string(),
+                        Style::default().fg(color),
+                    ))
+                })
+                .collect::<Vec<_>>();
+            lines.push(Line::from(""));
+            lines.push(Line::from(Span::styled(
+                report.summary(),
+                Style::default()
+                    .fg(Color::White)
+                    .add_modifier(Modifier::BOLD),
+            )));
+            if report.skipped() > 0 {
+                lines.push(Line::from(Span::raw(
+                    "Backups skipped here may open with an older master key: run `verify` to \
+                     type it.",
+                )));
+            }
+            lines
+        }
+        None => vec![Line::from(Span::raw(
+            "⏳ Decrypting the vault and its backups...",
+        ))],
+    };
+
+    Paragraph::new(text)
+        .style(Style::default().fg(Color::LightCyan))
+        .alignment(Alignment::Left)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .title(Span::styled(
+                    "Verify the vault: 'v' to close",
+                    Style::default().fg(Color::LightCyan),
+                ))
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .border_type(BorderType::Rounded),
+        )
+}
+
+fn draw_creation_form(app: &App) -> Paragraph<'_> {
     let text = vec![
         Line::from(Span::styled(
             "Key Name: ",
@@ -331,13 +751,52 @@ fn draw_creation_form(app: &App) -> Paragraph {
         )
 }
 
+/// One line per field, the focused one in bold
+fn form_lines(form: &Form) -> Vec<Line<'static>> {
+    let focused_style = Style::default()
+        .fg(Color::White)
+        .add_modifier(Modifier::BOLD);
+
+    form.fields()
+        .iter()
+        .enumerate()
+        .map(|(idx, field)| {
+            let label_style = if idx == form.focus() {
+                focused_style
+            } else {
+                Style::default().fg(Color::Gray)
+            };
+            if field.is_button() {
+                return Line::from(Span::styled(format!("[ {} ]", field.label()), label_style));
+            }
+            Line::from(vec![
+                Span::styled(format!("{}: ", field.label()), label_style),
+                Span::raw(field.display_value()),
+            ])
+        })
+        .collect()
+}
+
+fn draw_form<'a>(form: &Form, title: &'a str) -> Paragraph<'a> {
+    Paragraph::new(form_lines(form))
+        .style(Style::default().fg(Color::LightCyan))
+        .alignment(Alignment::Left)
+        .block(
+            Block::default()
+                .title(Span::styled(title, Style::default().fg(Color::LightCyan)))
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .border_type(BorderType::Rounded),
+        )
+}
+
 // fn draw_footer() -> Paragraph<'static> {
 //     Paragraph::new("Press q to exit, h for help")
 //         .style(Style::default().fg(Color::LightCyan))
 //         .alignment(Alignment::Center)
 // }
 
-fn draw_help(actions: &Actions) -> Table {
+fn draw_help(actions: &Actions) -> Table<'_> {
     let key_style = Style::default().fg(Color::LightCyan);
     let help_style = Style::default().fg(Color::Gray);
 
@@ -0,0 +1,498 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::config::Config;
+use crate::crypto::decoy::{clear_decoy, write_decoy, DuressKey};
+use crate::crypto::error::UnlockError;
+use crate::crypto::header::{KdfAlgorithm, KdfParams};
+use crate::crypto::keyfile::{self, Keyfile};
+use crate::crypto::recipient::{self, Identity, Recipient};
+use crate::crypto::recovery_key::RecoveryKey;
+use crate::crypto::secret::{MasterKey, SecretString};
+use crate::crypto::slot::{VaultKeys, DEFAULT_SLOT_LABEL};
+use crate::crypto::utils::{
+    calibrate_kdf, decrypt_small_file, encrypt_small_file, read_key_slots, save_key_slots,
+    unlock_small_file, unlock_small_file_with_identity, unlock_small_file_with_recovery_key,
+    UnlockedVault, ARGON2_MEMORY, ARGON2_PARALLELISM,
+};
+use crate::repository::{dump_database, init_database_schemas, load_database};
+use crate::storage::backup::{backup_vault, list_backups, restore_backup};
+use crate::storage::verify::verify_vault;
+
+pub fn upgrade_kdf(config: &Config, iterations: Option<u32>) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let kdf = match (config.kdf, iterations) {
+        (KdfAlgorithm::Pbkdf2, Some(iterations)) => KdfParams::pbkdf2(iterations),
+        (KdfAlgorithm::Argon2id, Some(time)) => {
+            KdfParams::argon2id(ARGON2_MEMORY, time, ARGON2_PARALLELISM)
+        }
+        (algorithm, None) => calibrate_kdf(algorithm, config.unlock_time),
+    };
+
+    let (mut vault, password, keyfile) = unlock(config, &config.vault, "Master key: ")?;
+    let label = vault.keys.opened().label.clone();
+    let current = vault.keys.opened().kdf().cloned().ok_or(anyhow!(
+        "The key slot {label} is a recipient, it has no master key"
+    ))?;
+
+    match (current.algorithm(), kdf.algorithm()) {
+        (KdfAlgorithm::Argon2id, KdfAlgorithm::Pbkdf2) => {
+            return Err(anyhow!(
+                "Refusing to move the key slot {label} from Argon2id back to PBKDF2"
+            ));
+        }
+        (KdfAlgorithm::Pbkdf2, KdfAlgorithm::Argon2id) => (),
+        _ if work_factor(&kdf) <= work_factor(&current) && !vault.converted => {
+            println!("🔐 The key slot {label} already uses {current}, nothing to do");
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    backup_vault(&config.vault, config.backups)?;
+    println!("⏳ Wrapping the data key of {label} with {kdf} (was {current})");
+    vault
+        .keys
+        .rewrap_opened(password.expose(), keyfile.as_ref(), &kdf)?;
+    save_key_slots(&config.vault, &vault)?;
+    println!("🔒 Key slot {label} upgraded");
+
+    Ok(())
+}
+
+pub fn restore(config: &Config, backup: Option<usize>) -> Result<(), anyhow::Error> {
+    let backups = list_backups(&config.vault)?;
+    if backups.is_empty() {
+        println!("📦 No backup of {}", config.vault);
+        return Ok(());
+    }
+
+    let Some(index) = backup else {
+        println!("📦 Backups of {}, the most recent first:", config.vault);
+        for (index, backup) in backups.iter().enumerate() {
+            println!("  {index}: {backup}");
+        }
+        println!("💡 Run `restore <number>` to roll the vault back");
+        return Ok(());
+    };
+
+    let backup = backups
+        .get(index)
+        .ok_or(anyhow!("No backup number {index}"))?;
+
+    // Never replace the vault with something that cannot be opened
+    let (vault, _, _) = unlock(
+        config,
+        &backup.path.to_string_lossy(),
+        "Master key of the backup: ",
+    )?;
+    load_database(&vault.plaintext)?;
+    println!("🔓 Backup of {backup} decrypted");
+
+    restore_backup(&config.vault, backup, config.backups)?;
+    println!("⏪ Vault rolled back to {backup}, the previous vault has been backed up");
+
+    Ok(())
+}
+
+pub fn create_keyfile(path: &Path) -> Result<(), anyhow::Error> {
+    keyfile::create_keyfile(path)?;
+    println!("🗝️ Keyfile written to {}", path.display());
+    println!("💡 Keep a copy somewhere safe: a vault bound to it cannot be opened without it");
+    Ok(())
+}
+
+/// Bind the key slot opened by the master key to the keyfile at `path`, or
+/// remove its binding when `path` is `None`, without changing the master key
+pub fn bind_keyfile(config: &Config, path: Option<&Path>) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let new_keyfile = path
+        .map(Keyfile::read)
+        .transpose()
+        .map_err(|err| anyhow!("Cannot read the new keyfile: {err}"))?;
+    let (mut vault, password, _) = unlock(config, &config.vault, "Master key: ")?;
+    let slot = vault.keys.opened().clone();
+    if path.is_none() && !slot.requires_keyfile() {
+        println!(
+            "🗝️ The key slot {} is not bound to a keyfile, nothing to do",
+            slot.label
+        );
+        return Ok(());
+    }
+    let kdf = slot.kdf().ok_or(anyhow!(
+        "The key slot {} is a recipient, it has no master key",
+        slot.label
+    ))?;
+
+    backup_vault(&config.vault, config.backups)?;
+    vault
+        .keys
+        .rewrap_opened(password.expose(), new_keyfile.as_ref(), kdf)?;
+    save_key_slots(&config.vault, &vault)?;
+    match path {
+        Some(path) => println!(
+            "🗝️ The key slot {} now requires {} to be unlocked",
+            slot.label,
+            path.display()
+        ),
+        None => println!(
+            "🔓 The key slot {} does not require a keyfile anymore",
+            slot.label
+        ),
+    }
+
+    Ok(())
+}
+
+/// List the key slots of the vault, no master key is needed
+pub fn list_slots(config: &Config) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let slots = read_key_slots(&config.vault)?;
+    if slots.is_empty() {
+        println!("📦 The vault has no key slots yet, it gets one the next time it is opened");
+        return Ok(());
+    }
+
+    println!("🔑 Key slots of {}:", config.vault);
+    for slot in slots {
+        println!("  {slot}");
+    }
+    Ok(())
+}
+
+/// Wrap the data key of the vault in a new slot, opened by another passphrase
+pub fn add_slot(config: &Config, label: &str) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let (mut vault, _, _) = unlock(config, &config.vault, "Master key: ")?;
+    let password = SecretString::new(rpassword::prompt_password(format!(
+        "Passphrase of {label}: "
+    ))?);
+    let confirm = SecretString::new(rpassword::prompt_password("Confirm the passphrase: ")?);
+    if password.is_empty() {
+        return Err(anyhow!("The passphrase cannot be empty"));
+    }
+    if password.expose() != confirm.expose() {
+        return Err(anyhow!("The passphrases do not match"));
+    }
+
+    let kdf = calibrate_kdf(config.kdf, config.unlock_time);
+    vault
+        .keys
+        .add_passphrase(label, password.expose(), None, &kdf)?;
+
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    println!("🔑 Key slot {label} added");
+
+    Ok(())
+}
+
+/// Remove a slot, its passphrase does not open the vault anymore
+pub fn revoke_slot(config: &Config, label: &str) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let (mut vault, _, _) = unlock(config, &config.vault, "Master key: ")?;
+    vault.keys.revoke(label)?;
+
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    println!("🗑️ Key slot {label} revoked");
+    if config.backups > 0 {
+        println!("💡 The backups made before still open with the passphrase of {label}");
+    }
+
+    Ok(())
+}
+
+pub fn create_identity(path: &Path) -> Result<(), anyhow::Error> {
+    let recipient = recipient::create_identity(path)?;
+    println!("🪪 Identity written to {}", path.display());
+    println!("💡 Give your public key to the owner of the vault: {recipient}");
+    Ok(())
+}
+
+/// Wrap the data key of the vault to the public key of a teammate, who then
+/// opens it with `--identity`
+pub fn add_recipient(config: &Config, label: &str, recipient: &str) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let recipient: Recipient = recipient.parse()?;
+    let (mut vault, _, _) = unlock(config, &config.vault, "Master key: ")?;
+    vault.keys.add_recipient(label, &recipient)?;
+
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    println!("🪪 {recipient} added as {label}");
+
+    Ok(())
+}
+
+/// Remove a recipient, then encrypt the whole vault with a new data key
+/// wrapped to the others
+pub fn remove_recipient(config: &Config, label: &str) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let (mut vault, password, keyfile) = unlock(config, &config.vault, "Master key: ")?;
+    vault
+        .keys
+        .remove_recipient(label, password.expose(), keyfile.as_ref())?;
+
+    backup_vault(&config.vault, config.backups)?;
+    println!("⏳ Encrypting the vault with a new data key");
+    encrypt_small_file(&vault.plaintext, &config.vault, &vault.keys)?;
+    println!("🗑️ Recipient {label} removed");
+    if config.backups > 0 {
+        println!("💡 The backups made before still open with the identity of {label}");
+    }
+
+    Ok(())
+}
+
+/// Wrap the data key to a new recovery key, and print it, or its `shares`
+/// when given as `(shares, threshold)`
+pub fn create_recovery_key(config: &Config, shares: Option<(u8, u8)>) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let recovery = RecoveryKey::generate();
+    let printed = match shares {
+        Some((shares, threshold)) => recovery.split(threshold, shares)?,
+        None => vec![recovery.to_printable()],
+    };
+    let (mut vault, _, _) = unlock(config, &config.vault, "Master key: ")?;
+    let replaced = vault.keys.slots().iter().any(|slot| slot.is_recovery());
+    vault.keys.set_recovery_key(&recovery)?;
+
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    if replaced {
+        println!("♻️ The previous recovery key does not open the vault anymore");
+    }
+
+    match shares {
+        Some((shares, threshold)) => {
+            println!(
+                "🧩 Recovery key split in {shares} shares, any {threshold} of them open the vault:"
+            );
+            for (index, share) in printed.iter().enumerate() {
+                println!("\n  {}: {}", index + 1, **share);
+            }
+            println!("\n💡 Print them, and give each one to a different person");
+        }
+        None => {
+            println!("🛟 Recovery key of the vault:\n\n  {}\n", *printed[0]);
+            println!("💡 Print it, and keep it somewhere safe: it opens the vault without its master key");
+        }
+    }
+
+    Ok(())
+}
+
+/// Open the vault with its recovery key, and set a new master key for the
+/// slot created along with the vault
+pub fn reset_master_key(config: &Config) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let typed = SecretString::new(rpassword::prompt_password(
+        "Recovery key, or its shares one after the other: ",
+    )?);
+    let recovery = RecoveryKey::parse(typed.expose())?;
+    let mut vault = unlock_small_file_with_recovery_key(&config.vault, &recovery)?;
+    println!("🛟 Vault opened with its recovery key");
+
+    let password = SecretString::new(rpassword::prompt_password("New master key: ")?);
+    let confirm = SecretString::new(rpassword::prompt_password("Confirm the master key: ")?);
+    if password.is_empty() {
+        return Err(anyhow!("The master key cannot be empty"));
+    }
+    if password.expose() != confirm.expose() {
+        return Err(anyhow!("The master keys do not match"));
+    }
+
+    let kdf = calibrate_kdf(config.kdf, config.unlock_time);
+    vault
+        .keys
+        .reset_passphrase(DEFAULT_SLOT_LABEL, password.expose(), &kdf)?;
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    println!("🔑 New master key set for the key slot {DEFAULT_SLOT_LABEL}");
+
+    Ok(())
+}
+
+/// Seal a new empty decoy vault with a duress password, in place of any
+/// previous decoy
+pub fn set_duress_password(config: &Config, destroy: bool) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let (vault, _, keyfile) = unlock(config, &config.vault, "Master key: ")?;
+    let password = SecretString::new(rpassword::prompt_password("Duress password: ")?);
+    let confirm = SecretString::new(rpassword::prompt_password("Confirm the duress password: ")?);
+    if password.is_empty() {
+        return Err(anyhow!("The duress password cannot be empty"));
+    }
+    if password.expose() != confirm.expose() {
+        return Err(anyhow!("The duress passwords do not match"));
+    }
+    // The real vault is always tried first, the decoy would never open
+    if VaultKeys::unlock(vault.keys.slots(), password.expose(), keyfile.as_ref()).is_ok() {
+        return Err(anyhow!(
+            "The duress password already opens a key slot of the vault"
+        ));
+    }
+
+    println!("⏳ Sealing an empty decoy vault");
+    let decoy = DuressKey::new(password.expose(), destroy)?;
+    let plaintext = dump_database(&init_database_schemas()?)?;
+    backup_vault(&config.vault, config.backups)?;
+    // Older formats have no room for a decoy yet
+    save_key_slots(&config.vault, &vault)?;
+    write_decoy(&config.vault, &plaintext, None, &decoy)?;
+
+    println!("🎭 Duress password set, any previous decoy is gone");
+    println!("💡 Open the vault with the duress password to fill the decoy with believable keys");
+    if destroy {
+        println!(
+            "💣 Opening the decoy destroys the key slots of the vault and of its backups, for good"
+        );
+    }
+    Ok(())
+}
+
+/// Fill the decoy area with random bytes again
+pub fn clear_duress_password(config: &Config) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let (vault, _, _) = unlock(config, &config.vault, "Master key: ")?;
+    backup_vault(&config.vault, config.backups)?;
+    save_key_slots(&config.vault, &vault)?;
+    clear_decoy(&config.vault)?;
+    println!("🎭 Decoy removed, no duress password opens anything anymore");
+    if config.backups > 0 {
+        println!("💡 The backups made before still hold the decoy");
+    }
+    Ok(())
+}
+
+/// Decrypt the vault and each of its backups in memory, and check the
+/// databases they hold.
+///
+/// Fails when a problem is found, so a scheduled run reports it.
+pub fn verify(config: &Config) -> Result<(), anyhow::Error> {
+    check_vault(config)?;
+
+    let credentials = Credentials::ask(config, "Master key: ")?;
+    // Most backups share the data key of the vault, the key derivation only
+    // runs again for the others
+    let mut data_keys: Vec<MasterKey> = Vec::new();
+    let report = verify_vault(&config.vault, |path| {
+        for key in &data_keys {
+            match decrypt_small_file(path, key) {
+                Err(err) if err.is_wrong_password() => continue,
+                res => return res,
+            }
+        }
+        let vault = credentials.open(path)?;
+        data_keys.push(vault.keys.data_key().clone());
+        Ok(vault.plaintext)
+    });
+
+    print!("{report}");
+    if report.has_problems() {
+        return Err(anyhow!(
+            "{} problem(s) found in {} or its backups",
+            report.problems(),
+            config.vault
+        ));
+    }
+    Ok(())
+}
+
+fn check_vault(config: &Config) -> Result<(), anyhow::Error> {
+    if !Path::new(&config.vault).exists() {
+        return Err(anyhow!("No vault found at {}", config.vault));
+    }
+    Ok(())
+}
+
+/// What opens the vault: the master key, with the keyfile given with
+/// `--keyfile` if any, or the identity given with `--identity`
+enum Credentials {
+    MasterKey {
+        password: SecretString,
+        keyfile: Option<Keyfile>,
+    },
+    Identity(Identity),
+}
+
+impl Credentials {
+    /// Read the identity given with `--identity`, or ask for the master key
+    fn ask(config: &Config, prompt: &str) -> Result<Self, anyhow::Error> {
+        if let Some(identity) = &config.identity {
+            let identity = Identity::read(identity)
+                .map_err(|err| anyhow!("Cannot read the identity {}: {err}", identity.display()))?;
+            return Ok(Self::Identity(identity));
+        }
+
+        let keyfile =
+            match &config.keyfile {
+                Some(keyfile) => Some(Keyfile::read(keyfile).map_err(|err| {
+                    anyhow!("Cannot read the keyfile {}: {err}", keyfile.display())
+                })?),
+                None => None,
+            };
+        let password = SecretString::new(rpassword::prompt_password(prompt)?);
+        Ok(Self::MasterKey { password, keyfile })
+    }
+
+    fn open(&self, path: &str) -> Result<UnlockedVault, UnlockError> {
+        match self {
+            Self::MasterKey { password, keyfile } => {
+                unlock_small_file(path, password.expose(), keyfile.as_ref())
+            }
+            Self::Identity(identity) => unlock_small_file_with_identity(path, identity),
+        }
+    }
+}
+
+/// Ask for the master key and open the vault at `path`, with the keyfile
+/// given with `--keyfile` if any, or open it with the identity given with
+/// `--identity` without asking anything.
+///
+/// Also returns the master key, and the keyfile if the slot opened needs it.
+fn unlock(
+    config: &Config,
+    path: &str,
+    prompt: &str,
+) -> Result<(UnlockedVault, SecretString, Option<Keyfile>), anyhow::Error> {
+    let credentials = Credentials::ask(config, prompt)?;
+    let vault = match credentials.open(path) {
+        Err(UnlockError::KeyfileRequired) => {
+            return Err(anyhow!(
+                "{}\n💡 Pass it with `--keyfile <PATH>`",
+                UnlockError::KeyfileRequired
+            ))
+        }
+        res => res?,
+    };
+
+    match credentials {
+        Credentials::MasterKey { password, keyfile } => {
+            let keyfile = keyfile.filter(|_| vault.keys.opened().requires_keyfile());
+            Ok((vault, password, keyfile))
+        }
+        Credentials::Identity(_) => Ok((vault, SecretString::default(), None)),
+    }
+}
+
+/// Cost of a key derivation, only comparable between the same algorithms
+fn work_factor(kdf: &KdfParams) -> u64 {
+    match kdf {
+        KdfParams::Pbkdf2Sha256 { iterations, .. } => *iterations as u64,
+        KdfParams::Argon2id { memory, time, .. } => *memory as u64 * *time as u64,
+    }
+}
@@ -0,0 +1,247 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+
+use crate::config::{Config, DEFAULT_BACKUPS, DEFAULT_LOCK_TIMEOUT, DEFAULT_VAULT_PATH};
+use crate::crypto::header::KdfAlgorithm;
+
+pub mod commands;
+
+/// A terminal password manager.
+///
+/// Without any command, the interactive interface is started.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Location of the encrypted vault, its backups and lock are kept next
+    /// to it
+    #[arg(long, global = true, default_value = DEFAULT_VAULT_PATH)]
+    pub vault: String,
+
+    /// Time in milliseconds spent deriving the key when unlocking a new vault
+    #[arg(long, global = true, default_value_t = 1000)]
+    pub unlock_time: u64,
+
+    /// Key derivation function used for new vaults and upgrades
+    #[arg(long, global = true, value_enum, default_value_t = KdfAlgorithm::Argon2id)]
+    pub kdf: KdfAlgorithm,
+
+    /// Number of previous generations of the vault to keep, 0 to disable
+    /// the backups
+    #[arg(long, global = true, default_value_t = DEFAULT_BACKUPS)]
+    pub backups: usize,
+
+    /// Open the vault read-only, even if another instance is using it
+    #[arg(long, global = true)]
+    pub read_only: bool,
+
+    /// Seconds of inactivity after which the vault is locked, 0 to never
+    /// lock it
+    #[arg(long, global = true, default_value_t = DEFAULT_LOCK_TIMEOUT.as_secs())]
+    pub lock_timeout: u64,
+
+    /// Keyfile required along with the master key, if the vault is bound to
+    /// one
+    #[arg(long, global = true)]
+    pub keyfile: Option<PathBuf>,
+
+    /// Identity file opening the vault instead of the master key, if the
+    /// vault is wrapped to its public key
+    #[arg(long, global = true)]
+    pub identity: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Raise the work factor of the key derivation of the key slot opened by
+    /// the master key, or move it from PBKDF2 to Argon2id
+    UpgradeKdf {
+        /// Iterations (PBKDF2) or passes (Argon2id) to use instead of
+        /// calibrating on this machine
+        #[arg(long)]
+        iterations: Option<u32>,
+    },
+    /// List the backups of the vault, or roll the vault back to one of them
+    Restore {
+        /// Number of the backup to restore, as listed without this argument
+        backup: Option<usize>,
+    },
+    /// Manage the keyfile required along with the master key
+    Keyfile {
+        #[command(subcommand)]
+        action: KeyfileAction,
+    },
+    /// Manage the key slots: each one opens the vault with its own passphrase
+    /// or identity
+    Slot {
+        #[command(subcommand)]
+        action: SlotAction,
+    },
+    /// Create an X25519 identity, to be added as a recipient of a vault
+    Identity {
+        /// Where to write the identity, only readable by you
+        path: PathBuf,
+    },
+    /// Manage the teammates whose identity opens the vault
+    Recipient {
+        #[command(subcommand)]
+        action: RecipientAction,
+    },
+    /// Open the vault without its master key
+    Recovery {
+        #[command(subcommand)]
+        action: RecoveryAction,
+    },
+    /// Manage the duress password, opening a decoy vault instead of the real
+    /// one
+    Duress {
+        #[command(subcommand)]
+        action: DuressAction,
+    },
+    /// Check the vault and its backups decrypt and hold a healthy database,
+    /// exiting with an error otherwise
+    Verify,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DuressAction {
+    /// Set a duress password opening a new empty decoy vault, replacing any
+    /// previous decoy
+    Set {
+        /// Destroy the key slots of the real vault, and of its backups, when
+        /// the decoy is opened
+        #[arg(long)]
+        destroy: bool,
+    },
+    /// Remove the decoy vault, no duress password opens anything anymore
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RecoveryAction {
+    /// Print a new recovery key, replacing the previous one
+    Create {
+        /// Split the recovery key in this many shares instead
+        #[arg(long, requires = "threshold")]
+        shares: Option<u8>,
+        /// Number of shares needed to rebuild the recovery key
+        #[arg(long, requires = "shares")]
+        threshold: Option<u8>,
+    },
+    /// Open the vault with its recovery key, or enough of its shares, and set
+    /// a new master key
+    Reset,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RecipientAction {
+    /// Wrap the data key to the public key of a teammate
+    Add {
+        /// Name of the slot, for example the teammate using it
+        label: String,
+        /// Public key of the teammate, `age1…`
+        recipient: String,
+    },
+    /// Remove a recipient, and encrypt the vault with a new data key wrapped
+    /// to the others
+    Remove {
+        /// Name of the slot of the recipient
+        label: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SlotAction {
+    /// List the key slots of the vault
+    List,
+    /// Add a key slot opened by a new passphrase
+    Add {
+        /// Name of the slot, for example the teammate using it
+        label: String,
+    },
+    /// Remove a key slot, its passphrase does not open the vault anymore
+    Revoke {
+        /// Name of the slot to remove
+        label: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyfileAction {
+    /// Write a new random keyfile
+    Create {
+        /// Where to write the keyfile, for example on a removable drive
+        path: PathBuf,
+    },
+    /// Require a keyfile along with the master key, from now on
+    Bind {
+        /// Keyfile to bind the vault to
+        path: PathBuf,
+    },
+    /// Stop requiring a keyfile along with the master key
+    Unbind,
+}
+
+impl Cli {
+    pub fn config(&self) -> Config {
+        Config {
+            vault: self.vault.clone(),
+            unlock_time: Duration::from_millis(self.unlock_time),
+            kdf: self.kdf,
+            backups: self.backups,
+            read_only: self.read_only,
+            lock_timeout: (self.lock_timeout > 0).then(|| Duration::from_secs(self.lock_timeout)),
+            keyfile: self.keyfile.clone(),
+            identity: self.identity.clone(),
+            ..Config::default()
+        }
+    }
+}
+
+/// Run a command outside of the interactive interface
+pub fn run(command: Command, config: &Config) -> Result<(), anyhow::Error> {
+    // Only checking the vault leaves it as it is
+    if config.read_only && !matches!(command, Command::Verify) {
+        return Err(anyhow!(
+            "This command modifies the vault, it cannot run read-only"
+        ));
+    }
+
+    match command {
+        Command::UpgradeKdf { iterations } => commands::upgrade_kdf(config, iterations),
+        Command::Restore { backup } => commands::restore(config, backup),
+        Command::Keyfile { action } => match action {
+            KeyfileAction::Create { path } => commands::create_keyfile(&path),
+            KeyfileAction::Bind { path } => commands::bind_keyfile(config, Some(&path)),
+            KeyfileAction::Unbind => commands::bind_keyfile(config, None),
+        },
+        Command::Slot { action } => match action {
+            SlotAction::List => commands::list_slots(config),
+            SlotAction::Add { label } => commands::add_slot(config, &label),
+            SlotAction::Revoke { label } => commands::revoke_slot(config, &label),
+        },
+        Command::Identity { path } => commands::create_identity(&path),
+        Command::Recipient { action } => match action {
+            RecipientAction::Add { label, recipient } => {
+                commands::add_recipient(config, &label, &recipient)
+            }
+            RecipientAction::Remove { label } => commands::remove_recipient(config, &label),
+        },
+        Command::Recovery { action } => match action {
+            RecoveryAction::Create { shares, threshold } => {
+                commands::create_recovery_key(config, shares.zip(threshold))
+            }
+            RecoveryAction::Reset => commands::reset_master_key(config),
+        },
+        Command::Duress { action } => match action {
+            DuressAction::Set { destroy } => commands::set_duress_password(config, destroy),
+            DuressAction::Clear => commands::clear_duress_password(config),
+        },
+        Command::Verify => commands::verify(config),
+    }
+}
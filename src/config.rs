@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::crypto::header::KdfAlgorithm;
+use crate::crypto::utils::DEFAULT_UNLOCK_TIME;
+use crate::repository::Backend;
+
+/// Location of the encrypted vault by default
+pub const DEFAULT_VAULT_PATH: &str = "./keys.db.encrypt";
+/// Number of previous generations of the vault kept by default
+pub const DEFAULT_BACKUPS: usize = 10;
+
+/// Idle time after which the vault is locked by default
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Runtime configuration of the application
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Location of the encrypted vault
+    pub vault: String,
+    /// Where the keys of the opened vault are kept
+    pub backend: Backend,
+    /// Time spent deriving the key when unlocking a new vault
+    pub unlock_time: Duration,
+    /// Key derivation function used for new vaults
+    pub kdf: KdfAlgorithm,
+    /// Number of previous generations of the vault to keep
+    pub backups: usize,
+    /// Open the vault without taking its lock, and never write it
+    pub read_only: bool,
+    /// Idle time after which the vault is locked, never when `None`
+    pub lock_timeout: Option<Duration>,
+    /// Keyfile to unlock the vault with, when it is bound to one
+    pub keyfile: Option<PathBuf>,
+    /// Identity opening the vault instead of the master key, when it is one
+    /// of its recipients
+    pub identity: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            vault: DEFAULT_VAULT_PATH.to_string(),
+            backend: Backend::default(),
+            unlock_time: DEFAULT_UNLOCK_TIME,
+            kdf: KdfAlgorithm::Argon2id,
+            backups: DEFAULT_BACKUPS,
+            read_only: false,
+            lock_timeout: Some(DEFAULT_LOCK_TIMEOUT),
+            keyfile: None,
+            identity: None,
+        }
+    }
+}
+
+impl Config {
+    /// Where the vault used to be decrypted, before it was only kept in
+    /// memory: next to it, without the `.encrypt` extension
+    /// ```
+    /// use rust_password_manager::config::Config;
+    ///
+    /// assert_eq!(Config::default().plaintext_path(), "./keys.db");
+    /// let config = Config {
+    ///     vault: "/srv/vault".to_string(),
+    ///     ..Config::default()
+    /// };
+    /// assert_eq!(config.plaintext_path(), "/srv/vault.plaintext");
+    /// ```
+    pub fn plaintext_path(&self) -> String {
+        match self.vault.strip_suffix(".encrypt") {
+            Some(plaintext) if !plaintext.is_empty() => plaintext.to_string(),
+            _ => format!("{}.plaintext", self.vault),
+        }
+    }
+}
@@ -0,0 +1,505 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+use super::error::UnlockError;
+use super::header::{
+    random_decoy_area, HeaderKeys, KdfParams, Reader, VaultHeader, DECOY_AREA_LEN, NONCE_LEN,
+    SALT_LEN,
+};
+use super::memory;
+use super::secret::MasterKey;
+use super::slot::{KeySlot, MAX_SLOTS};
+use super::utils::{gen_key_from_password, ARGON2_MEMORY, ARGON2_PARALLELISM};
+use crate::storage::backup::list_backups;
+use crate::storage::{rename_synced, write_synced};
+
+/// Passes of Argon2id over the duress password. Every vault uses the same
+/// parameters, so they reveal nothing.
+const DURESS_ARGON2_TIME: u32 = 3;
+/// Binds a decoy to this application
+const DECOY_AAD: &[u8] = b"rust_password_manager decoy";
+/// Size of the authentication tag closing the decoy
+const TAG_LEN: usize = 16;
+/// Opening the decoy destroys the key slots of the real vault
+const FLAG_DESTROY_SLOTS: u8 = 1;
+/// The key slots shown along the decoy follow its database
+const FLAG_SLOTS: u8 = 2;
+/// Flags and length of the database, sealed before the database itself
+const PREFIX_LEN: usize = 1 + 4;
+/// Largest database a decoy can hold
+pub const MAX_DECOY_LEN: usize = DECOY_AREA_LEN - SALT_LEN - NONCE_LEN - TAG_LEN - PREFIX_LEN;
+
+/// The key sealing a decoy vault in the decoy area of a vault, derived from
+/// the duress password.
+///
+/// The decoy area of every vault looks the same: a salt, a nonce and a
+/// ciphertext of a fixed size, all random unless a duress password has been
+/// set.
+pub struct DuressKey {
+    salt: [u8; SALT_LEN],
+    key: MasterKey,
+    destroy_slots: bool,
+}
+
+impl DuressKey {
+    /// A key for a new decoy, with a fresh salt
+    pub fn new(password: &str, destroy_slots: bool) -> Result<Self, anyhow::Error> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(password, salt, destroy_slots)
+    }
+
+    fn derive(
+        password: &str,
+        salt: [u8; SALT_LEN],
+        destroy_slots: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let kdf = KdfParams::Argon2id {
+            memory: ARGON2_MEMORY,
+            time: DURESS_ARGON2_TIME,
+            parallelism: ARGON2_PARALLELISM,
+            salt: salt.to_vec(),
+        };
+        let key = gen_key_from_password(password, None, &kdf)?;
+        Ok(Self {
+            salt,
+            key,
+            destroy_slots,
+        })
+    }
+
+    /// Opening the decoy destroys the key slots of the real vault
+    pub fn destroys_slots(&self) -> bool {
+        self.destroy_slots
+    }
+
+    /// Check `password` is the duress password
+    pub fn check_password(&self, password: &str) -> bool {
+        Self::derive(password, self.salt, self.destroy_slots)
+            .is_ok_and(|other| other.key == self.key)
+    }
+
+    /// A key for the same decoy, derived from another duress password
+    pub fn with_password(&self, password: &str) -> Result<Self, anyhow::Error> {
+        Self::new(password, self.destroy_slots)
+    }
+
+    /// Decrypt the decoy sealed with this key in the vault at `path`, one of
+    /// its backups for example.
+    ///
+    /// A decoy sealed with another key fails like a wrong master key.
+    pub fn open(&self, path: &str) -> Result<Zeroizing<Vec<u8>>, UnlockError> {
+        let file_data = fs::read(path)?;
+        let Some((area, _)) = read_decoy_area(&file_data)? else {
+            return Err(UnlockError::WrongPassword);
+        };
+        if area[..SALT_LEN] != self.salt {
+            return Err(UnlockError::WrongPassword);
+        }
+        let (plaintext, ..) = self.unseal(area)?.ok_or(UnlockError::WrongPassword)?;
+        Ok(plaintext)
+    }
+
+    /// Encrypt `plaintext` into a decoy area, padded to its fixed size, with
+    /// the key `slots` shown along it if any
+    fn seal(&self, plaintext: &[u8], slots: Option<&[KeySlot]>) -> Result<Vec<u8>, anyhow::Error> {
+        let mut slot_bytes = Vec::new();
+        if let Some(slots) = slots {
+            slot_bytes.push(slots.len() as u8);
+            for slot in slots {
+                slot.write(&mut slot_bytes);
+            }
+        }
+        if plaintext.len() + slot_bytes.len() > MAX_DECOY_LEN {
+            return Err(anyhow!(
+                "The decoy vault is full, it cannot hold more than {} KiB",
+                MAX_DECOY_LEN / 1024
+            ));
+        }
+
+        let mut padded = Zeroizing::new(vec![0u8; DECOY_AREA_LEN - SALT_LEN - NONCE_LEN - TAG_LEN]);
+        if self.destroy_slots {
+            padded[0] |= FLAG_DESTROY_SLOTS;
+        }
+        if slots.is_some() {
+            padded[0] |= FLAG_SLOTS;
+        }
+        padded[1..PREFIX_LEN].copy_from_slice(&(plaintext.len() as u32).to_le_bytes());
+        let end = PREFIX_LEN + plaintext.len();
+        padded[PREFIX_LEN..end].copy_from_slice(plaintext);
+        padded[end..end + slot_bytes.len()].copy_from_slice(&slot_bytes);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: &padded,
+            aad: DECOY_AAD,
+        };
+        let ciphertext = ChaCha20Poly1305::new((&*self.key).into())
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|err| anyhow!("Encrypting the decoy: {err}"))?;
+
+        let mut area = Vec::with_capacity(DECOY_AREA_LEN);
+        area.extend_from_slice(&self.salt);
+        area.extend_from_slice(&nonce);
+        area.extend_from_slice(&ciphertext);
+        Ok(area)
+    }
+
+    /// Decrypt the database of a decoy area, whether it destroys the slots of
+    /// the real vault and the slots shown along it, if the area has been
+    /// sealed with this key
+    fn unseal(&self, area: &[u8]) -> Result<Option<Unsealed>, UnlockError> {
+        let payload = Payload {
+            msg: &area[SALT_LEN + NONCE_LEN..],
+            aad: DECOY_AAD,
+        };
+        let nonce = Nonce::from_slice(&area[SALT_LEN..SALT_LEN + NONCE_LEN]);
+        let Ok(padded) = ChaCha20Poly1305::new((&*self.key).into()).decrypt(nonce, payload) else {
+            return Ok(None);
+        };
+        memory::lock(&padded);
+        let padded = Zeroizing::new(padded);
+
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&padded[1..PREFIX_LEN]);
+        let len = u32::from_le_bytes(len) as usize;
+        // Authenticated, so only a bug could write a wrong length
+        if len > MAX_DECOY_LEN {
+            return Err(UnlockError::Corrupted);
+        }
+
+        let slots = if padded[0] & FLAG_SLOTS != 0 {
+            let mut reader = Reader::new(&padded[PREFIX_LEN + len..]);
+            let count = reader.u8()? as usize;
+            if count > MAX_SLOTS {
+                return Err(UnlockError::Corrupted);
+            }
+            let slots = (0..count)
+                .map(|_| KeySlot::read(&mut reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(slots)
+        } else {
+            None
+        };
+
+        Ok(Some((
+            Zeroizing::new(padded[PREFIX_LEN..PREFIX_LEN + len].to_vec()),
+            padded[0] & FLAG_DESTROY_SLOTS != 0,
+            slots,
+        )))
+    }
+}
+
+/// The database of a decoy, whether opening it destroys the slots of the
+/// real vault, and the slots shown along it
+type Unsealed = (Zeroizing<Vec<u8>>, bool, Option<Vec<KeySlot>>);
+
+/// A decoy vault decrypted in memory
+pub struct Decoy {
+    pub plaintext: Zeroizing<Vec<u8>>,
+    pub key: DuressKey,
+    /// The slots shown along the decoy, the ones of the real vault until
+    /// they have been changed in the decoy
+    pub slots: Option<Vec<KeySlot>>,
+}
+
+/// Decrypt the decoy of the vault at `encrypted_file_path`, if one has been
+/// sealed with `password`.
+///
+/// Random bytes and a decoy sealed with another password are both `None`.
+/// ```
+/// use rust_password_manager::crypto::decoy::{clear_decoy, open_decoy, write_decoy, DuressKey};
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{encrypt_small_file, unlock_small_file};
+///
+/// let path = std::env::temp_dir().join(format!("rpm-decoy-{}", std::process::id()));
+/// let path = path.to_str().unwrap();
+/// let keys = VaultKeys::create("master", "real", None, &KdfParams::pbkdf2(1000)).unwrap();
+/// encrypt_small_file(b"secret", path, &keys).unwrap();
+/// let size = std::fs::metadata(path).unwrap().len();
+/// assert!(open_decoy(path, "duress").unwrap().is_none());
+///
+/// write_decoy(path, b"decoy", None, &DuressKey::new("duress", false).unwrap()).unwrap();
+/// let decoy = open_decoy(path, "duress").unwrap().unwrap();
+/// assert_eq!(*decoy.plaintext, b"decoy");
+/// assert!(decoy.slots.is_none());
+/// assert!(open_decoy(path, "real").unwrap().is_none());
+/// assert_eq!(*decoy.key.open(path).unwrap(), b"decoy");
+///
+/// // The real vault is left as it is, and keeps the decoy when saved
+/// assert_eq!(std::fs::metadata(path).unwrap().len(), size);
+/// assert_eq!(*unlock_small_file(path, "real", None).unwrap().plaintext, b"secret");
+/// encrypt_small_file(b"new secret", path, &keys).unwrap();
+/// assert!(open_decoy(path, "duress").unwrap().is_some());
+///
+/// // Slots changed along the decoy are sealed with it
+/// write_decoy(path, b"decoy", Some(keys.slots()), &decoy.key).unwrap();
+/// let reopened = open_decoy(path, "duress").unwrap().unwrap();
+/// assert_eq!(reopened.slots.as_deref(), Some(keys.slots()));
+/// assert_eq!(*reopened.plaintext, b"decoy");
+///
+/// clear_decoy(path).unwrap();
+/// assert!(open_decoy(path, "duress").unwrap().is_none());
+/// assert!(decoy.key.open(path).unwrap_err().is_wrong_password());
+/// std::fs::remove_file(path).unwrap();
+/// ```
+pub fn open_decoy(encrypted_file_path: &str, password: &str) -> Result<Option<Decoy>, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
+    let Some((area, _)) = read_decoy_area(&file_data)? else {
+        return Ok(None);
+    };
+
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&area[..SALT_LEN]);
+    let key = DuressKey::derive(password, salt, false)
+        .map_err(|err| UnlockError::BadHeader(err.to_string()))?;
+
+    let Some((plaintext, destroy_slots, slots)) = key.unseal(area)? else {
+        return Ok(None);
+    };
+    Ok(Some(Decoy {
+        plaintext,
+        key: DuressKey {
+            destroy_slots,
+            ..key
+        },
+        slots,
+    }))
+}
+
+/// Seal `plaintext` in the decoy area of the vault with `key`, along with
+/// the key `slots` shown with the decoy if they have been changed there. The
+/// real vault is left as it is.
+///
+/// Only vaults written since [`DECOY_VERSION`](super::header::DECOY_VERSION)
+/// have a decoy area.
+pub fn write_decoy(
+    encrypted_file_path: &str,
+    plaintext: &[u8],
+    slots: Option<&[KeySlot]>,
+    key: &DuressKey,
+) -> Result<(), anyhow::Error> {
+    replace_decoy_area(encrypted_file_path, &key.seal(plaintext, slots)?)
+}
+
+/// Fill the decoy area of the vault with random bytes again, so no duress
+/// password opens anything
+pub fn clear_decoy(encrypted_file_path: &str) -> Result<(), anyhow::Error> {
+    replace_decoy_area(encrypted_file_path, &random_decoy_area())
+}
+
+/// Overwrite the wrapped data keys of every slot of the vault and of its
+/// backups, so nothing opens the real vault anymore.
+///
+/// Each file is overwritten in place, at the same offsets, and flushed to
+/// the disk: a backup that is a hard link to the vault shares its blocks.
+/// A file that cannot be written in place is replaced by an overwritten
+/// copy instead. The copies a journaling filesystem or an SSD may keep
+/// elsewhere are out of reach.
+///
+/// A file failing does not stop the others, the errors are reported once
+/// every file has been tried.
+pub fn destroy_key_slots(encrypted_file_path: &str) -> Result<(), anyhow::Error> {
+    let mut paths = vec![PathBuf::from(encrypted_file_path)];
+    let mut errors = Vec::new();
+    match list_backups(encrypted_file_path) {
+        Ok(backups) => paths.extend(backups.into_iter().map(|backup| backup.path)),
+        Err(err) => errors.push(format!("listing the backups: {err}")),
+    }
+
+    for path in paths {
+        if let Err(err) = destroy_file_keys(&path) {
+            errors.push(format!("{}: {err}", path.display()));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "Cannot destroy every key slot, {}",
+            errors.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Overwrite the slots of a vault, or its body when its key is derived from
+/// the master key
+fn destroy_file_keys(path: &Path) -> Result<(), anyhow::Error> {
+    let mut file_data = Zeroizing::new(fs::read(path)?);
+    let range = match VaultHeader::parse(&file_data)? {
+        Some((mut header, header_len)) => match &mut header.keys {
+            HeaderKeys::Slots { slots, .. } => {
+                slots.iter_mut().for_each(|slot| slot.destroy());
+                let header = header.to_bytes();
+                file_data[..header_len].copy_from_slice(&header);
+                0..header_len
+            }
+            HeaderKeys::Derived { .. } => {
+                OsRng.fill_bytes(&mut file_data[header_len..]);
+                header_len..file_data.len()
+            }
+        },
+        None => {
+            OsRng.fill_bytes(&mut file_data);
+            0..file_data.len()
+        }
+    };
+
+    if overwrite_in_place(path, range.start as u64, &file_data[range]).is_err() {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        write_synced(&tmp, &file_data)?;
+        rename_synced(&tmp, path)?;
+    }
+    Ok(())
+}
+
+/// Write `bytes` over the content of `path` at `offset`, and flush them
+fn overwrite_in_place(path: &Path, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// The decoy area of a vault and its offset, if the vault has one
+fn read_decoy_area(file_data: &[u8]) -> Result<Option<(&[u8], usize)>, UnlockError> {
+    let Some((header, header_len)) = VaultHeader::parse(file_data)? else {
+        return Ok(None);
+    };
+    if header.decoy_area.is_none() {
+        return Ok(None);
+    }
+    let offset = header_len - DECOY_AREA_LEN;
+    Ok(Some((&file_data[offset..header_len], offset)))
+}
+
+/// Atomically replace the vault with a copy holding another decoy area
+fn replace_decoy_area(encrypted_file_path: &str, area: &[u8]) -> Result<(), anyhow::Error> {
+    let mut file_data = fs::read(encrypted_file_path)?;
+    let (_, offset) = read_decoy_area(&file_data)?.ok_or(anyhow!(
+        "The vault has no decoy area, it has to be saved once in the current format"
+    ))?;
+    file_data[offset..offset + DECOY_AREA_LEN].copy_from_slice(area);
+
+    let tmp = format!("{encrypted_file_path}.tmp");
+    write_synced(&tmp, &file_data)?;
+    if fs::read(&tmp)? != file_data {
+        fs::remove_file(&tmp)?;
+        return Err(anyhow!("The decoy has not been written correctly"));
+    }
+    rename_synced(&tmp, encrypted_file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::slot::VaultKeys;
+    use crate::crypto::utils::{encrypt_small_file, unlock_small_file};
+    use crate::storage::backup::{backup_dir, backup_vault};
+
+    #[test]
+    fn should_destroy_the_slots_of_the_vault_and_its_backups() {
+        let dir = std::env::temp_dir().join(format!("rpm-destroy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let vault = dir.join("keys.db.encrypt");
+        let vault = vault.to_str().unwrap();
+        let keys = VaultKeys::create("master", "real", None, &KdfParams::pbkdf2(1000)).unwrap();
+        let duress = DuressKey::new("duress", true).unwrap();
+
+        // Two backups, the last one a hard link to the vault itself
+        encrypt_small_file(b"first", vault, &keys).unwrap();
+        write_decoy(vault, b"decoy", None, &duress).unwrap();
+        backup_vault(vault, 5).unwrap();
+        encrypt_small_file(b"second", vault, &keys).unwrap();
+        backup_vault(vault, 5).unwrap();
+        let mut paths = vec![PathBuf::from(vault)];
+        paths.extend(list_backups(vault).unwrap().into_iter().map(|b| b.path));
+        assert_eq!(paths.len(), 3);
+        let sizes = paths
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .collect::<Vec<_>>();
+        #[cfg(unix)]
+        let inodes = paths
+            .iter()
+            .map(|path| std::os::unix::fs::MetadataExt::ino(&fs::metadata(path).unwrap()))
+            .collect::<Vec<_>>();
+        for path in &paths {
+            assert!(unlock_small_file(path.to_str().unwrap(), "real", None).is_ok());
+        }
+
+        destroy_key_slots(vault).unwrap();
+
+        for (path, size) in paths.iter().zip(sizes) {
+            let path = path.to_str().unwrap();
+            assert!(unlock_small_file(path, "real", None).is_err(), "{path}");
+            assert_eq!(fs::metadata(path).unwrap().len(), size, "{path}");
+            // Only the real vault is gone
+            assert_eq!(
+                *open_decoy(path, "duress").unwrap().unwrap().plaintext,
+                b"decoy"
+            );
+        }
+        let leftovers = fs::read_dir(backup_dir(vault))
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+            .count();
+        assert_eq!(leftovers, 0, "no temporary file is left");
+        #[cfg(unix)]
+        for (path, inode) in paths.iter().zip(inodes) {
+            let metadata = fs::metadata(path).unwrap();
+            let overwritten = std::os::unix::fs::MetadataExt::ino(&metadata);
+            assert_eq!(overwritten, inode, "{path:?} is overwritten in place");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_destroy_every_file_despite_a_broken_one() {
+        let dir = std::env::temp_dir().join(format!("rpm-destroy-broken-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let vault = dir.join("keys.db.encrypt");
+        let vault = vault.to_str().unwrap();
+        let keys = VaultKeys::create("master", "real", None, &KdfParams::pbkdf2(1000)).unwrap();
+
+        for generation in 0..4 {
+            encrypt_small_file(&[generation; 64], vault, &keys).unwrap();
+            if generation < 3 {
+                backup_vault(vault, 5).unwrap();
+            }
+        }
+        let backups = list_backups(vault).unwrap();
+        assert_eq!(backups.len(), 3);
+        // Cut in the middle of its header, it cannot be read anymore
+        fs::write(&backups[1].path, &fs::read(&backups[1].path).unwrap()[..8]).unwrap();
+
+        let err = destroy_key_slots(vault).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&backups[1].path.display().to_string()),
+            "{err}"
+        );
+        for path in [
+            PathBuf::from(vault),
+            backups[0].path.clone(),
+            backups[2].path.clone(),
+        ] {
+            let path = path.to_str().unwrap();
+            assert!(unlock_small_file(path, "real", None).is_err(), "{path}");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
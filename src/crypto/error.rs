@@ -0,0 +1,88 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+/// Reasons a vault cannot be unlocked
+#[derive(Debug)]
+pub enum UnlockError {
+    /// The master key, or the keyfile, does not match the key-check value of
+    /// the header
+    WrongPassword,
+    /// The vault has no key-check value, and the master key does not decrypt
+    /// it: either the master key is wrong or the vault is corrupted
+    WrongPasswordOrCorrupted,
+    /// The header is not one this application writes
+    BadHeader(String),
+    /// The vault has been written by a more recent version of the application
+    UnsupportedVersion(u8),
+    /// The database in the vault has been written by a more recent version of
+    /// the application
+    UnsupportedSchema(i64),
+    /// The vault can only be unlocked with a keyfile, and none was given
+    KeyfileRequired,
+    /// The keyfile cannot be read
+    Keyfile(io::Error),
+    /// No slot of the vault is wrapped to the identity given
+    NotRecipient,
+    /// The identity file cannot be read
+    Identity(io::Error),
+    /// The vault has no recovery slot
+    NoRecoveryKey,
+    /// The recovery key is not the one of the vault
+    WrongRecoveryKey,
+    /// The file ends before the vault does
+    Truncated,
+    /// The master key is right, but the content has been modified
+    Corrupted,
+    Io(io::Error),
+}
+
+impl UnlockError {
+    /// Check if trying another master key could unlock the vault
+    pub fn is_wrong_password(&self) -> bool {
+        matches!(self, Self::WrongPassword | Self::WrongPasswordOrCorrupted)
+    }
+}
+
+impl Display for UnlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WrongPassword => write!(f, "Wrong master key or keyfile"),
+            Self::WrongPasswordOrCorrupted => {
+                write!(f, "Wrong master key, or the vault is corrupted")
+            }
+            Self::BadHeader(reason) => write!(f, "Invalid vault header: {reason}"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Vault format version {version} is not supported, upgrade the application"
+            ),
+            Self::UnsupportedSchema(version) => write!(
+                f,
+                "Database schema version {version} is not supported, upgrade the application"
+            ),
+            Self::KeyfileRequired => write!(f, "This vault also requires its keyfile"),
+            Self::Keyfile(err) => write!(f, "Cannot read the keyfile: {err}"),
+            Self::NotRecipient => write!(f, "This identity is not a recipient of the vault"),
+            Self::Identity(err) => write!(f, "Cannot read the identity: {err}"),
+            Self::NoRecoveryKey => write!(f, "This vault has no recovery key"),
+            Self::WrongRecoveryKey => write!(f, "This is not the recovery key of the vault"),
+            Self::Truncated => write!(f, "The vault file is truncated"),
+            Self::Corrupted => write!(f, "The vault is corrupted, restore a backup"),
+            Self::Io(err) => write!(f, "Cannot read the vault: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UnlockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) | Self::Keyfile(err) | Self::Identity(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for UnlockError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
@@ -0,0 +1,682 @@
+use std::fmt::{Display, Formatter};
+
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+use super::error::UnlockError;
+use super::slot::{KeySlot, MAX_SLOTS};
+
+/// Every vault written by this application starts with these bytes
+pub const MAGIC: &[u8; 4] = b"RPMV";
+/// Current version of the vault file format
+pub const VERSION: u8 = 7;
+/// First version with a key-check value in the header
+pub const KEY_CHECK_VERSION: u8 = 2;
+/// First version with flags in the header
+pub const FLAGS_VERSION: u8 = 3;
+/// First version with a random data key wrapped in key slots
+pub const SLOTS_VERSION: u8 = 4;
+/// First version with an area for a decoy vault after the header
+pub const DECOY_VERSION: u8 = 5;
+/// First version with a cipher identifier in the header
+pub const CIPHER_VERSION: u8 = 6;
+/// First version with the length of the body in the header
+pub const BODY_LEN_VERSION: u8 = 7;
+/// Size of the area after the header, holding random bytes or a decoy vault
+pub const DECOY_AREA_LEN: usize = 64 * 1024;
+/// Size of the key-check value
+pub const KEY_CHECK_LEN: usize = 16;
+/// Size of the MAC closing the header
+pub const HEADER_MAC_LEN: usize = 32;
+/// Size of a ChaCha20Poly1305 nonce
+pub const NONCE_LEN: usize = 12;
+/// Size of an XChaCha20Poly1305 nonce
+pub const XNONCE_LEN: usize = 24;
+/// Size of the random salt generated for each vault
+pub const SALT_LEN: usize = 16;
+
+// The header is read before anything authenticates it: a key derivation
+// asking for more is refused rather than left to hang the application
+/// Most PBKDF2 iterations a header may ask for
+pub const MAX_PBKDF2_ITERATIONS: u32 = 100_000_000;
+/// Most memory a header may ask Argon2id for, in KiB: 4 GiB
+pub const MAX_ARGON2_MEMORY: u32 = 4 * 1024 * 1024;
+/// Most Argon2id passes a header may ask for
+pub const MAX_ARGON2_TIME: u32 = 64;
+/// Most Argon2id lanes a header may ask for
+pub const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+
+const CIPHER_CHACHA20_POLY1305: u8 = 1;
+const CIPHER_XCHACHA20_POLY1305: u8 = 2;
+
+/// The key is derived from the master key and a keyfile
+const FLAG_KEYFILE: u8 = 1;
+
+/// Key derivation functions a vault can be encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KdfAlgorithm {
+    Argon2id,
+    Pbkdf2,
+}
+
+/// Ciphers the body of a vault can be encrypted with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Cipher {
+    /// Used by every vault written before [`CIPHER_VERSION`]. Its nonce is
+    /// too short to be safely picked at random for every save.
+    ChaCha20Poly1305,
+    /// Its nonce is long enough to be picked at random without ever
+    /// repeating
+    #[default]
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => NONCE_LEN,
+            Self::XChaCha20Poly1305 => XNONCE_LEN,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Self::ChaCha20Poly1305 => CIPHER_CHACHA20_POLY1305,
+            Self::XChaCha20Poly1305 => CIPHER_XCHACHA20_POLY1305,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, UnlockError> {
+        match id {
+            CIPHER_CHACHA20_POLY1305 => Ok(Self::ChaCha20Poly1305),
+            CIPHER_XCHACHA20_POLY1305 => Ok(Self::XChaCha20Poly1305),
+            id => Err(UnlockError::BadHeader(format!("unknown cipher {id}"))),
+        }
+    }
+}
+
+impl Display for Cipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ChaCha20Poly1305 => write!(f, "ChaCha20-Poly1305"),
+            Self::XChaCha20Poly1305 => write!(f, "XChaCha20-Poly1305"),
+        }
+    }
+}
+
+/// Parameters needed to derive the vault key from the master password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KdfParams {
+    Pbkdf2Sha256 {
+        iterations: u32,
+        salt: Vec<u8>,
+    },
+    Argon2id {
+        /// Memory cost in KiB
+        memory: u32,
+        /// Number of passes over the memory
+        time: u32,
+        /// Number of lanes
+        parallelism: u32,
+        salt: Vec<u8>,
+    },
+}
+
+impl KdfParams {
+    /// PBKDF2 parameters with a fresh random salt
+    pub fn pbkdf2(iterations: u32) -> Self {
+        Self::Pbkdf2Sha256 {
+            iterations,
+            salt: random_salt(),
+        }
+    }
+
+    /// Argon2id parameters with a fresh random salt
+    pub fn argon2id(memory: u32, time: u32, parallelism: u32) -> Self {
+        Self::Argon2id {
+            memory,
+            time,
+            parallelism,
+            salt: random_salt(),
+        }
+    }
+
+    pub fn algorithm(&self) -> KdfAlgorithm {
+        match self {
+            Self::Pbkdf2Sha256 { .. } => KdfAlgorithm::Pbkdf2,
+            Self::Argon2id { .. } => KdfAlgorithm::Argon2id,
+        }
+    }
+
+    /// Parameters used by vaults written before the header existed
+    pub fn legacy() -> Self {
+        Self::Pbkdf2Sha256 {
+            iterations: 4096,
+            salt: b"salt".to_vec(),
+        }
+    }
+
+    /// Check if these are the constant parameters shared by every legacy vault
+    pub fn is_legacy(&self) -> bool {
+        *self == Self::legacy()
+    }
+}
+
+impl Display for KdfParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pbkdf2Sha256 { iterations, .. } => {
+                write!(f, "PBKDF2-SHA256 with {iterations} iterations")
+            }
+            Self::Argon2id {
+                memory,
+                time,
+                parallelism,
+                ..
+            } => write!(
+                f,
+                "Argon2id with {} MiB, {time} passes and {parallelism} lanes",
+                memory / 1024
+            ),
+        }
+    }
+}
+
+/// Keyed with the vault key, and computed over a constant: it reveals nothing
+/// about the key, but only the right key reproduces it
+pub(super) fn key_check_mac(key: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(b"rust_password_manager key check");
+    mac
+}
+
+/// Keyed with the data key, so only someone able to open a slot can change
+/// the slots
+fn header_mac(data_key: &[u8; 32], header: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(data_key).expect("HMAC accepts any key size");
+    mac.update(b"rust_password_manager header");
+    mac.update(header);
+    mac
+}
+
+/// Bytes filling the decoy area of a vault without a decoy
+pub fn random_decoy_area() -> Vec<u8> {
+    let mut area = vec![0u8; DECOY_AREA_LEN];
+    OsRng.fill_bytes(&mut area);
+    area
+}
+
+fn random_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Serialize key derivation parameters, as found in the header and the slots
+pub(super) fn write_kdf(kdf: &KdfParams, bytes: &mut Vec<u8>) {
+    match kdf {
+        KdfParams::Pbkdf2Sha256 { iterations, salt } => {
+            bytes.push(KDF_PBKDF2_SHA256);
+            bytes.extend_from_slice(&iterations.to_le_bytes());
+            bytes.push(salt.len() as u8);
+            bytes.extend_from_slice(salt);
+        }
+        KdfParams::Argon2id {
+            memory,
+            time,
+            parallelism,
+            salt,
+        } => {
+            bytes.push(KDF_ARGON2ID);
+            bytes.extend_from_slice(&memory.to_le_bytes());
+            bytes.extend_from_slice(&time.to_le_bytes());
+            bytes.extend_from_slice(&parallelism.to_le_bytes());
+            bytes.push(salt.len() as u8);
+            bytes.extend_from_slice(salt);
+        }
+    }
+}
+
+pub(super) fn read_kdf(reader: &mut Reader) -> Result<KdfParams, UnlockError> {
+    let kdf = match reader.u8()? {
+        KDF_PBKDF2_SHA256 => {
+            let iterations = at_most(reader.u32()?, MAX_PBKDF2_ITERATIONS, "PBKDF2 iterations")?;
+            let salt_len = reader.u8()? as usize;
+            let salt = reader.take(salt_len)?.to_vec();
+            KdfParams::Pbkdf2Sha256 { iterations, salt }
+        }
+        KDF_ARGON2ID => {
+            let memory = at_most(reader.u32()?, MAX_ARGON2_MEMORY, "KiB of Argon2id memory")?;
+            let time = at_most(reader.u32()?, MAX_ARGON2_TIME, "Argon2id passes")?;
+            let parallelism = at_most(reader.u32()?, MAX_ARGON2_PARALLELISM, "Argon2id lanes")?;
+            let salt_len = reader.u8()? as usize;
+            let salt = reader.take(salt_len)?.to_vec();
+            KdfParams::Argon2id {
+                memory,
+                time,
+                parallelism,
+                salt,
+            }
+        }
+        kdf => {
+            return Err(UnlockError::BadHeader(format!(
+                "unknown key derivation function {kdf}"
+            )))
+        }
+    };
+    Ok(kdf)
+}
+
+/// Refuse a key derivation parameter above `max`
+fn at_most(value: u32, max: u32, what: &str) -> Result<u32, UnlockError> {
+    if value > max {
+        return Err(UnlockError::BadHeader(format!(
+            "{value} {what}, more than the {max} allowed"
+        )));
+    }
+    Ok(value)
+}
+
+/// How the key encrypting the body is obtained
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderKeys {
+    /// Before [`SLOTS_VERSION`], the body is encrypted with the key derived
+    /// from the master key
+    Derived {
+        kdf: KdfParams,
+        /// A keyfile is needed along with the master key
+        keyfile: bool,
+        /// Tells a wrong master key from a corrupted vault, absent before
+        /// [`KEY_CHECK_VERSION`]
+        key_check: Option<[u8; KEY_CHECK_LEN]>,
+    },
+    /// The body is encrypted with a random data key, wrapped in each slot
+    Slots {
+        slots: Vec<KeySlot>,
+        /// Authenticates the whole header with the data key
+        mac: [u8; HEADER_MAC_LEN],
+    },
+}
+
+/// Header stored in clear at the beginning of an encrypted vault.
+///
+/// Its content is authenticated, so tampering with any of its fields makes
+/// the decryption fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultHeader {
+    pub version: u8,
+    /// Always ChaCha20Poly1305 before [`CIPHER_VERSION`]
+    pub cipher: Cipher,
+    /// As long as the nonces of the cipher
+    pub nonce: Vec<u8>,
+    /// Length of the ciphertext following the header, tag included. Absent
+    /// before [`BODY_LEN_VERSION`].
+    pub body_len: Option<u64>,
+    pub keys: HeaderKeys,
+    /// Random bytes, or a decoy vault sealed with a duress password: nothing
+    /// tells them apart. Absent before [`DECOY_VERSION`], and left out of
+    /// the MAC so the decoy can change without the data key.
+    pub decoy_area: Option<Vec<u8>>,
+}
+
+impl VaultHeader {
+    /// Create a header for a new encryption with `data_key` of a body
+    /// `body_len` bytes long, with the default cipher and a fresh random nonce
+    pub fn new(slots: Vec<KeySlot>, data_key: &[u8; 32], body_len: u64) -> Self {
+        let cipher = Cipher::default();
+        let mut nonce = vec![0u8; cipher.nonce_len()];
+        OsRng.fill_bytes(&mut nonce);
+        Self::with_nonce(slots, data_key, cipher, nonce, body_len)
+    }
+
+    /// Create a header for a body already encrypted with `data_key`,
+    /// `cipher` and `nonce`, when only the slots change.
+    ///
+    /// The decoy area is filled with random bytes, see
+    /// [`with_decoy_area`](Self::with_decoy_area) to keep the one of the
+    /// vault.
+    pub fn with_nonce(
+        slots: Vec<KeySlot>,
+        data_key: &[u8; 32],
+        cipher: Cipher,
+        nonce: Vec<u8>,
+        body_len: u64,
+    ) -> Self {
+        let mut header = Self {
+            version: VERSION,
+            cipher,
+            nonce,
+            body_len: Some(body_len),
+            keys: HeaderKeys::Slots {
+                slots,
+                mac: [0u8; HEADER_MAC_LEN],
+            },
+            decoy_area: Some(random_decoy_area()),
+        };
+
+        let tag = header_mac(data_key, &header.unauthenticated_bytes()).finalize();
+        if let HeaderKeys::Slots { mac, .. } = &mut header.keys {
+            mac.copy_from_slice(&tag.into_bytes());
+        }
+        header
+    }
+
+    /// Use the decoy area of another header, if it has one
+    pub fn with_decoy_area(mut self, decoy_area: Option<Vec<u8>>) -> Self {
+        if decoy_area.is_some() {
+            self.decoy_area = decoy_area;
+        }
+        self
+    }
+
+    /// Check `key` against the key-check value, or the header MAC when the
+    /// vault has key slots.
+    ///
+    /// Headers written before the key-check value existed accept any key.
+    /// ```
+    /// use rust_password_manager::crypto::header::VaultHeader;
+    ///
+    /// let header = VaultHeader::new(Vec::new(), &[1u8; 32], 16);
+    /// assert!(header.check_key(&[1u8; 32]));
+    /// assert!(!header.check_key(&[2u8; 32]));
+    /// ```
+    pub fn check_key(&self, key: &[u8; 32]) -> bool {
+        match &self.keys {
+            HeaderKeys::Derived {
+                key_check: Some(expected),
+                ..
+            } => key_check_mac(key).verify_truncated_left(expected).is_ok(),
+            HeaderKeys::Derived { .. } => true,
+            HeaderKeys::Slots { mac, .. } => header_mac(key, &self.unauthenticated_bytes())
+                .verify_slice(mac)
+                .is_ok(),
+        }
+    }
+
+    /// Check if a keyfile may be needed to unlock the vault
+    pub fn requires_keyfile(&self) -> bool {
+        match &self.keys {
+            HeaderKeys::Derived { keyfile, .. } => *keyfile,
+            HeaderKeys::Slots { slots, .. } => slots.iter().any(KeySlot::requires_keyfile),
+        }
+    }
+
+    /// The bytes authenticated along with the body.
+    ///
+    /// With key slots, it leaves the slots out, so they can change without
+    /// encrypting the body again.
+    pub fn associated_data(&self) -> Vec<u8> {
+        match &self.keys {
+            HeaderKeys::Derived { .. } => self.to_bytes(),
+            HeaderKeys::Slots { .. } => {
+                let mut bytes = Vec::new();
+                bytes.extend_from_slice(MAGIC);
+                bytes.push(self.version);
+                if self.version >= CIPHER_VERSION {
+                    bytes.push(self.cipher.id());
+                }
+                bytes.extend_from_slice(&self.nonce);
+                if let Some(body_len) = self.body_len {
+                    bytes.extend_from_slice(&body_len.to_le_bytes());
+                }
+                bytes
+            }
+        }
+    }
+
+    /// Serialize the header as it is written on disk
+    /// ```
+    /// use rust_password_manager::crypto::header::{KdfParams, VaultHeader};
+    /// use rust_password_manager::crypto::slot::VaultKeys;
+    ///
+    /// for kdf in [KdfParams::pbkdf2(1000), KdfParams::argon2id(1024, 1, 1)] {
+    ///     let keys = VaultKeys::create("master", "password", None, &kdf).unwrap();
+    ///     let header = VaultHeader::new(keys.slots().to_vec(), keys.data_key(), 16);
+    ///     let bytes = header.to_bytes();
+    ///     let (parsed, len) = VaultHeader::parse(&bytes).unwrap().unwrap();
+    ///     assert_eq!(parsed, header);
+    ///     assert_eq!(len, bytes.len());
+    /// }
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.unauthenticated_bytes();
+        match &self.keys {
+            HeaderKeys::Derived {
+                key_check: Some(key_check),
+                ..
+            } => bytes.extend_from_slice(key_check),
+            HeaderKeys::Derived { .. } => (),
+            HeaderKeys::Slots { mac, .. } => bytes.extend_from_slice(mac),
+        }
+        if let Some(decoy_area) = &self.decoy_area {
+            bytes.extend_from_slice(decoy_area);
+        }
+        bytes
+    }
+
+    /// Everything but the key-check value or the header MAC
+    fn unauthenticated_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+
+        match &self.keys {
+            HeaderKeys::Derived { kdf, keyfile, .. } => {
+                if self.version >= FLAGS_VERSION {
+                    bytes.push(if *keyfile { FLAG_KEYFILE } else { 0 });
+                }
+                write_kdf(kdf, &mut bytes);
+                bytes.extend_from_slice(&self.nonce);
+            }
+            HeaderKeys::Slots { slots, .. } => {
+                if self.version >= CIPHER_VERSION {
+                    bytes.push(self.cipher.id());
+                }
+                bytes.extend_from_slice(&self.nonce);
+                if let Some(body_len) = self.body_len {
+                    bytes.extend_from_slice(&body_len.to_le_bytes());
+                }
+                bytes.push(slots.len() as u8);
+                for slot in slots {
+                    slot.write(&mut bytes);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Parse the header at the beginning of `data`.
+    ///
+    /// Returns `None` when `data` does not start with the magic number, which
+    /// means the file has been written in the legacy headerless format.
+    /// Otherwise returns the header and its length in bytes.
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, UnlockError> {
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+
+        let mut reader = Reader::new(&data[MAGIC.len()..]);
+
+        let version = reader.u8()?;
+        if version == 0 {
+            return Err(UnlockError::BadHeader(format!("version {version}")));
+        }
+        if version > VERSION {
+            return Err(UnlockError::UnsupportedVersion(version));
+        }
+
+        let header = if version >= SLOTS_VERSION {
+            Self::parse_slots(version, &mut reader)?
+        } else {
+            Self::parse_derived(version, &mut reader)?
+        };
+        Ok(Some((header, MAGIC.len() + reader.position())))
+    }
+
+    fn parse_slots(version: u8, reader: &mut Reader) -> Result<Self, UnlockError> {
+        let cipher = if version >= CIPHER_VERSION {
+            Cipher::from_id(reader.u8()?)?
+        } else {
+            Cipher::ChaCha20Poly1305
+        };
+        let nonce = reader.take(cipher.nonce_len())?.to_vec();
+        let body_len = if version >= BODY_LEN_VERSION {
+            Some(reader.u64()?)
+        } else {
+            None
+        };
+
+        let count = reader.u8()? as usize;
+        if count > MAX_SLOTS {
+            return Err(UnlockError::BadHeader(format!("{count} key slots")));
+        }
+        let slots = (0..count)
+            .map(|_| KeySlot::read(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut mac = [0u8; HEADER_MAC_LEN];
+        mac.copy_from_slice(reader.take(HEADER_MAC_LEN)?);
+
+        let decoy_area = if version >= DECOY_VERSION {
+            Some(reader.take(DECOY_AREA_LEN)?.to_vec())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version,
+            cipher,
+            nonce,
+            body_len,
+            keys: HeaderKeys::Slots { slots, mac },
+            decoy_area,
+        })
+    }
+
+    fn parse_derived(version: u8, reader: &mut Reader) -> Result<Self, UnlockError> {
+        let flags = if version >= FLAGS_VERSION {
+            reader.u8()?
+        } else {
+            0
+        };
+        if flags & !FLAG_KEYFILE != 0 {
+            return Err(UnlockError::BadHeader(format!(
+                "unknown flags {flags:#04x}"
+            )));
+        }
+
+        let kdf = read_kdf(reader)?;
+        let nonce = reader.take(NONCE_LEN)?.to_vec();
+
+        let key_check = if version >= KEY_CHECK_VERSION {
+            let mut key_check = [0u8; KEY_CHECK_LEN];
+            key_check.copy_from_slice(reader.take(KEY_CHECK_LEN)?);
+            Some(key_check)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version,
+            cipher: Cipher::ChaCha20Poly1305,
+            nonce,
+            body_len: None,
+            keys: HeaderKeys::Derived {
+                kdf,
+                keyfile: flags & FLAG_KEYFILE != 0,
+                key_check,
+            },
+            decoy_area: None,
+        })
+    }
+}
+
+/// Minimal cursor over the header bytes
+pub(super) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    pub(super) fn take(&mut self, len: usize) -> Result<&'a [u8], UnlockError> {
+        let end = self.position + len;
+        if end > self.data.len() {
+            return Err(UnlockError::Truncated);
+        }
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8, UnlockError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32, UnlockError> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, UnlockError> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::slot::KeySlot;
+
+    /// A header with one slot derived with `kdf`, parsed back
+    fn parse_with(kdf: KdfParams) -> Result<KdfParams, UnlockError> {
+        let slot = KeySlot::passphrase("master", &[1u8; 32], &[2u8; 32], &kdf, false).unwrap();
+        let bytes = VaultHeader::new(vec![slot], &[1u8; 32], 16).to_bytes();
+        let (header, _) = VaultHeader::parse(&bytes)?.unwrap();
+        let HeaderKeys::Slots { slots, .. } = header.keys else {
+            unreachable!("written with slots");
+        };
+        Ok(slots[0].kdf().unwrap().clone())
+    }
+
+    #[test]
+    fn should_reject_out_of_range_kdf_params() {
+        let (memory, time, lanes) = (MAX_ARGON2_MEMORY, MAX_ARGON2_TIME, MAX_ARGON2_PARALLELISM);
+        for kdf in [
+            KdfParams::pbkdf2(MAX_PBKDF2_ITERATIONS),
+            KdfParams::argon2id(memory, time, lanes),
+        ] {
+            assert_eq!(parse_with(kdf.clone()).unwrap(), kdf);
+        }
+
+        for kdf in [
+            KdfParams::pbkdf2(MAX_PBKDF2_ITERATIONS + 1),
+            KdfParams::argon2id(memory + 1, time, lanes),
+            KdfParams::argon2id(memory, time + 1, lanes),
+            KdfParams::argon2id(memory, time, lanes + 1),
+            KdfParams::argon2id(u32::MAX, u32::MAX, u32::MAX),
+        ] {
+            assert!(
+                matches!(parse_with(kdf.clone()), Err(UnlockError::BadHeader(_))),
+                "{kdf:?}"
+            );
+        }
+    }
+}
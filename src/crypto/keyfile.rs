@@ -0,0 +1,74 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::anyhow;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use crate::storage::write_synced;
+
+/// Size of the keyfiles created by [`create_keyfile`]
+pub const KEYFILE_LEN: usize = 64;
+
+/// A file required along with the master key to unlock a vault.
+///
+/// Only the digest of its content is kept, and it is wiped from memory when
+/// dropped.
+pub struct Keyfile {
+    digest: Zeroizing<[u8; 32]>,
+}
+
+impl Keyfile {
+    /// Read a keyfile, any non-empty file can be used as one
+    /// ```
+    /// use rust_password_manager::crypto::keyfile::{create_keyfile, Keyfile};
+    ///
+    /// let path = std::env::temp_dir().join(format!("rpm-keyfile-{}", std::process::id()));
+    /// create_keyfile(&path).unwrap();
+    /// assert!(create_keyfile(&path).is_err(), "an existing keyfile is never replaced");
+    ///
+    /// assert!(Keyfile::read(&path).is_ok());
+    /// std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = Zeroizing::new(fs::read(path)?);
+        if content.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the keyfile is empty",
+            ));
+        }
+
+        let mut digest = Zeroizing::new([0u8; 32]);
+        digest.copy_from_slice(&Sha256::digest(&*content));
+        Ok(Self { digest })
+    }
+
+    pub(crate) fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+}
+
+impl Debug for Keyfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Keyfile(***)")
+    }
+}
+
+/// Write a new random keyfile at `path`, only readable by its owner
+pub fn create_keyfile(path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(anyhow!("{} already exists", path.display()));
+    }
+
+    let mut content = Zeroizing::new([0u8; KEYFILE_LEN]);
+    OsRng.fill_bytes(&mut *content);
+    write_synced(path, &*content)?;
+
+    Ok(())
+}
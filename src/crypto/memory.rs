@@ -0,0 +1,80 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+/// Only the first failure is logged, the next ones fail for the same reason
+static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Keep the pages holding `secret` in RAM, so it is never written to the
+/// swap.
+///
+/// The pages may hold other allocations, so they are never unlocked one by
+/// one, only all at once by [`unlock_all`] when the vault is locked. A
+/// failure, usually a memlock limit too low, is logged the first time only.
+/// ```
+/// use rust_password_manager::crypto::memory::{lock, unlock_all};
+///
+/// let secret = vec![42u8; 100];
+/// lock(&secret);
+/// unlock_all();
+/// ```
+pub fn lock(secret: &[u8]) {
+    if secret.is_empty() {
+        return;
+    }
+    if let Err(err) = mlock(secret) {
+        if !LOCK_FAILED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "⚠️ Cannot lock the secrets in memory, they may be swapped to disk: {err}. \
+                 Raise the memlock limit, see `ulimit -l`"
+            );
+        }
+    }
+}
+
+/// Let the pages locked by [`lock`] be swapped again, once the secrets they
+/// held have been wiped
+pub fn unlock_all() {
+    #[cfg(unix)]
+    unsafe {
+        libc::munlockall();
+    }
+}
+
+/// Keep the process out of core dumps. It also prevents other processes of
+/// the same user from attaching to it or reading its memory.
+///
+/// Only supported on Linux, a no-op elsewhere.
+pub fn disable_core_dumps() -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let res = unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mlock(secret: &[u8]) -> io::Result<()> {
+    let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => return Err(io::Error::last_os_error()),
+    };
+    // Not every system rounds the address down to its page by itself
+    let start = secret.as_ptr() as usize & !(page_size - 1);
+    let len = secret.as_ptr() as usize + secret.len() - start;
+
+    let res = unsafe { libc::mlock(start as *const libc::c_void, len) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn mlock(_secret: &[u8]) -> io::Result<()> {
+    Ok(())
+}
@@ -1 +1,12 @@
+pub mod decoy;
+pub mod error;
+pub mod header;
+pub mod keyfile;
+pub mod memory;
+pub mod recipient;
+pub mod recovery_key;
+pub mod secret;
+pub mod shamir;
+pub mod slot;
+pub mod stream;
 pub mod utils;
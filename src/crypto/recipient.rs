@@ -0,0 +1,180 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bech32::{FromBase32, ToBase32, Variant};
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::storage::write_synced;
+
+/// Prefix of the public keys, the same as age
+const RECIPIENT_HRP: &str = "age";
+/// Prefix of the secret keys, written in upper case like age
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+/// The public key of a teammate, the data key of a vault can be wrapped to
+/// it.
+///
+/// Written like an age recipient, `age1…`, so an existing age identity can be
+/// used.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(PublicKey::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub(crate) fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let encoded = bech32::encode(
+            RECIPIENT_HRP,
+            self.0.as_bytes().to_base32(),
+            Variant::Bech32,
+        )
+        .map_err(|_| std::fmt::Error)?;
+        write!(f, "{encoded}")
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Recipient({self})")
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    /// ```
+    /// use rust_password_manager::crypto::recipient::Recipient;
+    ///
+    /// let recipient: Recipient = "age1zvkyg2lqzraa2lnjvqej32nkuu0ues2s82hzrye869xeexvn73equnujwj"
+    ///     .parse()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     recipient.to_string(),
+    ///     "age1zvkyg2lqzraa2lnjvqej32nkuu0ues2s82hzrye869xeexvn73equnujwj"
+    /// );
+    /// assert!("age1notakey".parse::<Recipient>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = decode(s, RECIPIENT_HRP).ok_or(anyhow!("{s} is not an X25519 public key"))?;
+        Ok(Self::from_bytes(*bytes))
+    }
+}
+
+/// The secret key of a recipient, opening the vaults wrapped to it.
+///
+/// It is wiped from memory when dropped.
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub(crate) fn from_bytes(secret: [u8; 32]) -> Self {
+        Self(StaticSecret::from(secret))
+    }
+
+    /// Read an identity file, either written by [`create_identity`] or by
+    /// `age-keygen`: the first `AGE-SECRET-KEY-1…` line is used, comments are
+    /// skipped
+    /// ```
+    /// use rust_password_manager::crypto::recipient::{create_identity, Identity};
+    ///
+    /// let path = std::env::temp_dir().join(format!("rpm-identity-{}", std::process::id()));
+    /// let recipient = create_identity(&path).unwrap();
+    /// assert!(create_identity(&path).is_err(), "an existing identity is never replaced");
+    ///
+    /// assert_eq!(Identity::read(&path).unwrap().recipient(), recipient);
+    /// std::fs::remove_file(path).unwrap();
+    /// ```
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = Zeroizing::new(fs::read_to_string(path)?);
+        let secret = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|line| decode(line, IDENTITY_HRP))
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no X25519 secret key in the identity file",
+            ))?;
+        Ok(Self(StaticSecret::from(*secret)))
+    }
+
+    /// The public key to give to the owner of a vault
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    pub(crate) fn secret(&self) -> &StaticSecret {
+        &self.0
+    }
+
+    /// The secret key as written in identity files
+    fn encode(&self) -> Zeroizing<String> {
+        let bytes = Zeroizing::new(self.0.to_bytes());
+        let encoded = bech32::encode(IDENTITY_HRP, bytes.to_base32(), Variant::Bech32)
+            .expect("the prefix is valid");
+        Zeroizing::new(encoded.to_uppercase())
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+/// Write a new identity at `path`, only readable by its owner, and return its
+/// public key
+pub fn create_identity(path: impl AsRef<Path>) -> Result<Recipient, anyhow::Error> {
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(anyhow!("{} already exists", path.display()));
+    }
+
+    let identity = Identity::generate();
+    let recipient = identity.recipient();
+    let content = Zeroizing::new(format!(
+        "# created: {}\n# public key: {recipient}\n{}\n",
+        chrono::Local::now().to_rfc3339(),
+        *identity.encode()
+    ));
+    write_synced(path, content.as_bytes())?;
+
+    Ok(recipient)
+}
+
+/// The 32 bytes of a bech32 string with the prefix `hrp`, in any case
+fn decode(s: &str, hrp: &str) -> Option<Zeroizing<[u8; 32]>> {
+    let (decoded_hrp, data, variant) = bech32::decode(s).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+
+    let bytes = Zeroizing::new(Vec::<u8>::from_base32(&data).ok()?);
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&bytes);
+    Some(key)
+}
@@ -0,0 +1,192 @@
+use std::fmt::{Debug, Formatter};
+
+use anyhow::anyhow;
+use bech32::{FromBase32, ToBase32, Variant};
+use zeroize::Zeroizing;
+
+use super::recipient::{Identity, Recipient};
+use super::shamir;
+
+/// Prefix of a printed recovery key
+const KEY_HRP: &str = "rpm-recovery-";
+/// Prefix of a printed share of a recovery key
+const SHARE_HRP: &str = "rpm-share-";
+/// Characters between two spaces when a key or a share is printed
+const GROUP_LEN: usize = 6;
+
+/// A secret opening the vault when its master key is forgotten, meant to be
+/// printed and kept offline.
+///
+/// It is the secret key of a recipient whose slot is labeled
+/// [`RECOVERY_SLOT_LABEL`](super::slot::RECOVERY_SLOT_LABEL), so it keeps
+/// opening the vault when its data key changes.
+pub struct RecoveryKey(Identity);
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        Self(Identity::generate())
+    }
+
+    /// Read a recovery key, or enough of its shares, as typed by the user:
+    /// case and spaces do not matter, and the shares follow each other
+    /// ```
+    /// use rust_password_manager::crypto::recovery_key::RecoveryKey;
+    ///
+    /// let key = RecoveryKey::generate();
+    /// let printed = key.to_printable();
+    /// assert!(printed.starts_with("RPM-RECOVERY-1"));
+    /// let typed = RecoveryKey::parse(&printed.to_lowercase()).unwrap();
+    /// assert_eq!(typed.recipient(), key.recipient());
+    ///
+    /// // Any 2 shares out of 3
+    /// let shares = key.split(2, 3).unwrap();
+    /// let typed = RecoveryKey::parse(&format!("{} {}", *shares[2], *shares[0])).unwrap();
+    /// assert_eq!(typed.recipient(), key.recipient());
+    /// assert!(RecoveryKey::parse(&shares[1]).is_err());
+    /// // The same share twice is still one share
+    /// assert!(RecoveryKey::parse(&format!("{} {}", *shares[1], *shares[1])).is_err());
+    ///
+    /// let mut typo = printed.to_string();
+    /// let last = if typo.ends_with('Q') { "P" } else { "Q" };
+    /// typo.replace_range(typo.len() - 1.., last);
+    /// assert!(RecoveryKey::parse(&typo).is_err());
+    /// ```
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let input: Zeroizing<String> = Zeroizing::new(
+            input
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>()
+                .to_lowercase(),
+        );
+
+        if input.starts_with(KEY_HRP) {
+            let bytes = decode(&input, KEY_HRP)
+                .filter(|bytes| bytes.len() == 32)
+                .ok_or(anyhow!("This is not a valid recovery key, check for typos"))?;
+            return Ok(Self::from_secret(&bytes));
+        }
+        if !input.starts_with(SHARE_HRP) {
+            return Err(anyhow!("Type the recovery key, or its shares"));
+        }
+
+        // Each share starts with its threshold
+        let mut threshold = None;
+        let mut shares = Vec::new();
+        for (index, part) in input.split(SHARE_HRP).skip(1).enumerate() {
+            let share = decode(&format!("{SHARE_HRP}{part}"), SHARE_HRP)
+                .filter(|bytes| bytes.len() == 2 + 32)
+                .ok_or(anyhow!(
+                    "The share number {} is not valid, check for typos",
+                    index + 1
+                ))?;
+            if share[0] < 2 || share[1] == 0 {
+                return Err(anyhow!(
+                    "The share number {} is not valid, check for typos",
+                    index + 1
+                ));
+            }
+            if *threshold.get_or_insert(share[0]) != share[0] {
+                return Err(anyhow!("These shares come from different recovery keys"));
+            }
+            // The same share typed twice only counts once
+            if !shares
+                .iter()
+                .any(|other: &Zeroizing<Vec<u8>>| other[0] == share[1])
+            {
+                shares.push(Zeroizing::new(share[1..].to_vec()));
+            }
+        }
+
+        let threshold = threshold.unwrap_or_default();
+        if shares.len() < threshold as usize {
+            return Err(anyhow!(
+                "Only {} of the {threshold} shares needed to rebuild the recovery key",
+                shares.len()
+            ));
+        }
+        let secret = shamir::combine(&shares)
+            .ok_or(anyhow!("These shares come from different recovery keys"))?;
+        Ok(Self::from_secret(&secret))
+    }
+
+    /// The public key the recovery slot is wrapped to
+    pub fn recipient(&self) -> Recipient {
+        self.0.recipient()
+    }
+
+    pub(crate) fn identity(&self) -> &Identity {
+        &self.0
+    }
+
+    /// The recovery key as it is printed, in groups easy to copy by hand
+    pub fn to_printable(&self) -> Zeroizing<String> {
+        let secret = Zeroizing::new(self.0.secret().to_bytes());
+        encode(KEY_HRP, &*secret)
+    }
+
+    /// Split the recovery key in `shares` parts, any `threshold` of them
+    /// rebuild it and fewer reveal nothing about it
+    pub fn split(
+        &self,
+        threshold: u8,
+        shares: u8,
+    ) -> Result<Vec<Zeroizing<String>>, anyhow::Error> {
+        if threshold < 2 || threshold > shares {
+            return Err(anyhow!(
+                "The threshold must be between 2 and the number of shares, {shares}"
+            ));
+        }
+
+        let secret = Zeroizing::new(self.0.secret().to_bytes());
+        let printed = shamir::split(&*secret, threshold, shares)
+            .iter()
+            .map(|share| {
+                let mut bytes = Zeroizing::new(vec![threshold]);
+                bytes.extend_from_slice(share);
+                encode(SHARE_HRP, &bytes)
+            })
+            .collect();
+        Ok(printed)
+    }
+
+    fn from_secret(secret: &[u8]) -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        bytes.copy_from_slice(secret);
+        Self(Identity::from_bytes(*bytes))
+    }
+}
+
+impl Debug for RecoveryKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RecoveryKey(***)")
+    }
+}
+
+/// Bech32 in upper case, so a typo is caught by its checksum, split in
+/// groups after the prefix
+fn encode(hrp: &str, bytes: &[u8]) -> Zeroizing<String> {
+    let encoded = Zeroizing::new(
+        bech32::encode(hrp, bytes.to_base32(), Variant::Bech32)
+            .expect("the prefix is valid")
+            .to_uppercase(),
+    );
+    let (prefix, data) = encoded.split_at(hrp.len() + 1);
+
+    let mut printed = Zeroizing::new(String::from(prefix));
+    for (index, c) in data.chars().enumerate() {
+        if index > 0 && index % GROUP_LEN == 0 {
+            printed.push(' ');
+        }
+        printed.push(c);
+    }
+    printed
+}
+
+fn decode(s: &str, hrp: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let (decoded_hrp, data, variant) = bech32::decode(s).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+    Vec::<u8>::from_base32(&data).ok().map(Zeroizing::new)
+}
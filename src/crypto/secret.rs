@@ -0,0 +1,150 @@
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::memory;
+
+/// A string wiped from memory when dropped, and never shown by `Debug`.
+///
+/// It never changes once created, so its buffer is kept out of the swap.
+/// ```
+/// use rust_password_manager::crypto::secret::SecretString;
+///
+/// let secret = SecretString::from("hunter2");
+/// assert_eq!(secret.expose(), "hunter2");
+/// assert!(!format!("{secret:?}").contains("hunter2"));
+/// ```
+#[derive(Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        memory::lock(secret.as_bytes());
+        Self(secret)
+    }
+
+    /// The secret itself, to be used right away and never stored elsewhere
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self::new(secret.to_string())
+    }
+}
+
+impl Zeroize for SecretString {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretString {}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+impl ToSql for SecretString {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Text(self.0.as_bytes())))
+    }
+}
+
+impl FromSql for SecretString {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        String::column_result(value).map(Self::new)
+    }
+}
+
+/// The vault key derived from the master password, wiped from memory when
+/// dropped.
+///
+/// It lives on the heap, so it never moves and its page is kept out of the
+/// swap.
+pub struct MasterKey(Box<[u8; 32]>);
+
+impl Default for MasterKey {
+    fn default() -> Self {
+        let key = Box::new([0u8; 32]);
+        memory::lock(&*key);
+        Self(key)
+    }
+}
+
+impl Clone for MasterKey {
+    fn clone(&self) -> Self {
+        let mut key = Self::default();
+        key.0.copy_from_slice(&*self.0);
+        key
+    }
+}
+
+impl MasterKey {
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8; 32] {
+        &mut self.0
+    }
+}
+
+impl Deref for MasterKey {
+    type Target = [u8; 32];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Constant-time comparison, so checking a key leaks nothing about it
+impl PartialEq for MasterKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+impl Eq for MasterKey {}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.as_mut().zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for MasterKey {}
+
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MasterKey(***)")
+    }
+}
@@ -0,0 +1,151 @@
+//! Shamir's secret sharing, byte by byte over GF(256).
+//!
+//! A share is its `x` coordinate followed by one `y` per byte of the
+//! secret, the layout the first recovery key shares were printed with.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+/// x^8 + x^4 + x^3 + x^2 + 1, the field the first shares were printed in
+const POLYNOMIAL: u16 = 0x11d;
+
+/// Split `secret` in `count` shares, any `threshold` of them rebuild it.
+///
+/// Every coefficient but the secret is drawn over the whole field, zero
+/// included, so fewer than `threshold` shares leave every secret as likely.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Vec<Zeroizing<Vec<u8>>> {
+    assert!(threshold >= 1 && threshold <= count, "invalid threshold");
+
+    // One polynomial per byte, the secret being its constant term
+    let mut coefficients = Zeroizing::new(vec![0u8; secret.len() * threshold as usize]);
+    OsRng.fill_bytes(&mut coefficients);
+    for (polynomial, byte) in coefficients
+        .chunks_mut(threshold as usize)
+        .zip(secret.iter())
+    {
+        polynomial[0] = *byte;
+    }
+
+    (1..=count)
+        .map(|x| {
+            let mut share = Zeroizing::new(Vec::with_capacity(1 + secret.len()));
+            share.push(x);
+            for polynomial in coefficients.chunks(threshold as usize) {
+                let y = polynomial
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, coefficient| mul(acc, x) ^ coefficient);
+                share.push(y);
+            }
+            share
+        })
+        .collect()
+}
+
+/// Rebuild the secret of `shares`, `None` when they do not fit together.
+///
+/// Nothing tells apart the secret of too few shares from a random one:
+/// checking there are enough of them is up to the caller.
+pub fn combine(shares: &[Zeroizing<Vec<u8>>]) -> Option<Zeroizing<Vec<u8>>> {
+    let len = shares.first()?.len();
+    if len < 2
+        || shares
+            .iter()
+            .any(|share| share.len() != len || share[0] == 0)
+    {
+        return None;
+    }
+    for (index, share) in shares.iter().enumerate() {
+        if shares[..index].iter().any(|other| other[0] == share[0]) {
+            return None;
+        }
+    }
+
+    // Lagrange interpolation at x = 0, where subtracting is adding
+    let mut secret = Zeroizing::new(vec![0u8; len - 1]);
+    for share in shares {
+        let basis = shares
+            .iter()
+            .filter(|other| other[0] != share[0])
+            .fold(1, |acc, other| {
+                mul(acc, mul(other[0], inverse(other[0] ^ share[0])))
+            });
+        for (byte, y) in secret.iter_mut().zip(&share[1..]) {
+            *byte ^= mul(basis, *y);
+        }
+    }
+    Some(secret)
+}
+
+/// Product in GF(256), without any table lookup nor branch depending on
+/// the secret
+fn mul(a: u8, b: u8) -> u8 {
+    let mut a = a as u16;
+    let mut b = b;
+    let mut product = 0u16;
+    for _ in 0..8 {
+        product ^= a & 0u16.wrapping_sub((b & 1) as u16);
+        b >>= 1;
+        a <<= 1;
+        a ^= POLYNOMIAL & 0u16.wrapping_sub(a >> 8);
+    }
+    product as u8
+}
+
+/// Inverse in GF(256), as a^254
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+    for bit in 0..8 {
+        if 254 & (1 << bit) != 0 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"a recovery key of thirty-two b..";
+
+    #[test]
+    fn should_rebuild_from_any_threshold_shares_only() {
+        let shares = split(SECRET, 3, 5);
+        assert_eq!(shares.len(), 5);
+
+        for a in 0..5 {
+            for b in a + 1..5 {
+                let pair = [shares[a].clone(), shares[b].clone()];
+                assert_ne!(combine(&pair).unwrap().as_slice(), SECRET);
+
+                for c in b + 1..5 {
+                    let three = [shares[c].clone(), shares[a].clone(), shares[b].clone()];
+                    assert_eq!(combine(&three).unwrap().as_slice(), SECRET);
+                }
+            }
+        }
+        assert_eq!(combine(&shares).unwrap().as_slice(), SECRET);
+
+        let twice = [shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(combine(&twice).is_none());
+    }
+
+    #[test]
+    fn should_draw_coefficients_over_the_whole_field() {
+        // With a threshold of 2, a share is the secret plus x times a
+        // random coefficient: it is the secret itself when that one is 0
+        let hits = (0..4096).filter(|_| split(&[42], 2, 2)[0][1] == 42).count();
+        assert!(hits > 0, "a coefficient is never 0");
+    }
+
+    #[test]
+    fn should_invert_every_element() {
+        for a in 1..=255 {
+            assert_eq!(mul(a, inverse(a)), 1, "{a}");
+        }
+    }
+}
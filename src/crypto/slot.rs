@@ -0,0 +1,843 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::anyhow;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::error::UnlockError;
+use super::header::{read_kdf, write_kdf, KdfParams, Reader, NONCE_LEN};
+use super::keyfile::Keyfile;
+use super::recipient::{Identity, Recipient};
+use super::recovery_key::RecoveryKey;
+use super::secret::MasterKey;
+use super::utils::{gen_key_from_password, gen_key_from_shared_secret};
+
+/// Most slots a vault can have
+pub const MAX_SLOTS: usize = 32;
+/// Longest label of a slot, in bytes
+pub const MAX_LABEL_LEN: usize = 64;
+/// Label of the slot created along with a vault
+pub const DEFAULT_SLOT_LABEL: &str = "master";
+/// Label of the slot opened by the recovery key
+pub const RECOVERY_SLOT_LABEL: &str = "recovery";
+
+const SLOT_PASSPHRASE: u8 = 1;
+const SLOT_RECIPIENT: u8 = 2;
+const SLOT_RECOVERY: u8 = 3;
+
+/// The data key encrypted and authenticated in a slot
+const WRAPPED_LEN: usize = 32 + 16;
+
+/// A nonce, and the data key wrapped with it
+type WrappedKey = ([u8; NONCE_LEN], [u8; WRAPPED_LEN]);
+
+/// One way to open a vault: a copy of its data key wrapped with a key
+/// derived from a passphrase, or with the X25519 key of a recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub label: String,
+    pub kind: SlotKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SlotKind {
+    Passphrase {
+        kdf: KdfParams,
+        /// A keyfile is needed along with the passphrase
+        keyfile: bool,
+        nonce: [u8; NONCE_LEN],
+        wrapped: [u8; WRAPPED_LEN],
+    },
+    Recipient {
+        recipient: Recipient,
+        /// Public half of the key generated for this slot only
+        ephemeral: [u8; 32],
+        nonce: [u8; NONCE_LEN],
+        wrapped: [u8; WRAPPED_LEN],
+    },
+    /// Wrapped like a recipient, to the public key of the recovery key
+    Recovery {
+        recipient: Recipient,
+        ephemeral: [u8; 32],
+        nonce: [u8; NONCE_LEN],
+        wrapped: [u8; WRAPPED_LEN],
+    },
+}
+
+impl KeySlot {
+    /// Wrap `data_key` with `kek`, the key derived from a passphrase with
+    /// `kdf`
+    pub fn passphrase(
+        label: &str,
+        data_key: &[u8; 32],
+        kek: &[u8; 32],
+        kdf: &KdfParams,
+        keyfile: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let (nonce, wrapped) = wrap(kek, label, data_key)?;
+        Ok(Self {
+            label: label.to_string(),
+            kind: SlotKind::Passphrase {
+                kdf: kdf.clone(),
+                keyfile,
+                nonce,
+                wrapped,
+            },
+        })
+    }
+
+    /// Wrap `data_key` to `recipient`, with a key agreed between a new
+    /// ephemeral key and the key of the recipient
+    pub fn recipient(
+        label: &str,
+        data_key: &[u8; 32],
+        recipient: &Recipient,
+    ) -> Result<Self, anyhow::Error> {
+        let (ephemeral, (nonce, wrapped)) = wrap_to(recipient, label, data_key)?;
+        Ok(Self {
+            label: label.to_string(),
+            kind: SlotKind::Recipient {
+                recipient: *recipient,
+                ephemeral,
+                nonce,
+                wrapped,
+            },
+        })
+    }
+
+    /// Wrap `data_key` to the public key of a recovery key
+    pub fn recovery(data_key: &[u8; 32], recipient: &Recipient) -> Result<Self, anyhow::Error> {
+        let (ephemeral, (nonce, wrapped)) = wrap_to(recipient, RECOVERY_SLOT_LABEL, data_key)?;
+        Ok(Self {
+            label: RECOVERY_SLOT_LABEL.to_string(),
+            kind: SlotKind::Recovery {
+                recipient: *recipient,
+                ephemeral,
+                nonce,
+                wrapped,
+            },
+        })
+    }
+
+    /// The data key, if `kek` opens this passphrase slot
+    pub fn unwrap(&self, kek: &[u8; 32]) -> Option<MasterKey> {
+        match &self.kind {
+            SlotKind::Passphrase { nonce, wrapped, .. } => unwrap(kek, &self.label, nonce, wrapped),
+            SlotKind::Recipient { .. } | SlotKind::Recovery { .. } => None,
+        }
+    }
+
+    /// The data key, if this slot is wrapped to `identity`
+    pub fn unwrap_with_identity(&self, identity: &Identity) -> Option<MasterKey> {
+        let (SlotKind::Recipient {
+            recipient,
+            ephemeral,
+            nonce,
+            wrapped,
+        }
+        | SlotKind::Recovery {
+            recipient,
+            ephemeral,
+            nonce,
+            wrapped,
+        }) = &self.kind
+        else {
+            return None;
+        };
+        if *recipient != identity.recipient() {
+            return None;
+        }
+
+        let ephemeral = PublicKey::from(*ephemeral);
+        let shared = identity.secret().diffie_hellman(&ephemeral);
+        let kek = gen_key_from_shared_secret(&shared, &ephemeral, recipient.public_key()).ok()?;
+        unwrap(&kek, &self.label, nonce, wrapped)
+    }
+
+    pub fn requires_keyfile(&self) -> bool {
+        match &self.kind {
+            SlotKind::Passphrase { keyfile, .. } => *keyfile,
+            SlotKind::Recipient { .. } | SlotKind::Recovery { .. } => false,
+        }
+    }
+
+    /// The key derivation parameters of a passphrase slot
+    pub fn kdf(&self) -> Option<&KdfParams> {
+        match &self.kind {
+            SlotKind::Passphrase { kdf, .. } => Some(kdf),
+            SlotKind::Recipient { .. } | SlotKind::Recovery { .. } => None,
+        }
+    }
+
+    /// The public key a recipient slot is wrapped to
+    pub fn recipient_key(&self) -> Option<&Recipient> {
+        match &self.kind {
+            SlotKind::Recipient { recipient, .. } => Some(recipient),
+            SlotKind::Passphrase { .. } | SlotKind::Recovery { .. } => None,
+        }
+    }
+
+    pub fn is_recovery(&self) -> bool {
+        matches!(self.kind, SlotKind::Recovery { .. })
+    }
+
+    /// Replace the wrapped data key with random bytes: nothing opens the
+    /// slot anymore, but it looks the same
+    pub(super) fn destroy(&mut self) {
+        let (SlotKind::Passphrase { nonce, wrapped, .. }
+        | SlotKind::Recipient { nonce, wrapped, .. }
+        | SlotKind::Recovery { nonce, wrapped, .. }) = &mut self.kind;
+        OsRng.fill_bytes(nonce);
+        OsRng.fill_bytes(wrapped);
+    }
+
+    pub(super) fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.push(match self.kind {
+            SlotKind::Passphrase { .. } => SLOT_PASSPHRASE,
+            SlotKind::Recipient { .. } => SLOT_RECIPIENT,
+            SlotKind::Recovery { .. } => SLOT_RECOVERY,
+        });
+        bytes.push(self.label.len() as u8);
+        bytes.extend_from_slice(self.label.as_bytes());
+
+        match &self.kind {
+            SlotKind::Passphrase {
+                kdf,
+                keyfile,
+                nonce,
+                wrapped,
+            } => {
+                bytes.push(*keyfile as u8);
+                write_kdf(kdf, bytes);
+                bytes.extend_from_slice(nonce);
+                bytes.extend_from_slice(wrapped);
+            }
+            SlotKind::Recipient {
+                recipient,
+                ephemeral,
+                nonce,
+                wrapped,
+            }
+            | SlotKind::Recovery {
+                recipient,
+                ephemeral,
+                nonce,
+                wrapped,
+            } => {
+                bytes.extend_from_slice(&recipient.to_bytes());
+                bytes.extend_from_slice(ephemeral);
+                bytes.extend_from_slice(nonce);
+                bytes.extend_from_slice(wrapped);
+            }
+        }
+    }
+
+    pub(super) fn read(reader: &mut Reader) -> Result<Self, UnlockError> {
+        let kind = reader.u8()?;
+        let label_len = reader.u8()? as usize;
+        let label = String::from_utf8(reader.take(label_len)?.to_vec())
+            .map_err(|_| UnlockError::BadHeader("slot label is not UTF-8".to_string()))?;
+
+        let kind = match kind {
+            SLOT_PASSPHRASE => {
+                let keyfile = match reader.u8()? {
+                    0 => false,
+                    1 => true,
+                    flag => {
+                        return Err(UnlockError::BadHeader(format!(
+                            "unknown keyfile flag {flag} in slot {label}"
+                        )))
+                    }
+                };
+                let kdf = read_kdf(reader)?;
+                let mut nonce = [0u8; NONCE_LEN];
+                nonce.copy_from_slice(reader.take(NONCE_LEN)?);
+                let mut wrapped = [0u8; WRAPPED_LEN];
+                wrapped.copy_from_slice(reader.take(WRAPPED_LEN)?);
+                SlotKind::Passphrase {
+                    kdf,
+                    keyfile,
+                    nonce,
+                    wrapped,
+                }
+            }
+            SLOT_RECIPIENT | SLOT_RECOVERY => {
+                let mut recipient = [0u8; 32];
+                recipient.copy_from_slice(reader.take(32)?);
+                let recipient = Recipient::from_bytes(recipient);
+                let mut ephemeral = [0u8; 32];
+                ephemeral.copy_from_slice(reader.take(32)?);
+                let mut nonce = [0u8; NONCE_LEN];
+                nonce.copy_from_slice(reader.take(NONCE_LEN)?);
+                let mut wrapped = [0u8; WRAPPED_LEN];
+                wrapped.copy_from_slice(reader.take(WRAPPED_LEN)?);
+                match kind {
+                    SLOT_RECIPIENT => SlotKind::Recipient {
+                        recipient,
+                        ephemeral,
+                        nonce,
+                        wrapped,
+                    },
+                    _ => SlotKind::Recovery {
+                        recipient,
+                        ephemeral,
+                        nonce,
+                        wrapped,
+                    },
+                }
+            }
+            kind => {
+                return Err(UnlockError::BadHeader(format!(
+                    "unknown kind {kind} of slot {label}"
+                )))
+            }
+        };
+
+        Ok(Self { label, kind })
+    }
+}
+
+impl Display for KeySlot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SlotKind::Passphrase { kdf, keyfile, .. } => {
+                write!(f, "{}: passphrase, {kdf}", self.label)?;
+                if *keyfile {
+                    write!(f, ", with a keyfile")?;
+                }
+                Ok(())
+            }
+            SlotKind::Recipient { recipient, .. } => {
+                write!(f, "{}: recipient {recipient}", self.label)
+            }
+            SlotKind::Recovery { .. } => write!(f, "{}: recovery key", self.label),
+        }
+    }
+}
+
+/// The label is authenticated along with the data key, so it cannot be moved
+/// to another slot
+fn wrap(kek: &[u8; 32], label: &str, data_key: &[u8; 32]) -> Result<WrappedKey, anyhow::Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let payload = Payload {
+        msg: data_key,
+        aad: label.as_bytes(),
+    };
+    let ciphertext = ChaCha20Poly1305::new(kek.into())
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|err| anyhow!("Wrapping the data key: {err}"))?;
+
+    let mut wrapped = [0u8; WRAPPED_LEN];
+    wrapped.copy_from_slice(&ciphertext);
+    Ok((nonce, wrapped))
+}
+
+/// Wrap `data_key` with a key agreed between a new ephemeral key and the key
+/// of `recipient`, returning the public half of the ephemeral key
+fn wrap_to(
+    recipient: &Recipient,
+    label: &str,
+    data_key: &[u8; 32],
+) -> Result<([u8; 32], WrappedKey), anyhow::Error> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(recipient.public_key());
+    let kek = gen_key_from_shared_secret(&shared, &ephemeral, recipient.public_key())?;
+
+    Ok((ephemeral.to_bytes(), wrap(&kek, label, data_key)?))
+}
+
+fn unwrap(
+    kek: &[u8; 32],
+    label: &str,
+    nonce: &[u8; NONCE_LEN],
+    wrapped: &[u8; WRAPPED_LEN],
+) -> Option<MasterKey> {
+    let payload = Payload {
+        msg: wrapped,
+        aad: label.as_bytes(),
+    };
+    let plaintext = ChaCha20Poly1305::new(kek.into())
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .ok()
+        .map(zeroize::Zeroizing::new)?;
+
+    let mut data_key = MasterKey::default();
+    data_key.as_mut_bytes().copy_from_slice(&plaintext);
+    Some(data_key)
+}
+
+/// The data key of an opened vault, and its slots
+pub struct VaultKeys {
+    data_key: MasterKey,
+    slots: Vec<KeySlot>,
+    /// The slot the vault has been opened with
+    opened: usize,
+}
+
+impl VaultKeys {
+    /// Keys of a new vault: a random data key, and a first slot opened by
+    /// `password`
+    /// ```
+    /// use rust_password_manager::crypto::header::KdfParams;
+    /// use rust_password_manager::crypto::slot::VaultKeys;
+    ///
+    /// let kdf = KdfParams::pbkdf2(1000);
+    /// let mut keys = VaultKeys::create("alice", "first", None, &kdf).unwrap();
+    /// keys.add_passphrase("bob", "second", None, &kdf).unwrap();
+    /// assert!(keys.add_passphrase("bob", "third", None, &kdf).is_err());
+    ///
+    /// let opened = VaultKeys::unlock(keys.slots(), "second", None).unwrap();
+    /// assert!(opened.data_key() == keys.data_key());
+    /// assert_eq!(opened.opened().label, "bob");
+    ///
+    /// // The slot in use cannot be revoked, the others can
+    /// assert!(keys.revoke("alice").is_err());
+    /// keys.revoke("bob").unwrap();
+    /// assert!(VaultKeys::unlock(keys.slots(), "second", None).is_err());
+    /// ```
+    pub fn create(
+        label: &str,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+        kdf: &KdfParams,
+    ) -> Result<Self, anyhow::Error> {
+        let mut data_key = MasterKey::default();
+        OsRng.fill_bytes(data_key.as_mut_bytes());
+
+        let kek = gen_key_from_password(password, keyfile, kdf)?;
+        Self::from_derived(data_key, label, &kek, kdf, keyfile.is_some())
+    }
+
+    /// Keys of a vault written before the slots existed: the key derived from
+    /// its master key now wraps a new random data key
+    pub fn from_derived_key(
+        kek: &[u8; 32],
+        kdf: &KdfParams,
+        keyfile: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let mut data_key = MasterKey::default();
+        OsRng.fill_bytes(data_key.as_mut_bytes());
+        Self::from_derived(data_key, DEFAULT_SLOT_LABEL, kek, kdf, keyfile)
+    }
+
+    fn from_derived(
+        data_key: MasterKey,
+        label: &str,
+        kek: &[u8; 32],
+        kdf: &KdfParams,
+        keyfile: bool,
+    ) -> Result<Self, anyhow::Error> {
+        check_label(label)?;
+        let slot = KeySlot::passphrase(label, &data_key, kek, kdf, keyfile)?;
+        Ok(Self {
+            data_key,
+            slots: vec![slot],
+            opened: 0,
+        })
+    }
+
+    /// The slots of a vault around a random data key, as if opened with the
+    /// slot named `opened`, `None` without any slot.
+    ///
+    /// They open nothing, but change like the slots of an opened vault: they
+    /// stand for the slots of a vault shown without its data key.
+    pub fn unopened(slots: Vec<KeySlot>, opened: &str) -> Option<Self> {
+        if slots.is_empty() {
+            return None;
+        }
+        let mut data_key = MasterKey::default();
+        OsRng.fill_bytes(data_key.as_mut_bytes());
+        let opened = slots
+            .iter()
+            .position(|slot| slot.label == opened)
+            .unwrap_or_default();
+        Some(Self {
+            data_key,
+            slots,
+            opened,
+        })
+    }
+
+    /// Try every slot with `password`, and `keyfile` for the slots needing
+    /// one.
+    ///
+    /// Slots with the same key derivation parameters share one derivation.
+    pub fn unlock(
+        slots: &[KeySlot],
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<Self, UnlockError> {
+        let mut derived: Vec<(&KdfParams, bool, MasterKey)> = Vec::new();
+        let mut missing_keyfile = false;
+
+        for (index, slot) in slots.iter().enumerate() {
+            let Some(kdf) = slot.kdf() else {
+                continue;
+            };
+            let with_keyfile = slot.requires_keyfile();
+            if with_keyfile && keyfile.is_none() {
+                missing_keyfile = true;
+                continue;
+            }
+
+            let cached = derived
+                .iter()
+                .position(|(params, bound, _)| *params == kdf && *bound == with_keyfile);
+            let kek = match cached {
+                Some(position) => &derived[position].2,
+                None => {
+                    let kek =
+                        gen_key_from_password(password, keyfile.filter(|_| with_keyfile), kdf)
+                            .map_err(|err| UnlockError::BadHeader(err.to_string()))?;
+                    derived.push((kdf, with_keyfile, kek));
+                    &derived[derived.len() - 1].2
+                }
+            };
+
+            if let Some(data_key) = slot.unwrap(kek) {
+                return Ok(Self {
+                    data_key,
+                    slots: slots.to_vec(),
+                    opened: index,
+                });
+            }
+        }
+
+        if missing_keyfile {
+            Err(UnlockError::KeyfileRequired)
+        } else {
+            Err(UnlockError::WrongPassword)
+        }
+    }
+
+    /// Open the slot wrapped to `identity`
+    pub fn unlock_with_identity(
+        slots: &[KeySlot],
+        identity: &Identity,
+    ) -> Result<Self, UnlockError> {
+        let recipient = identity.recipient();
+        let index = slots
+            .iter()
+            .position(|slot| slot.recipient_key() == Some(&recipient))
+            .ok_or(UnlockError::NotRecipient)?;
+
+        // The slot is wrapped to this identity, it can only fail if modified
+        let data_key = slots[index]
+            .unwrap_with_identity(identity)
+            .ok_or(UnlockError::Corrupted)?;
+        Ok(Self {
+            data_key,
+            slots: slots.to_vec(),
+            opened: index,
+        })
+    }
+
+    /// Open the recovery slot with `recovery`
+    pub fn unlock_with_recovery_key(
+        slots: &[KeySlot],
+        recovery: &RecoveryKey,
+    ) -> Result<Self, UnlockError> {
+        let index = slots
+            .iter()
+            .position(KeySlot::is_recovery)
+            .ok_or(UnlockError::NoRecoveryKey)?;
+
+        let data_key = slots[index]
+            .unwrap_with_identity(recovery.identity())
+            .ok_or(UnlockError::WrongRecoveryKey)?;
+        Ok(Self {
+            data_key,
+            slots: slots.to_vec(),
+            opened: index,
+        })
+    }
+
+    pub fn data_key(&self) -> &MasterKey {
+        &self.data_key
+    }
+
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+
+    /// The slot the vault has been opened with
+    pub fn opened(&self) -> &KeySlot {
+        &self.slots[self.opened]
+    }
+
+    /// Check if `password`, and `keyfile` if needed, open the slot the vault
+    /// has been opened with
+    pub fn check_passphrase(&self, password: &str, keyfile: Option<&Keyfile>) -> bool {
+        let slot = self.opened();
+        let Some(kdf) = slot.kdf() else {
+            return false;
+        };
+        let keyfile = keyfile.filter(|_| slot.requires_keyfile());
+        match gen_key_from_password(password, keyfile, kdf) {
+            Ok(kek) => slot.unwrap(&kek).as_ref() == Some(&self.data_key),
+            Err(_) => false,
+        }
+    }
+
+    /// Add a slot opened by `password`, and `keyfile` if given
+    pub fn add_passphrase(
+        &mut self,
+        label: &str,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+        kdf: &KdfParams,
+    ) -> Result<(), anyhow::Error> {
+        self.check_new_slot(label)?;
+
+        let kek = gen_key_from_password(password, keyfile, kdf)?;
+        let slot = KeySlot::passphrase(label, &self.data_key, &kek, kdf, keyfile.is_some())?;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    /// Add a slot wrapped to `recipient`, opened by its identity
+    pub fn add_recipient(
+        &mut self,
+        label: &str,
+        recipient: &Recipient,
+    ) -> Result<(), anyhow::Error> {
+        self.check_new_slot(label)?;
+        if let Some(slot) = self
+            .slots
+            .iter()
+            .find(|slot| slot.recipient_key() == Some(recipient))
+        {
+            return Err(anyhow!(
+                "{recipient} is already the recipient of the slot {}",
+                slot.label
+            ));
+        }
+
+        let slot = KeySlot::recipient(label, &self.data_key, recipient)?;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    /// Remove the recipient of the slot named `label`, and wrap a new data
+    /// key to the others and to the recovery key: a removed recipient who
+    /// kept the data key cannot read what is encrypted with the new one.
+    ///
+    /// The passphrase slots cannot follow without their passphrase, so only
+    /// the one the vault has been opened with can be left, and `password`,
+    /// with `keyfile` if it needs one, has to open it. The whole vault has to
+    /// be encrypted again.
+    /// ```
+    /// use rust_password_manager::crypto::header::KdfParams;
+    /// use rust_password_manager::crypto::recipient::Identity;
+    /// use rust_password_manager::crypto::slot::VaultKeys;
+    ///
+    /// let mut keys = VaultKeys::create("owner", "first", None, &KdfParams::pbkdf2(1000)).unwrap();
+    /// let (alice, bob) = (Identity::generate(), Identity::generate());
+    /// keys.add_recipient("alice", &alice.recipient()).unwrap();
+    /// keys.add_recipient("bob", &bob.recipient()).unwrap();
+    ///
+    /// let previous = keys.data_key().clone();
+    /// keys.remove_recipient("bob", "first", None).unwrap();
+    /// assert!(*keys.data_key() != previous);
+    ///
+    /// let opened = VaultKeys::unlock_with_identity(keys.slots(), &alice).unwrap();
+    /// assert!(opened.data_key() == keys.data_key());
+    /// assert!(VaultKeys::unlock_with_identity(keys.slots(), &bob).is_err());
+    /// assert!(VaultKeys::unlock(keys.slots(), "first", None).is_ok());
+    /// ```
+    pub fn remove_recipient(
+        &mut self,
+        label: &str,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+    ) -> Result<KeySlot, anyhow::Error> {
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.label == label)
+            .ok_or(anyhow!("There is no slot named {label}"))?;
+        if self.slots[index].recipient_key().is_none() {
+            return Err(anyhow!("{label} is a passphrase slot, revoke it instead"));
+        }
+        if index == self.opened {
+            return Err(anyhow!(
+                "Cannot remove {label}: the vault has been opened with it"
+            ));
+        }
+
+        let stranded: Vec<&str> = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(other, slot)| *other != self.opened && slot.kdf().is_some())
+            .map(|(_, slot)| slot.label.as_str())
+            .collect();
+        if !stranded.is_empty() {
+            return Err(anyhow!(
+                "The data key cannot be wrapped again for the passphrase slots {} \
+                 without their passphrase: revoke them, or open the vault with them",
+                stranded.join(", ")
+            ));
+        }
+
+        let mut data_key = MasterKey::default();
+        OsRng.fill_bytes(data_key.as_mut_bytes());
+
+        let mut slots = Vec::with_capacity(self.slots.len() - 1);
+        for (other, slot) in self.slots.iter().enumerate() {
+            if other == index {
+                continue;
+            }
+            let rewrapped = match &slot.kind {
+                SlotKind::Recipient { recipient, .. } => {
+                    KeySlot::recipient(&slot.label, &data_key, recipient)?
+                }
+                SlotKind::Recovery { recipient, .. } => KeySlot::recovery(&data_key, recipient)?,
+                SlotKind::Passphrase { kdf, .. } => {
+                    let keyfile = keyfile.filter(|_| slot.requires_keyfile());
+                    let kek = gen_key_from_password(password, keyfile, kdf)?;
+                    if slot.unwrap(&kek).as_ref() != Some(&self.data_key) {
+                        return Err(anyhow!("Wrong master key or keyfile for {}", slot.label));
+                    }
+                    KeySlot::passphrase(&slot.label, &data_key, &kek, kdf, keyfile.is_some())?
+                }
+            };
+            slots.push(rewrapped);
+        }
+
+        if index < self.opened {
+            self.opened -= 1;
+        }
+        self.data_key = data_key;
+        let removed = std::mem::replace(&mut self.slots, slots).remove(index);
+        Ok(removed)
+    }
+
+    /// Replace the slot the vault has been opened with by one opened by
+    /// `password`, and `keyfile` if given, keeping its label
+    pub fn rewrap_opened(
+        &mut self,
+        password: &str,
+        keyfile: Option<&Keyfile>,
+        kdf: &KdfParams,
+    ) -> Result<(), anyhow::Error> {
+        let label = self.opened().label.clone();
+        let kek = gen_key_from_password(password, keyfile, kdf)?;
+        self.slots[self.opened] =
+            KeySlot::passphrase(&label, &self.data_key, &kek, kdf, keyfile.is_some())?;
+        Ok(())
+    }
+
+    /// Wrap the data key to a new recovery key, replacing the previous one
+    pub fn set_recovery_key(&mut self, recovery: &RecoveryKey) -> Result<(), anyhow::Error> {
+        let slot = KeySlot::recovery(&self.data_key, &recovery.recipient())?;
+        match self.slots.iter().position(KeySlot::is_recovery) {
+            Some(index) => self.slots[index] = slot,
+            None => {
+                self.check_new_slot(RECOVERY_SLOT_LABEL)?;
+                self.slots.push(slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the passphrase slot named `label`, or add it, with one opened
+    /// by `password` alone, and use it as the slot the vault is opened with.
+    ///
+    /// Used once the vault is opened with its recovery key: the keyfile the
+    /// slot needed may be lost too.
+    pub fn reset_passphrase(
+        &mut self,
+        label: &str,
+        password: &str,
+        kdf: &KdfParams,
+    ) -> Result<(), anyhow::Error> {
+        let index = self.slots.iter().position(|slot| slot.label == label);
+        if let Some(index) = index {
+            if self.slots[index].kdf().is_none() {
+                return Err(anyhow!("{label} is not a passphrase slot"));
+            }
+        } else {
+            self.check_new_slot(label)?;
+        }
+
+        let kek = gen_key_from_password(password, None, kdf)?;
+        let slot = KeySlot::passphrase(label, &self.data_key, &kek, kdf, false)?;
+        self.opened = match index {
+            Some(index) => {
+                self.slots[index] = slot;
+                index
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        };
+        Ok(())
+    }
+
+    /// Remove the slot named `label`, its passphrase does not open the vault
+    /// anymore
+    pub fn revoke(&mut self, label: &str) -> Result<KeySlot, anyhow::Error> {
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.label == label)
+            .ok_or(anyhow!("There is no slot named {label}"))?;
+        if index == self.opened {
+            return Err(anyhow!(
+                "Cannot revoke {label}: the vault has been opened with it"
+            ));
+        }
+
+        if index < self.opened {
+            self.opened -= 1;
+        }
+        Ok(self.slots.remove(index))
+    }
+
+    /// Put back a slot removed or replaced while its change could not be
+    /// written
+    pub fn restore(&mut self, slot: KeySlot) {
+        match self
+            .slots
+            .iter()
+            .position(|other| other.label == slot.label)
+        {
+            Some(index) => self.slots[index] = slot,
+            None => self.slots.push(slot),
+        }
+    }
+
+    /// Check a slot named `label` can be added
+    fn check_new_slot(&self, label: &str) -> Result<(), anyhow::Error> {
+        check_label(label)?;
+        if self.slots.iter().any(|slot| slot.label == label) {
+            return Err(anyhow!("There is already a slot named {label}"));
+        }
+        if self.slots.len() >= MAX_SLOTS {
+            return Err(anyhow!("A vault cannot have more than {MAX_SLOTS} slots"));
+        }
+        Ok(())
+    }
+}
+
+fn check_label(label: &str) -> Result<(), anyhow::Error> {
+    if label.is_empty() {
+        return Err(anyhow!("The label of a slot cannot be empty"));
+    }
+    if label.len() > MAX_LABEL_LEN {
+        return Err(anyhow!(
+            "The label of a slot cannot be longer than {MAX_LABEL_LEN} bytes"
+        ));
+    }
+    Ok(())
+}
@@ -1,79 +1,755 @@
 use std::fs;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
-// use chacha20poly1305::XChaCha20Poly1305;
+use argon2::{Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Nonce,
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce,
 };
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::warn;
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
+use x25519_dalek::{PublicKey, SharedSecret};
+use zeroize::Zeroizing;
 
-pub fn encrypt_small_file(
-    filepath: &str,
-    dist: &str,
+use super::error::UnlockError;
+use super::header::{
+    Cipher, HeaderKeys, KdfAlgorithm, KdfParams, VaultHeader, MAX_ARGON2_TIME,
+    MAX_PBKDF2_ITERATIONS, VERSION,
+};
+use super::keyfile::Keyfile;
+use super::memory;
+use super::recipient::Identity;
+use super::recovery_key::RecoveryKey;
+use super::secret::MasterKey;
+use super::slot::{KeySlot, VaultKeys};
+use crate::storage::{rename_synced, write_synced};
+
+/// Binds the keys wrapping a data key to a recipient to this application
+const X25519_INFO: &[u8] = b"rust_password_manager X25519";
+/// Nonce used by every vault written before the header existed
+const LEGACY_NONCE: &[u8; 12] = b"0123456789ab";
+/// Size of the authentication tag closing every ciphertext
+const TAG_LEN: usize = 16;
+
+/// Time spent deriving the key when unlocking a new vault
+pub const DEFAULT_UNLOCK_TIME: Duration = Duration::from_secs(1);
+/// Never go below this, whatever the calibration says
+pub const MIN_PBKDF2_ITERATIONS: u32 = 100_000;
+/// Memory used by Argon2id for new vaults, in KiB
+pub const ARGON2_MEMORY: u32 = 64 * 1024;
+/// Lanes used by Argon2id for new vaults
+pub const ARGON2_PARALLELISM: u32 = 1;
+/// Never go below this number of passes, whatever the calibration says
+pub const MIN_ARGON2_TIME: u32 = 2;
+
+/// Encrypt `plaintext` into a vault: a header holding the key slots followed
+/// by the ciphertext.
+///
+/// A fresh nonce is generated for each call, and the header is
+/// authenticated.
+pub fn encrypt(plaintext: &[u8], keys: &VaultKeys) -> Result<Vec<u8>, anyhow::Error> {
+    encrypt_with_decoy_area(plaintext, keys, None)
+}
+
+/// Encrypt `plaintext` like [`encrypt`], keeping `decoy_area` if given
+fn encrypt_with_decoy_area(
+    plaintext: &[u8],
+    keys: &VaultKeys,
+    decoy_area: Option<Vec<u8>>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let body_len = (plaintext.len() + TAG_LEN) as u64;
+    let header = VaultHeader::new(keys.slots().to_vec(), keys.data_key(), body_len)
+        .with_decoy_area(decoy_area);
+    let payload = Payload {
+        msg: plaintext,
+        aad: &header.associated_data(),
+    };
+    let ciphertext = encrypt_with(header.cipher, keys.data_key(), &header.nonce, payload)
+        .map_err(|err| anyhow!("Encrypting vault: {err}"))?;
+
+    let mut encrypted = header.to_bytes();
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+/// Decrypt a vault produced by [`encrypt`] with its data key, or a vault in
+/// an older format with the key derived from its master key.
+///
+/// The plaintext is kept out of the swap, and wiped from memory once
+/// dropped.
+/// ```
+/// use rust_password_manager::crypto::error::UnlockError;
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{decrypt, encrypt};
+///
+/// let keys = VaultKeys::create("master", "password", None, &KdfParams::pbkdf2(1000)).unwrap();
+/// let key = keys.data_key();
+/// let encrypted = encrypt(b"secret", &keys).unwrap();
+/// assert_eq!(*decrypt(&encrypted, key).unwrap(), b"secret");
+///
+/// // Two encryptions never share a nonce
+/// let other = encrypt(b"secret", &keys).unwrap();
+/// assert_ne!(encrypted, other);
+///
+/// // A wrong key is told apart from a damaged vault
+/// let err = decrypt(&encrypted, &[0u8; 32]).unwrap_err();
+/// assert!(matches!(err, UnlockError::WrongPassword));
+/// let err = decrypt(&encrypted[..encrypted.len() - 20], key).unwrap_err();
+/// assert!(matches!(err, UnlockError::Truncated));
+/// let mut damaged = encrypted.clone();
+/// *damaged.last_mut().unwrap() ^= 1;
+/// assert!(matches!(decrypt(&damaged, key).unwrap_err(), UnlockError::Corrupted));
+/// ```
+pub fn decrypt(data: &[u8], key: &[u8; 32]) -> Result<Zeroizing<Vec<u8>>, UnlockError> {
+    let decrypted = match VaultHeader::parse(data)? {
+        Some((header, header_len)) => {
+            if !header.check_key(key) {
+                return Err(UnlockError::WrongPassword);
+            }
+            // A body cut short is not a wrong key, nor worth decrypting
+            let body_len = header.body_len.unwrap_or(TAG_LEN as u64);
+            if ((data.len() - header_len) as u64) < body_len {
+                return Err(UnlockError::Truncated);
+            }
+
+            let payload = Payload {
+                msg: &data[header_len..],
+                aad: &header.associated_data(),
+            };
+            let verified = !matches!(
+                header.keys,
+                HeaderKeys::Derived {
+                    key_check: None,
+                    ..
+                }
+            );
+            decrypt_with(header.cipher, key, &header.nonce, payload).map_err(|_| match verified {
+                // The key is known to be right, so the ciphertext is wrong
+                true => UnlockError::Corrupted,
+                false => UnlockError::WrongPasswordOrCorrupted,
+            })
+        }
+        None => {
+            warn!("📦 Legacy vault without header, it will be rewritten in the new format");
+            decrypt_with(Cipher::ChaCha20Poly1305, key, LEGACY_NONCE, data.into())
+                .map_err(|_| UnlockError::WrongPasswordOrCorrupted)
+        }
+    };
+
+    decrypted.map(|plaintext| {
+        memory::lock(&plaintext);
+        Zeroizing::new(plaintext)
+    })
+}
+
+/// Encrypt with `cipher`, `nonce` being as long as its nonces
+fn encrypt_with(
+    cipher: Cipher,
     key: &[u8; 32],
-    // nonce: &[u8; 24],
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, chacha20poly1305::Error> {
+    match cipher {
+        Cipher::ChaCha20Poly1305 => {
+            ChaCha20Poly1305::new(key.into()).encrypt(Nonce::from_slice(nonce), payload)
+        }
+        Cipher::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.into()).encrypt(XNonce::from_slice(nonce), payload)
+        }
+    }
+}
+
+/// Decrypt with `cipher`, `nonce` being as long as its nonces
+fn decrypt_with(
+    cipher: Cipher,
+    key: &[u8; 32],
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, chacha20poly1305::Error> {
+    match cipher {
+        Cipher::ChaCha20Poly1305 => {
+            ChaCha20Poly1305::new(key.into()).decrypt(Nonce::from_slice(nonce), payload)
+        }
+        Cipher::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.into()).decrypt(XNonce::from_slice(nonce), payload)
+        }
+    }
+}
+
+/// Check if a keyfile may be needed to unlock an encrypted vault
+pub fn requires_keyfile(encrypted_file_path: &str) -> Result<bool, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
+    let requires_keyfile = match VaultHeader::parse(&file_data)? {
+        Some((header, _)) => header.requires_keyfile(),
+        None => false,
+    };
+    Ok(requires_keyfile)
+}
+
+/// Read the key slots of an encrypted vault, none if it has been written
+/// before they existed
+pub fn read_key_slots(encrypted_file_path: &str) -> Result<Vec<KeySlot>, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
+    Ok(read_slots(&file_data)?.unwrap_or_default())
+}
+
+/// Write the slots of an opened vault.
+///
+/// Only the header changes, unless the vault has been opened from a format
+/// without slots and has to be encrypted again as a whole.
+pub fn save_key_slots(
+    encrypted_file_path: &str,
+    vault: &UnlockedVault,
 ) -> Result<(), anyhow::Error> {
-    let cipher = ChaCha20Poly1305::new(key.into());
+    if vault.converted {
+        encrypt_small_file(&vault.plaintext, encrypted_file_path, &vault.keys)
+    } else {
+        update_key_slots(encrypted_file_path, &vault.keys)
+    }
+}
 
-    let file_data = fs::read(filepath)?;
+/// Replace the slots in the header of a vault, without encrypting its body
+/// again.
+///
+/// The vault on disk must be encrypted with the data key of `keys`. A vault
+/// written in an older version of the format is encrypted again as a whole,
+/// since its version is authenticated along with its body.
+pub fn update_key_slots(encrypted_file_path: &str, keys: &VaultKeys) -> Result<(), anyhow::Error> {
+    let file_data = fs::read(encrypted_file_path)?;
 
-    let nonce = Nonce::from_slice("0123456789ab".as_bytes());
+    let (header, header_len) =
+        VaultHeader::parse(&file_data)?.ok_or(anyhow!("The vault has no key slots yet"))?;
+    if !matches!(header.keys, HeaderKeys::Slots { .. }) {
+        return Err(anyhow!("The vault has no key slots yet"));
+    }
+    if !header.check_key(keys.data_key()) {
+        return Err(anyhow!("The vault on disk is not the one opened"));
+    }
+    let body_len = match header.body_len {
+        Some(body_len) if header.version == VERSION => body_len,
+        _ => {
+            let plaintext = decrypt(&file_data, keys.data_key())?;
+            return encrypt_small_file(&plaintext, encrypted_file_path, keys);
+        }
+    };
 
-    let encrypted_file = cipher
-        .encrypt(nonce, file_data.as_ref())
-        .map_err(|err| anyhow!("Encrypting small file: {err}"))?;
+    let header = VaultHeader::with_nonce(
+        keys.slots().to_vec(),
+        keys.data_key(),
+        header.cipher,
+        header.nonce,
+        body_len,
+    )
+    .with_decoy_area(header.decoy_area);
+    let mut encrypted_file = header.to_bytes();
+    encrypted_file.extend_from_slice(&file_data[header_len..]);
 
-    fs::write(dist, encrypted_file)?;
+    replace_verified(encrypted_file_path, &encrypted_file, keys.data_key(), None)
+}
 
-    Ok(())
+/// Encrypt `plaintext` and atomically replace `dist` with the result.
+///
+/// The new vault is first written and flushed next to `dist`, then read back
+/// and checked, so `dist` is only ever replaced by a vault known to decrypt,
+/// even if the machine crashes in the middle of the write. The decoy area of
+/// `dist` is kept.
+pub fn encrypt_small_file(
+    plaintext: &[u8],
+    dist: &str,
+    keys: &VaultKeys,
+) -> Result<(), anyhow::Error> {
+    let decoy_area = fs::read(dist)
+        .ok()
+        .and_then(|file_data| VaultHeader::parse(&file_data).ok().flatten())
+        .and_then(|(header, _)| header.decoy_area);
+    let encrypted_file = encrypt_with_decoy_area(plaintext, keys, decoy_area)?;
+    replace_verified(dist, &encrypted_file, keys.data_key(), Some(plaintext))
 }
 
-pub fn decrypt_small_file(
-    encrypted_file_path: &str,
+/// Replace `dist` with `encrypted_file` once it is on disk and decrypts, to
+/// `plaintext` if given
+fn replace_verified(
     dist: &str,
+    encrypted_file: &[u8],
     key: &[u8; 32],
+    plaintext: Option<&[u8]>,
 ) -> Result<(), anyhow::Error> {
-    let cipher = ChaCha20Poly1305::new(key.into());
+    let tmp = format!("{dist}.tmp");
+    write_synced(&tmp, encrypted_file)?;
+
+    let verified = fs::read(&tmp)
+        .map_err(anyhow::Error::from)
+        .and_then(|written| Ok(decrypt(&written, key)?));
+    match verified {
+        Ok(decrypted_file) if plaintext.is_none_or(|plaintext| *decrypted_file == plaintext) => {
+            rename_synced(&tmp, dist)?;
+            Ok(())
+        }
+        Ok(_) => {
+            fs::remove_file(&tmp)?;
+            Err(anyhow!(
+                "The new vault does not decrypt to the same content"
+            ))
+        }
+        Err(err) => {
+            fs::remove_file(&tmp)?;
+            Err(err)
+        }
+    }
+}
 
+/// Decrypt a vault in memory, the plaintext never reaches the filesystem
+pub fn decrypt_small_file(
+    encrypted_file_path: &str,
+    key: &[u8; 32],
+) -> Result<Zeroizing<Vec<u8>>, UnlockError> {
     let file_data = fs::read(encrypted_file_path)?;
 
-    let nonce = Nonce::from_slice("0123456789ab".as_bytes());
+    decrypt(&file_data, key)
+}
+
+/// A vault decrypted in memory, with what is needed to encrypt it again
+pub struct UnlockedVault {
+    pub plaintext: Zeroizing<Vec<u8>>,
+    pub keys: VaultKeys,
+    /// Opened from a format without key slots, the whole vault has to be
+    /// encrypted again
+    pub converted: bool,
+}
+
+/// Open a slot of a vault with `password`, and decrypt the vault in memory.
+///
+/// `keyfile` is only used by the slots requiring one. A vault written before
+/// the slots existed gets a random data key, wrapped in a single slot by the
+/// key derived from `password`.
+/// ```
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{encrypt_small_file, unlock_small_file, update_key_slots};
+///
+/// let path = std::env::temp_dir().join(format!("rpm-unlock-{}", std::process::id()));
+/// let path = path.to_str().unwrap();
+/// let kdf = KdfParams::pbkdf2(1000);
+/// let mut keys = VaultKeys::create("alice", "first", None, &kdf).unwrap();
+/// encrypt_small_file(b"secret", path, &keys).unwrap();
+/// let body = std::fs::read(path).unwrap();
+///
+/// // A new slot only changes the header
+/// keys.add_passphrase("bob", "second", None, &kdf).unwrap();
+/// update_key_slots(path, &keys).unwrap();
+/// let vault = unlock_small_file(path, "second", None).unwrap();
+/// assert_eq!(*vault.plaintext, b"secret");
+/// assert!(std::fs::read(path).unwrap().ends_with(&body[body.len() - 22..]));
+///
+/// assert!(unlock_small_file(path, "third", None).is_err());
+/// std::fs::remove_file(path).unwrap();
+/// ```
+pub fn unlock_small_file(
+    encrypted_file_path: &str,
+    password: &str,
+    keyfile: Option<&Keyfile>,
+) -> Result<UnlockedVault, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
 
-    let decrypted_file = cipher
-        .decrypt(nonce, file_data.as_ref())
-        .map_err(|err| anyhow!("Decrypting small file: {}", err))?;
+    let (kdf, requires_keyfile) = match VaultHeader::parse(&file_data)? {
+        Some((
+            VaultHeader {
+                keys: HeaderKeys::Slots { slots, .. },
+                ..
+            },
+            _,
+        )) => {
+            let keys = VaultKeys::unlock(&slots, password, keyfile)?;
+            return open_slots(&file_data, keys);
+        }
+        Some((
+            VaultHeader {
+                keys: HeaderKeys::Derived { kdf, keyfile, .. },
+                ..
+            },
+            _,
+        )) => (kdf, keyfile),
+        None => (KdfParams::legacy(), false),
+    };
 
-    fs::write(dist, decrypted_file)?;
+    let keyfile = match (requires_keyfile, keyfile) {
+        (true, None) => return Err(UnlockError::KeyfileRequired),
+        (true, keyfile) => keyfile,
+        (false, _) => None,
+    };
+    let key = gen_key_from_password(password, keyfile, &kdf)
+        .map_err(|err| UnlockError::BadHeader(err.to_string()))?;
+    let plaintext = decrypt(&file_data, &key)?;
 
-    Ok(())
+    let keys = VaultKeys::from_derived_key(&key, &kdf, requires_keyfile)
+        .map_err(|err| UnlockError::BadHeader(err.to_string()))?;
+    Ok(UnlockedVault {
+        plaintext,
+        keys,
+        converted: true,
+    })
+}
+
+/// Open the slot wrapping the data key of a vault to `identity`, and decrypt
+/// the vault in memory.
+///
+/// Vaults written before the slots existed have no recipients.
+/// ```
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::recipient::Identity;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{encrypt_small_file, unlock_small_file_with_identity};
+///
+/// let path = std::env::temp_dir().join(format!("rpm-identity-unlock-{}", std::process::id()));
+/// let path = path.to_str().unwrap();
+/// let mut keys = VaultKeys::create("owner", "first", None, &KdfParams::pbkdf2(1000)).unwrap();
+/// let alice = Identity::generate();
+/// keys.add_recipient("alice", &alice.recipient()).unwrap();
+/// encrypt_small_file(b"secret", path, &keys).unwrap();
+///
+/// let vault = unlock_small_file_with_identity(path, &alice).unwrap();
+/// assert_eq!(*vault.plaintext, b"secret");
+/// assert_eq!(vault.keys.opened().label, "alice");
+///
+/// assert!(unlock_small_file_with_identity(path, &Identity::generate()).is_err());
+/// std::fs::remove_file(path).unwrap();
+/// ```
+pub fn unlock_small_file_with_identity(
+    encrypted_file_path: &str,
+    identity: &Identity,
+) -> Result<UnlockedVault, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
+    let slots = read_slots(&file_data)?.ok_or(UnlockError::NotRecipient)?;
+    let keys = VaultKeys::unlock_with_identity(&slots, identity)?;
+    open_slots(&file_data, keys)
+}
+
+/// Open the recovery slot of a vault with its recovery key, and decrypt the
+/// vault in memory
+/// ```
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::recovery_key::RecoveryKey;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{encrypt_small_file, unlock_small_file, unlock_small_file_with_recovery_key};
+///
+/// let path = std::env::temp_dir().join(format!("rpm-recovery-{}", std::process::id()));
+/// let path = path.to_str().unwrap();
+/// let kdf = KdfParams::pbkdf2(1000);
+/// let mut keys = VaultKeys::create("master", "forgotten", None, &kdf).unwrap();
+/// let recovery = RecoveryKey::generate();
+/// keys.set_recovery_key(&recovery).unwrap();
+/// encrypt_small_file(b"secret", path, &keys).unwrap();
+///
+/// let mut vault = unlock_small_file_with_recovery_key(path, &recovery).unwrap();
+/// assert_eq!(*vault.plaintext, b"secret");
+/// assert!(unlock_small_file_with_recovery_key(path, &RecoveryKey::generate()).is_err());
+///
+/// // A new master key replaces the forgotten one
+/// vault.keys.reset_passphrase("master", "remembered", &kdf).unwrap();
+/// encrypt_small_file(&vault.plaintext, path, &vault.keys).unwrap();
+/// assert!(unlock_small_file(path, "remembered", None).is_ok());
+/// assert!(unlock_small_file(path, "forgotten", None).is_err());
+/// std::fs::remove_file(path).unwrap();
+/// ```
+pub fn unlock_small_file_with_recovery_key(
+    encrypted_file_path: &str,
+    recovery: &RecoveryKey,
+) -> Result<UnlockedVault, UnlockError> {
+    let file_data = fs::read(encrypted_file_path)?;
+    let slots = read_slots(&file_data)?.ok_or(UnlockError::NoRecoveryKey)?;
+    let keys = VaultKeys::unlock_with_recovery_key(&slots, recovery)?;
+    open_slots(&file_data, keys)
+}
+
+/// The slots of a vault, if it has been written with slots
+fn read_slots(file_data: &[u8]) -> Result<Option<Vec<KeySlot>>, UnlockError> {
+    match VaultHeader::parse(file_data)? {
+        Some((
+            VaultHeader {
+                keys: HeaderKeys::Slots { slots, .. },
+                ..
+            },
+            _,
+        )) => Ok(Some(slots)),
+        _ => Ok(None),
+    }
+}
+
+/// Decrypt a vault with the data key unwrapped from one of its slots
+fn open_slots(file_data: &[u8], keys: VaultKeys) -> Result<UnlockedVault, UnlockError> {
+    // The data key comes from a slot, so a header failing its check has been
+    // tampered with
+    let plaintext =
+        decrypt(file_data, keys.data_key()).map_err(|err| match err.is_wrong_password() {
+            true => UnlockError::Corrupted,
+            false => err,
+        })?;
+    Ok(UnlockedVault {
+        plaintext,
+        keys,
+        converted: false,
+    })
+}
+
+/// Derive the key wrapping a data key to a recipient, from the secret shared
+/// by an ephemeral key and the key of the recipient.
+///
+/// Both public keys are mixed in, the same way age does.
+///
+/// # Arguments
+/// * `shared` - The X25519 shared secret
+/// * `ephemeral` - The public key generated for this slot only
+/// * `recipient` - The public key of the recipient
+/// # Returns
+/// * A key, or an error if a public key is of low order
+///
+pub fn gen_key_from_shared_secret(
+    shared: &SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<MasterKey, anyhow::Error> {
+    if !shared.was_contributory() {
+        return Err(anyhow!("Refusing an X25519 key of low order"));
+    }
+
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = MasterKey::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(X25519_INFO, key.as_mut_bytes())
+        .map_err(|err| anyhow!("Deriving key with HKDF: {err}"))?;
+    Ok(key)
 }
 
 //Write doctest
 //
-/// Generate a key from a password
+/// Generate a key from a password, and optionally a keyfile, wiped from
+/// memory once dropped
 /// ```
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::keyfile::{create_keyfile, Keyfile};
 /// use rust_password_manager::crypto::utils::gen_key_from_password;
 ///
-/// let password = "password".to_string();
-/// let key = gen_key_from_password(password);
+/// let password = "password";
+/// let key = gen_key_from_password(password, None, &KdfParams::legacy()).unwrap();
+/// assert_eq!(key.len(), 32);
+///
+/// let kdf = KdfParams::argon2id(1024, 2, 1);
+/// let key = gen_key_from_password(password, None, &kdf).unwrap();
 /// assert_eq!(key.len(), 32);
+///
+/// let path = std::env::temp_dir().join(format!("rpm-gen-key-{}", std::process::id()));
+/// create_keyfile(&path).unwrap();
+/// let keyfile = Keyfile::read(&path).unwrap();
+/// let with_keyfile = gen_key_from_password(password, Some(&keyfile), &kdf).unwrap();
+/// assert!(with_keyfile != key);
+/// std::fs::remove_file(path).unwrap();
 /// ```
 ///
 /// # Arguments
 /// * `password` - A password
+/// * `keyfile` - The keyfile the vault is bound to, if any
+/// * `kdf` - The key derivation parameters stored in the vault header
 /// # Returns
-/// * A key
+/// * A key, or an error if the parameters are invalid
 ///
-pub fn gen_key_from_password(password: String) -> [u8; 32] {
-    //TODO: put all this in a config file
-    let salt = b"salt";
-    let n = 4096;
+pub fn gen_key_from_password(
+    password: &str,
+    keyfile: Option<&Keyfile>,
+    kdf: &KdfParams,
+) -> Result<MasterKey, anyhow::Error> {
+    let mut key = MasterKey::default();
+
+    match kdf {
+        KdfParams::Pbkdf2Sha256 { iterations, salt } => {
+            pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, *iterations, key.as_mut_bytes());
+        }
+        KdfParams::Argon2id {
+            memory,
+            time,
+            parallelism,
+            salt,
+        } => {
+            argon2id(*memory, *time, *parallelism)?
+                .hash_password_into(password.as_bytes(), salt, key.as_mut_bytes())
+                .map_err(|err| anyhow!("Deriving key with Argon2id: {err}"))?;
+        }
+    }
+
+    // Both the master key and the keyfile are needed to get the final key
+    if let Some(keyfile) = keyfile {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&*key).expect("HMAC accepts any key size");
+        mac.update(b"rust_password_manager keyfile");
+        mac.update(keyfile.digest());
+        key.as_mut_bytes()
+            .copy_from_slice(&mac.finalize().into_bytes());
+    }
+
+    Ok(key)
+}
+
+fn argon2id(memory: u32, time: u32, parallelism: u32) -> Result<Argon2<'static>, anyhow::Error> {
+    let params = Params::new(memory, time, parallelism, Some(32))
+        .map_err(|err| anyhow!("Invalid Argon2id parameters: {err}"))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// Find the key derivation parameters that take about `unlock_time` on this
+/// machine, with a fresh random salt
+pub fn calibrate_kdf(algorithm: KdfAlgorithm, unlock_time: Duration) -> KdfParams {
+    match algorithm {
+        KdfAlgorithm::Pbkdf2 => {
+            let probe_iterations = 10_000;
+            let mut key = [0u8; 32];
+
+            let elapsed = measure(|| {
+                pbkdf2_hmac::<Sha256>(b"calibration", b"calibration", probe_iterations, &mut key);
+            });
+
+            let iterations = probe_iterations as f64 * unlock_time.as_secs_f64() / elapsed;
+            let iterations =
+                (iterations as u32).clamp(MIN_PBKDF2_ITERATIONS, MAX_PBKDF2_ITERATIONS);
+
+            KdfParams::pbkdf2(iterations)
+        }
+        KdfAlgorithm::Argon2id => {
+            let mut key = [0u8; 32];
+            let argon2 = argon2id(ARGON2_MEMORY, 1, ARGON2_PARALLELISM)
+                .expect("default Argon2id parameters are valid");
+
+            let elapsed = measure(|| {
+                let _ = argon2.hash_password_into(b"calibration", b"calibration", &mut key);
+            });
+
+            let time = (unlock_time.as_secs_f64() / elapsed) as u32;
+            let time = time.clamp(MIN_ARGON2_TIME, MAX_ARGON2_TIME);
+
+            KdfParams::argon2id(ARGON2_MEMORY, time, ARGON2_PARALLELISM)
+        }
+    }
+}
+
+/// Seconds spent running `f`
+fn measure(mut f: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    f();
+    start.elapsed().max(Duration::from_micros(1)).as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::header::{NONCE_LEN, XNONCE_LEN};
+
+    // Known answers shared by RFC 8439, section 2.8.2, and
+    // draft-arciszewski-xchacha-03, appendix A.1
+    const KEY: &[u8; 32] = &[
+        0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e,
+        0x8f, 0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d,
+        0x9e, 0x9f,
+    ];
+    const AAD: &[u8] = &[
+        0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    ];
+    const PLAINTEXT: &[u8] = b"Ladies and Gentlemen of the class of '99: \
+        If I could offer you only one tip for the future, sunscreen would be it.";
+
+    const CHACHA20_NONCE: &[u8; NONCE_LEN] = &[
+        0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+    ];
+    /// Ciphertext followed by its tag
+    const CHACHA20_SEALED: &[u8] = &[
+        0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef, 0x7e,
+        0xc2, 0xa4, 0xad, 0xed, 0x51, 0x29, 0x6e, 0x08, 0xfe, 0xa9, 0xe2, 0xb5, 0xa7, 0x36, 0xee,
+        0x62, 0xd6, 0x3d, 0xbe, 0xa4, 0x5e, 0x8c, 0xa9, 0x67, 0x12, 0x82, 0xfa, 0xfb, 0x69, 0xda,
+        0x92, 0x72, 0x8b, 0x1a, 0x71, 0xde, 0x0a, 0x9e, 0x06, 0x0b, 0x29, 0x05, 0xd6, 0xa5, 0xb6,
+        0x7e, 0xcd, 0x3b, 0x36, 0x92, 0xdd, 0xbd, 0x7f, 0x2d, 0x77, 0x8b, 0x8c, 0x98, 0x03, 0xae,
+        0xe3, 0x28, 0x09, 0x1b, 0x58, 0xfa, 0xb3, 0x24, 0xe4, 0xfa, 0xd6, 0x75, 0x94, 0x55, 0x85,
+        0x80, 0x8b, 0x48, 0x31, 0xd7, 0xbc, 0x3f, 0xf4, 0xde, 0xf0, 0x8e, 0x4b, 0x7a, 0x9d, 0xe5,
+        0x76, 0xd2, 0x65, 0x86, 0xce, 0xc6, 0x4b, 0x61, 0x16, 0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09,
+        0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60, 0x06, 0x91,
+    ];
+
+    const XCHACHA20_NONCE: &[u8; XNONCE_LEN] = &[
+        0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e,
+        0x4f, 0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57,
+    ];
+    /// Ciphertext followed by its tag
+    const XCHACHA20_SEALED: &[u8] = &[
+        0xbd, 0x6d, 0x17, 0x9d, 0x3e, 0x83, 0xd4, 0x3b, 0x95, 0x76, 0x57, 0x94, 0x93, 0xc0, 0xe9,
+        0x39, 0x57, 0x2a, 0x17, 0x00, 0x25, 0x2b, 0xfa, 0xcc, 0xbe, 0xd2, 0x90, 0x2c, 0x21, 0x39,
+        0x6c, 0xbb, 0x73, 0x1c, 0x7f, 0x1b, 0x0b, 0x4a, 0xa6, 0x44, 0x0b, 0xf3, 0xa8, 0x2f, 0x4e,
+        0xda, 0x7e, 0x39, 0xae, 0x64, 0xc6, 0x70, 0x8c, 0x54, 0xc2, 0x16, 0xcb, 0x96, 0xb7, 0x2e,
+        0x12, 0x13, 0xb4, 0x52, 0x2f, 0x8c, 0x9b, 0xa4, 0x0d, 0xb5, 0xd9, 0x45, 0xb1, 0x1b, 0x69,
+        0xb9, 0x82, 0xc1, 0xbb, 0x9e, 0x3f, 0x3f, 0xac, 0x2b, 0xc3, 0x69, 0x48, 0x8f, 0x76, 0xb2,
+        0x38, 0x35, 0x65, 0xd3, 0xff, 0xf9, 0x21, 0xf9, 0x66, 0x4c, 0x97, 0x63, 0x7d, 0xa9, 0x76,
+        0x88, 0x12, 0xf6, 0x15, 0xc6, 0x8b, 0x13, 0xb5, 0x2e, 0xc0, 0x87, 0x59, 0x24, 0xc1, 0xc7,
+        0x98, 0x79, 0x47, 0xde, 0xaf, 0xd8, 0x78, 0x0a, 0xcf, 0x49,
+    ];
+
+    fn payload(msg: &[u8]) -> Payload<'_, '_> {
+        Payload { msg, aad: AAD }
+    }
+
+    fn check_known_answer(cipher: Cipher, nonce: &[u8], sealed: &[u8]) {
+        assert_eq!(nonce.len(), cipher.nonce_len());
+        let encrypted = encrypt_with(cipher, KEY, nonce, payload(PLAINTEXT)).unwrap();
+        assert_eq!(encrypted, sealed);
+        let decrypted = decrypt_with(cipher, KEY, nonce, payload(sealed)).unwrap();
+        assert_eq!(decrypted, PLAINTEXT);
+
+        let mut tampered = sealed.to_vec();
+        tampered[0] ^= 0xaa;
+        assert!(decrypt_with(cipher, KEY, nonce, payload(&tampered)).is_err());
+    }
+
+    #[test]
+    fn should_match_chacha20_poly1305_known_answer() {
+        check_known_answer(Cipher::ChaCha20Poly1305, CHACHA20_NONCE, CHACHA20_SEALED);
+    }
+
+    #[test]
+    fn should_match_xchacha20_poly1305_known_answer() {
+        check_known_answer(Cipher::XChaCha20Poly1305, XCHACHA20_NONCE, XCHACHA20_SEALED);
+    }
+
+    #[test]
+    fn should_encrypt_new_vaults_with_xchacha20_poly1305() {
+        let keys = VaultKeys::create("master", "password", None, &KdfParams::pbkdf2(1000)).unwrap();
+        let encrypted = encrypt(b"secret", &keys).unwrap();
+        let (header, _) = VaultHeader::parse(&encrypted).unwrap().unwrap();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.cipher, Cipher::XChaCha20Poly1305);
+        assert_eq!(header.nonce.len(), XNONCE_LEN);
+        assert_eq!(*decrypt(&encrypted, keys.data_key()).unwrap(), b"secret");
+    }
 
-    let mut key = [0u8; 32];
+    #[test]
+    fn should_report_a_vault_cut_mid_body_as_truncated() {
+        let path = std::env::temp_dir().join(format!("rpm-cut-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let keys = VaultKeys::create("master", "password", None, &KdfParams::pbkdf2(1000)).unwrap();
+        encrypt_small_file(&[7u8; 4096], path, &keys).unwrap();
+        let encrypted = fs::read(path).unwrap();
+        let (_, header_len) = VaultHeader::parse(&encrypted).unwrap().unwrap();
 
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, n, &mut key);
+        for len in [header_len + TAG_LEN, header_len + 2048, encrypted.len() - 1] {
+            fs::write(path, &encrypted[..len]).unwrap();
+            let err = unlock_small_file(path, "password", None).err().unwrap();
+            assert!(matches!(err, UnlockError::Truncated), "{len}: {err:?}");
+            assert!(!err.is_wrong_password());
+        }
 
-    key
+        fs::write(path, &encrypted).unwrap();
+        assert!(unlock_small_file(path, "password", None).is_ok());
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -1,35 +1,93 @@
 use std::path::Path;
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use eyre::Result;
-use log::{error, info};
+use log::{error, info, warn};
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
-use super::IoEvent;
+use super::{IoEvent, KeyEdit, PasswordChange, Recovery};
+use crate::app::state::StatefulList;
 use crate::app::App;
-use crate::crypto::utils::{decrypt_small_file, encrypt_small_file, gen_key_from_password};
+use crate::config::Config;
+use crate::crypto::decoy::{destroy_key_slots, open_decoy, write_decoy, Decoy, DuressKey};
+use crate::crypto::error::UnlockError;
+use crate::crypto::header::KdfAlgorithm;
+use crate::crypto::keyfile::Keyfile;
+use crate::crypto::memory;
+use crate::crypto::recipient::Identity;
+use crate::crypto::recovery_key::RecoveryKey;
+use crate::crypto::secret::SecretString;
+use crate::crypto::slot::{KeySlot, VaultKeys, DEFAULT_SLOT_LABEL, RECOVERY_SLOT_LABEL};
+use crate::crypto::utils::{
+    calibrate_kdf, decrypt_small_file, encrypt_small_file, read_key_slots, requires_keyfile,
+    unlock_small_file, unlock_small_file_with_identity, unlock_small_file_with_recovery_key,
+    update_key_slots, UnlockedVault,
+};
 use crate::models::key::Key;
+use crate::repository::{stored_user_version, Backend, KeyRepository, SCHEMA_VERSION};
+use crate::storage::backup::{backup_vault, list_backups, restore_backup};
+use crate::storage::recovery::{detect_issue, quarantine, VaultIssue};
+use crate::storage::verify::verify_vault;
 
 /// In the IO thread, we handle IO event without blocking the UI thread
 pub struct IoAsyncHandler {
     app: Arc<tokio::sync::Mutex<App>>,
-    password: String,
+    config: Config,
+    /// Unwrapped once when the vault is opened, the master key itself is
+    /// never kept
+    keys: Option<VaultKeys>,
+    /// The keyfile the vault has been opened with, needed to wrap its data
+    /// key again
+    keyfile: Option<Keyfile>,
+    /// Set instead of `keys` when the duress password opened the decoy
+    decoy: Option<DuressKey>,
+    /// The slots shown along the decoy, the ones of the real vault until
+    /// they change there: they are sealed with the decoy, never written in
+    /// the header
+    decoy_slots: Option<VaultKeys>,
+    /// A key has been copied since the vault was opened: its usage date is
+    /// only written when the vault is locked or closed
+    usage_unsaved: bool,
 }
 
 impl IoAsyncHandler {
-    pub fn new(app: Arc<tokio::sync::Mutex<App>>) -> Self {
+    pub fn new(app: Arc<tokio::sync::Mutex<App>>, config: Config) -> Self {
         Self {
             app,
-            password: String::new(),
+            config,
+            keys: None,
+            keyfile: None,
+            decoy: None,
+            decoy_slots: None,
+            usage_unsaved: false,
         }
     }
 
     /// We could be async here
     pub async fn handle_io_event(&mut self, io_event: IoEvent) {
         let result = match io_event {
+            IoEvent::CheckVault => self.check_vault().await,
             IoEvent::Initialize => self.do_initialize().await,
-            IoEvent::Copy(key) => self.do_copy(key).await,
+            IoEvent::Copy(id) => self.do_copy(id).await,
             IoEvent::RegisterKey(key) => self.register_key(key).await,
+            IoEvent::EditKey(edit) => self.edit_key(edit).await,
+            IoEvent::ChangeMasterKey { current, new } => self.change_master_key(current, new).await,
+            IoEvent::Recover(recovery) => self.recover(recovery).await,
+            IoEvent::AddSlot { label, password } => self.add_slot(label, password).await,
+            IoEvent::RevokeSlot(label) => self.revoke_slot(label).await,
+            IoEvent::NewRecoveryKey => self.new_recovery_key().await,
+            IoEvent::UnlockWithRecoveryKey {
+                recovery_key,
+                password,
+            } => self.unlock_with_recovery_key(recovery_key, password).await,
+            IoEvent::VerifyVault => self.verify_vault().await,
+            IoEvent::PasswordHistory(key_id) => self.load_history(key_id).await,
+            IoEvent::CopyPreviousPassword(id) => self.copy_previous_password(id).await,
+            IoEvent::RestorePassword(id) => self.restore_password(id).await,
             IoEvent::Refresh => self.refresh_application_state().await,
+            IoEvent::Lock => self.lock_vault().await,
             IoEvent::Close => self.close_application().await,
         };
 
@@ -41,6 +99,132 @@ impl IoAsyncHandler {
         app.loaded();
     }
 
+    async fn check_vault(&mut self) -> Result<()> {
+        if self.config.read_only {
+            warn!("👀 Read-only: the vault is not locked, and changes will not be saved");
+        }
+
+        let mut app = self.app.lock().await;
+        check_issue(&mut app, &self.config);
+        Ok(())
+    }
+
+    async fn recover(&mut self, recovery: Recovery) -> Result<()> {
+        if self.refuse_read_only("recover the vault") {
+            return Ok(());
+        }
+
+        let mut app = self.app.lock().await;
+        let Some(issue) = app.state().recovery_issue() else {
+            return Ok(());
+        };
+        let vault = &self.config.vault;
+        let plaintext = self.config.plaintext_path();
+
+        match recovery {
+            Recovery::ReEncrypt(password) => {
+                info!("⏳ Re-encrypting the plaintext database");
+                match self.reencrypt_plaintext(&password) {
+                    Err(err) => {
+                        error!("Cannot re-encrypt the plaintext database: {:?}", err);
+                        app.clear_input_buffer();
+                    }
+                    Ok((repository, keys, _, keyfile)) => {
+                        app.data.repository = Some(repository);
+
+                        // Never drop the plaintext before its encrypted copy is on disk
+                        let res = write_vault(&app, &keys, &self.config, true)
+                            .and_then(|_| Ok(std::fs::remove_file(&plaintext)?));
+                        if let Err(err) = res {
+                            error!("Cannot replace the plaintext database: {:?}", err);
+                            app.data.repository = None;
+                            app.clear_input_buffer();
+                            return Ok(());
+                        }
+                        info!("🔒 Plaintext database encrypted and removed");
+                        app.data.set_slots(&keys);
+                        self.keys = Some(keys);
+                        self.keyfile = keyfile;
+
+                        app.data.load_key_list();
+                        app.toggle_input_mode();
+                        app.clear_input_buffer();
+                        app.initialized();
+                        info!("🍾 Application initialized");
+                    }
+                }
+            }
+            Recovery::OpenBackup => {
+                let res = list_backups(vault)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|backups| {
+                        let latest = backups.first().ok_or(anyhow!("There is no backup"))?;
+                        if issue.has_plaintext() {
+                            let moved = quarantine(&plaintext)?;
+                            warn!("☣️ Plaintext database moved to {}", moved.display());
+                        }
+                        restore_backup(vault, latest, self.config.backups)?;
+                        Ok(latest.clone())
+                    });
+                match res {
+                    Err(err) => error!("Cannot open the latest backup: {:?}", err),
+                    Ok(backup) => {
+                        info!("⏪ Vault rolled back to the backup of {backup}");
+                        check_issue(&mut app, &self.config);
+                    }
+                }
+            }
+            Recovery::Quarantine => match quarantine(&plaintext) {
+                Err(err) => error!("Cannot quarantine the plaintext database: {:?}", err),
+                Ok(moved) => {
+                    warn!("☣️ Plaintext database moved to {}", moved.display());
+                    check_issue(&mut app, &self.config);
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Load the plaintext database, after checking the master key typed by the
+    /// user opens the vault it comes from.
+    ///
+    /// The slots of that vault are kept, along with the keyfile if the slot
+    /// opened needs it.
+    fn reencrypt_plaintext(&self, password: &SecretString) -> Result<OpenedVault, anyhow::Error> {
+        let vault = &self.config.vault;
+        let keyfile = read_keyfile(self.config.keyfile.as_deref())?;
+        let reference = if Path::new(vault).exists() {
+            Some(vault.clone())
+        } else {
+            list_backups(vault)?
+                .first()
+                .map(|backup| backup.path.to_string_lossy().into_owned())
+        };
+
+        let plaintext = Zeroizing::new(std::fs::read(self.config.plaintext_path())?);
+        let repository = self.config.backend.load(&plaintext)?;
+        match reference {
+            Some(reference) => {
+                let vault = unlock_small_file(&reference, password.expose(), keyfile.as_ref())
+                    .map_err(|err| anyhow!("{err}, cannot open {reference}"))?;
+                let keyfile = keyfile.filter(|_| vault.keys.opened().requires_keyfile());
+                Ok((repository, vault.keys, vault.converted, keyfile))
+            }
+            None => {
+                warn!("🤷 No encrypted vault to check the master key against");
+                let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+                let keys = VaultKeys::create(
+                    DEFAULT_SLOT_LABEL,
+                    password.expose(),
+                    keyfile.as_ref(),
+                    &kdf,
+                )?;
+                Ok((repository, keys, false, keyfile))
+            }
+        }
+    }
+
     async fn refresh_application_state(&mut self) -> Result<()> {
         info!("🔄 Refresh application state");
         let mut app = self.app.lock().await;
@@ -49,39 +233,490 @@ impl IoAsyncHandler {
     }
 
     async fn register_key(&mut self, key: Key) -> Result<()> {
-        let save = key.persist();
+        if self.refuse_read_only("save key") {
+            return Ok(());
+        }
+
+        let mut app = self.app.lock().await;
+        let Some(repository) = &mut app.data.repository else {
+            error!("Cannot save key: the vault is not opened");
+            return Ok(());
+        };
+
+        let save = repository.insert(&key);
         if let Err(err) = save {
             error!("Cannot save key: {:?}", err);
         } else {
             info!("🔑 Key saved");
+            self.save_vault(&app);
         }
         Ok(())
     }
 
-    async fn do_copy(&mut self, key: Key) -> Result<()> {
+    /// Apply the changes made in the form of a key. Only the fields that
+    /// changed are set, so the dates of the key only move when they should.
+    async fn edit_key(&mut self, edit: KeyEdit) -> Result<()> {
+        if self.refuse_read_only("edit the key") {
+            return Ok(());
+        }
+
+        let mut guard = self.app.lock().await;
+        let app = &mut *guard;
+        let data = &mut app.data;
+        let Some(key) = data.keys.items.iter_mut().find(|key| key.id() == edit.id) else {
+            error!("Cannot edit: the key is not in the vault");
+            return Ok(());
+        };
+        let Some(repository) = &mut data.repository else {
+            error!("Cannot edit: the vault is not opened");
+            return Ok(());
+        };
+
+        let previous = key.clone();
+        if key.name() != edit.name {
+            key.set_name(edit.name);
+        }
+        if key.username() != edit.username {
+            key.set_username(edit.username);
+        }
+        if key.urls() != edit.urls {
+            key.set_urls(edit.urls);
+        }
+        if key.notes() != edit.notes {
+            key.set_notes(edit.notes);
+        }
+        match edit.password {
+            PasswordChange::Keep => (),
+            PasswordChange::Set(password) if password.expose() == key.password() => (),
+            PasswordChange::Set(password) => key.set_password(password),
+            PasswordChange::Generate => key.update_password(),
+        }
+
+        if key.updated_at() == previous.updated_at() {
+            info!("✏️ Nothing to change in {}", key.name());
+            return Ok(());
+        }
+        if let Err(err) = repository.update(key) {
+            error!("Cannot edit the key: {:?}", err);
+            *key = previous;
+            return Ok(());
+        }
+        if key.last_changed_at() != previous.last_changed_at() {
+            info!("🔑 Key {} updated, with a new password", key.name());
+        } else {
+            info!("🔑 Key {} updated", key.name());
+        }
+
+        self.save_vault(app);
+        Ok(())
+    }
+
+    async fn change_master_key(&mut self, current: SecretString, new: SecretString) -> Result<()> {
+        if self.refuse_read_only("change the master key") {
+            return Ok(());
+        }
+        if self.decoy.is_some() {
+            return self.change_duress_password(current, new).await;
+        }
+        let Some(keys) = &mut self.keys else {
+            error!("Cannot change the master key: the vault is locked");
+            return Ok(());
+        };
+
+        if keys.opened().kdf().is_none() {
+            error!(
+                "Cannot change the master key: the vault has been opened with the identity of {}",
+                keys.opened().label
+            );
+            return Ok(());
+        }
+        // The current master key has to open the slot the vault is opened with
+        if !keys.check_passphrase(current.expose(), self.keyfile.as_ref()) {
+            error!("❌ Wrong master key, it has not been changed");
+            return Ok(());
+        }
+
+        info!("⏳ Wrapping the data key with the new master key");
+        let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+        let previous = keys.opened().clone();
+        let res = keys
+            .rewrap_opened(new.expose(), self.keyfile.as_ref(), &kdf)
+            .and_then(|_| write_key_slots(keys, &self.config, None));
+        match res {
+            Err(err) => {
+                error!("Cannot change the master key: {:?}", err);
+                keys.restore(previous);
+            }
+            Ok(_) => info!("🔑 Master key of the slot {} changed", keys.opened().label),
+        }
+
+        Ok(())
+    }
+
+    /// Seal the decoy with another duress password, shown to the user as a
+    /// master key change
+    async fn change_duress_password(
+        &mut self,
+        current: SecretString,
+        new: SecretString,
+    ) -> Result<()> {
+        let Some(decoy) = &self.decoy else {
+            return Ok(());
+        };
+        if !decoy.check_password(current.expose()) {
+            error!("❌ Wrong master key, it has not been changed");
+            return Ok(());
+        }
+
+        info!("⏳ Wrapping the data key with the new master key");
+        let app = self.app.lock().await;
+        let slots = self.decoy_slots.as_ref();
+        let res = decoy.with_password(new.expose()).and_then(|decoy| {
+            write_decoy_vault(&app, &decoy, slots, &self.config, true)?;
+            Ok(decoy)
+        });
+        match res {
+            Err(err) => error!("Cannot change the master key: {:?}", err),
+            Ok(decoy) => {
+                self.decoy = Some(decoy);
+                info!("🔑 Master key of the slot {DEFAULT_SLOT_LABEL} changed");
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_slot(&mut self, label: String, password: SecretString) -> Result<()> {
+        if self.refuse_read_only("add a key slot") {
+            return Ok(());
+        }
+        let Some(keys) = self.keys.as_mut().or(self.decoy_slots.as_mut()) else {
+            error!("Cannot add a key slot: the vault is locked");
+            return Ok(());
+        };
+
+        info!("⏳ Wrapping the data key in a new slot");
+        let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+        let res = keys.add_passphrase(&label, password.expose(), None, &kdf);
+        let mut app = self.app.lock().await;
+        let decoy = self.decoy.as_ref().map(|decoy| (decoy, &*app));
+        let res = res.and_then(|_| {
+            write_key_slots(keys, &self.config, decoy).inspect_err(|_| {
+                // Never keep a slot that is not on disk
+                let _ = keys.revoke(&label);
+            })
+        });
+        match res {
+            Err(err) => error!("Cannot add the key slot: {:?}", err),
+            Ok(_) => info!("🔑 Key slot {label} added"),
+        }
+
+        app.data.set_slots(keys);
+        Ok(())
+    }
+
+    async fn revoke_slot(&mut self, label: String) -> Result<()> {
+        if self.refuse_read_only("revoke a key slot") {
+            return Ok(());
+        }
+        let Some(keys) = self.keys.as_mut().or(self.decoy_slots.as_mut()) else {
+            error!("Cannot revoke a key slot: the vault is locked");
+            return Ok(());
+        };
+
+        let mut app = self.app.lock().await;
+        let decoy = self.decoy.as_ref().map(|decoy| (decoy, &*app));
+        let res = keys.revoke(&label).and_then(|slot| {
+            write_key_slots(keys, &self.config, decoy).inspect_err(|_| keys.restore(slot))
+        });
+        match res {
+            Err(err) => error!("Cannot revoke the key slot: {:?}", err),
+            Ok(_) => {
+                info!("🗑️ Key slot {label} revoked");
+                if self.config.backups > 0 {
+                    info!("💡 The backups made before still open with the passphrase of {label}");
+                }
+            }
+        }
+
+        app.data.set_slots(keys);
+        Ok(())
+    }
+
+    async fn new_recovery_key(&mut self) -> Result<()> {
+        if self.refuse_read_only("generate a recovery key") {
+            return Ok(());
+        }
+        // Along the decoy, the recovery key opens nothing
+        let Some(keys) = self.keys.as_mut().or(self.decoy_slots.as_mut()) else {
+            error!("Cannot generate a recovery key: the vault is locked");
+            return Ok(());
+        };
+
+        let mut app = self.app.lock().await;
+        let decoy = self.decoy.as_ref().map(|decoy| (decoy, &*app));
+        let recovery = RecoveryKey::generate();
+        let previous = keys.slots().iter().find(|slot| slot.is_recovery()).cloned();
+        let res = keys.set_recovery_key(&recovery).and_then(|_| {
+            write_key_slots(keys, &self.config, decoy).inspect_err(|_| {
+                // Never keep a slot that is not on disk
+                match previous.clone() {
+                    Some(slot) => keys.restore(slot),
+                    None => {
+                        let _ = keys.revoke(RECOVERY_SLOT_LABEL);
+                    }
+                }
+            })
+        });
+
+        match res {
+            Err(err) => error!("Cannot generate a recovery key: {:?}", err),
+            Ok(_) => {
+                if previous.is_some() {
+                    info!("♻️ The previous recovery key does not open the vault anymore");
+                }
+                info!("🛟 Recovery key generated, write it down before closing it");
+                app.data.recovery_key = Some(SecretString::from(recovery.to_printable().as_str()));
+            }
+        }
+        app.data.set_slots(keys);
+        Ok(())
+    }
+
+    /// Open the vault with its recovery key, and replace the master key
+    /// nobody remembers
+    async fn unlock_with_recovery_key(
+        &mut self,
+        recovery_key: SecretString,
+        password: SecretString,
+    ) -> Result<()> {
+        if self.refuse_read_only("set a new master key") {
+            return Ok(());
+        }
         let mut app = self.app.lock().await;
-        let clipped = app.clipboard.set_text(key.password());
+
+        let recovery = match RecoveryKey::parse(recovery_key.expose()) {
+            Ok(recovery) => recovery,
+            Err(err) => {
+                error!("{err}");
+                return Ok(());
+            }
+        };
+        let UnlockedVault {
+            plaintext,
+            mut keys,
+            ..
+        } = match unlock_small_file_with_recovery_key(&self.config.vault, &recovery) {
+            Ok(vault) => vault,
+            Err(err) => {
+                error!("Cannot decrypt file: {err}");
+                app.unlock_failed(&err);
+                return Ok(());
+            }
+        };
+        let repository = match load_vault_database(&plaintext, self.config.backend) {
+            Ok(repository) => repository,
+            Err(err) => {
+                error!("Cannot decrypt file: {err}");
+                app.unlock_failed(&err);
+                return Ok(());
+            }
+        };
+        info!("🛟 File decrypted with the recovery key");
+
+        info!("⏳ Wrapping the data key with the new master key");
+        let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+        let res = keys
+            .reset_passphrase(DEFAULT_SLOT_LABEL, password.expose(), &kdf)
+            .and_then(|_| write_key_slots(&keys, &self.config, None));
+        if let Err(err) = res {
+            error!("Cannot set the new master key: {:?}", err);
+            return Ok(());
+        }
+        info!("🔑 New master key set for the key slot {DEFAULT_SLOT_LABEL}");
+
+        app.data.repository = Some(repository);
+        app.data.set_slots(&keys);
+        self.keys = Some(keys);
+        self.keyfile = None;
+
+        info!("💾 Retrieve data");
+        app.data.load_key_list();
+        app.toggle_input_mode();
+        app.initialized();
+        info!("🍾 Application initialized");
+        Ok(())
+    }
+
+    /// Decrypt the vault and its backups in memory with the data key, and
+    /// check the databases they hold
+    async fn verify_vault(&mut self) -> Result<()> {
+        let report = match (&self.keys, &self.decoy) {
+            (Some(keys), _) => {
+                info!("⏳ Verifying the vault and its backups");
+                verify_vault(&self.config.vault, |path| {
+                    decrypt_small_file(path, keys.data_key())
+                })
+            }
+            // The same report, about the decoy in the vault and its backups
+            (None, Some(decoy)) => {
+                info!("⏳ Verifying the vault and its backups");
+                verify_vault(&self.config.vault, |path| decoy.open(path))
+            }
+            (None, None) => {
+                error!("Cannot verify the vault: it is locked");
+                let mut app = self.app.lock().await;
+                if app.state().is_verify_popup() {
+                    app.toggle_verify_popup();
+                }
+                return Ok(());
+            }
+        };
+        if report.has_problems() {
+            error!("{}, see the report", report.summary());
+        } else {
+            info!("{}", report.summary());
+        }
+
+        let mut app = self.app.lock().await;
+        app.data.verify_report = Some(report);
+        Ok(())
+    }
+
+    async fn do_copy(&mut self, id: Uuid) -> Result<()> {
+        let mut guard = self.app.lock().await;
+        let app = &mut *guard;
+
+        let data = &mut app.data;
+        let Some(item) = data.keys.items.iter_mut().find(|item| item.id() == id) else {
+            error!("Cannot copy: the key is not in the vault");
+            return Ok(());
+        };
+        // A vault opened read-only is never touched, not even its usage dates
+        if let (Some(repository), false) = (&mut data.repository, self.config.read_only) {
+            item.update_last_used_at();
+            let updated = repository.update(item);
+            if let Err(err) = updated {
+                error!("Cannot update key: {:?}", err);
+            } else {
+                self.usage_unsaved = true;
+            }
+        }
+
+        let clipped = app.clipboard.set_text(item.password());
         if let Err(err) = clipped {
             error!("Cannot copy to clipboard: {:?}", err);
         } else {
             info!("📝 Copy password to clipboard");
         }
+        Ok(())
+    }
+
+    async fn load_history(&mut self, key_id: Uuid) -> Result<()> {
+        let mut app = self.app.lock().await;
+        let Some(repository) = &app.data.repository else {
+            error!("Cannot show the previous passwords: the vault is not opened");
+            return Ok(());
+        };
+
+        match repository.history(key_id) {
+            Err(err) => error!("Cannot retrieve the previous passwords: {:?}", err),
+            Ok(history) => app.data.history = StatefulList::with_items(history),
+        }
+        Ok(())
+    }
+
+    async fn copy_previous_password(&mut self, id: Uuid) -> Result<()> {
+        let mut guard = self.app.lock().await;
+        let app = &mut *guard;
+
+        let Some(password) = app.data.history.items.iter().find(|item| item.id() == id) else {
+            error!("Cannot copy: the password is not in the history");
+            return Ok(());
+        };
+        match app.clipboard.set_text(password.password()) {
+            Err(err) => error!("Cannot copy to clipboard: {:?}", err),
+            Ok(_) => info!("📝 Copy previous password to clipboard"),
+        }
+        Ok(())
+    }
+
+    /// Give a previous password back to its key, the password it replaces
+    /// goes to the history in turn
+    async fn restore_password(&mut self, id: Uuid) -> Result<()> {
+        if self.refuse_read_only("restore a password") {
+            return Ok(());
+        }
 
+        let mut guard = self.app.lock().await;
+        let app = &mut *guard;
+        let data = &mut app.data;
+        let Some(password) = data.history.items.iter().find(|item| item.id() == id) else {
+            error!("Cannot restore: the password is not in the history");
+            return Ok(());
+        };
+        let Some(key) = data
+            .keys
+            .items
+            .iter_mut()
+            .find(|key| key.id() == password.key_id())
+        else {
+            error!("Cannot restore: the key is not in the vault");
+            return Ok(());
+        };
+        let Some(repository) = &mut data.repository else {
+            error!("Cannot restore: the vault is not opened");
+            return Ok(());
+        };
+
+        let previous = key.clone();
+        key.set_password(password.secret().clone());
+        if let Err(err) = repository.update(key) {
+            error!("Cannot restore the password: {:?}", err);
+            *key = previous;
+            return Ok(());
+        }
+        info!("⏪ Password of {} restored", key.name());
+
+        match repository.history(key.id()) {
+            Err(err) => error!("Cannot retrieve the previous passwords: {:?}", err),
+            Ok(history) => data.history = StatefulList::with_items(history),
+        }
+        self.save_vault(app);
+        Ok(())
+    }
+
+    async fn lock_vault(&mut self) -> Result<()> {
+        // Every change has already been saved, only the decrypted state and
+        // the usage dates are left
+        let mut app = self.app.lock().await;
+        if self.usage_unsaved {
+            self.write_database(&app, false);
+        }
+        app.data.wipe();
+        drop(app);
+
+        self.keys = None;
+        self.keyfile = None;
+        self.decoy = None;
+        self.decoy_slots = None;
+        self.usage_unsaved = false;
+        memory::unlock_all();
+        info!("🔒 Vault locked");
         Ok(())
     }
 
     async fn close_application(&mut self) -> Result<()> {
         info!("🚪 Close the application");
 
-        let key1 = gen_key_from_password(self.password.clone());
-
-        let res = encrypt_small_file("./keys.db", "./keys.db.encrypt", &key1);
-        if let Err(err) = res {
-            error!("Cannot encrypt file: {:?}", err);
-        } else {
-            info!("🔒 File encrypted");
-            std::fs::remove_file("./keys.db")?;
+        let mut app = self.app.lock().await;
+        if app.data.repository.is_some() {
+            if self.usage_unsaved {
+                self.write_database(&app, false);
+            }
+            app.data.repository = None;
         }
+        drop(app);
+
         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         Ok(())
     }
@@ -92,38 +727,376 @@ impl IoAsyncHandler {
 
         app.toggle_input_mode();
 
-        // self.password = app.get_input_buffer().clone().to_string();
-        self.password = <String>::clone(&app.get_input_buffer().to_string());
-
-        //TODO: get the configuration
+        let (password, keyfile) = app.unlock_input();
+        let keyfile = keyfile.or_else(|| self.config.keyfile.clone());
 
-        if std::path::Path::exists(Path::new("./keys.db.encrypt")) {
+        if std::path::Path::exists(Path::new(&self.config.vault)) {
             info!("🔒 File encrypted");
 
-            let key1 = gen_key_from_password(self.password.clone());
+            let opened = match &self.config.identity {
+                Some(identity) => open_vault_with_identity(&self.config, identity),
+                None => open_vault(&self.config, &password, keyfile.as_deref()),
+            };
+            // A password opening no slot may be the duress password
+            let opened = match opened {
+                Err(err)
+                    if err.is_wrong_password() || matches!(err, UnlockError::KeyfileRequired) =>
+                {
+                    match open_decoy_vault(&self.config, &password) {
+                        Some((repository, Decoy { key, slots, .. })) => {
+                            let slots = show_decoy(&mut app, repository, &key, slots, &self.config);
+                            self.keys = None;
+                            self.keyfile = None;
+                            self.decoy = Some(key);
+                            self.decoy_slots = slots;
+                            return Ok(());
+                        }
+                        None => Err(err),
+                    }
+                }
+                opened => opened,
+            };
+            match opened {
+                Err(err) => {
+                    error!("Cannot decrypt file: {err}");
+                    app.unlock_failed(&err);
 
-            let res = decrypt_small_file("./keys.db.encrypt", "./keys.db", &key1);
-            if let Err(err) = res {
-                error!("Cannot decrypt file: {:?}", err);
+                    app.toggle_input_mode();
+                }
+                Ok((repository, mut keys, converted, keyfile)) => {
+                    info!(
+                        "🔓 File decrypted with the key slot {}",
+                        keys.opened().label
+                    );
+                    app.data.repository = Some(repository);
 
-                app.toggle_input_mode();
-            } else {
-                info!("🔓 File decrypted");
-                std::fs::remove_file("./keys.db.encrypt")?;
-                info!("💾 Retrieve data");
-                app.data.load_key_list();
+                    let kdf = keys.opened().kdf().cloned();
+                    let legacy = kdf.as_ref().is_some_and(|kdf| kdf.is_legacy());
+                    if legacy {
+                        info!("🧂 Legacy key derivation, the slot gets its own salt");
+                        let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+                        if let Err(err) = keys.rewrap_opened(password.expose(), None, &kdf) {
+                            error!("Cannot derive the new key: {:?}", err);
+                        }
+                    } else if kdf.is_some_and(|kdf| kdf.algorithm() == KdfAlgorithm::Pbkdf2) {
+                        info!("💡 Run `upgrade-kdf` to move this slot to Argon2id");
+                    }
+
+                    app.data.set_slots(&keys);
+                    self.keys = Some(keys);
+                    self.keyfile = keyfile;
+                    if converted {
+                        info!("📦 Older vault format, its key moves to a key slot");
+                    }
+                    if converted || legacy {
+                        self.save_vault(&app);
+                    }
 
-                app.initialized(); // we could update the app state
-                info!("🍾 Application initialized");
+                    info!("💾 Retrieve data");
+                    app.data.load_key_list();
+
+                    app.initialized(); // we could update the app state
+                    info!("🍾 Application initialized");
+                }
             }
+        } else if Path::new(&self.config.plaintext_path()).exists() {
+            let plaintext = self.config.plaintext_path();
+            error!("A plaintext database is left at {plaintext}, it has to be recovered first");
+            app.recovery(VaultIssue::LeftoverPlaintext, plaintext);
+            return Ok(());
+        } else if self.config.read_only {
+            error!(
+                "There is no vault to open read-only at {}",
+                self.config.vault
+            );
+            app.toggle_input_mode();
         } else {
             info!("🔒 File not encrypted");
-            crate::repository::init_database_schemas("keys.db")?;
+            let kdf = calibrate_kdf(self.config.kdf, self.config.unlock_time);
+            // A new vault is bound to the keyfile given on the command line
+            let keys = read_keyfile(keyfile.as_deref())
+                .map_err(anyhow::Error::from)
+                .and_then(|bound| {
+                    let keys = VaultKeys::create(
+                        DEFAULT_SLOT_LABEL,
+                        password.expose(),
+                        bound.as_ref(),
+                        &kdf,
+                    )?;
+                    Ok((keys, bound))
+                });
+            match keys {
+                Ok((keys, bound)) => {
+                    if let Some(path) = &keyfile {
+                        info!("🗝️ New vault bound to {}", path.display());
+                    }
+                    app.data.set_slots(&keys);
+                    self.keys = Some(keys);
+                    self.keyfile = bound;
+                }
+                Err(err) => {
+                    error!("Cannot derive the key: {:?}", err);
+                    app.toggle_input_mode();
+                    app.clear_input_buffer();
+                    return Ok(());
+                }
+            }
+            app.data.repository = Some(self.config.backend.create()?);
+            self.save_vault(&app);
             app.initialized(); // we could update the app state
             info!("🍾 Application initialized");
+            // Nothing opens the vault without its master key yet
+            app.toggle_recovery_key_popup();
         }
 
         app.clear_input_buffer();
         Ok(())
     }
+
+    /// Encrypt the in-memory database and replace the vault with it, once
+    /// the current one is backed up
+    fn save_vault(&self, app: &App) {
+        self.write_database(app, true);
+    }
+
+    /// Encrypt the in-memory database and replace the vault with it.
+    ///
+    /// Only a change of usage dates is saved without a `backup`: otherwise
+    /// copying a few passwords would rotate every older generation out.
+    fn write_database(&self, app: &App, backup: bool) {
+        if self.config.read_only {
+            return;
+        }
+        if let Some(decoy) = &self.decoy {
+            let slots = self.decoy_slots.as_ref();
+            match write_decoy_vault(app, decoy, slots, &self.config, backup) {
+                Err(err) => error!("Cannot save the vault: {:?}", err),
+                Ok(_) => info!("🔒 Vault saved"),
+            }
+            return;
+        }
+
+        let Some(keys) = &self.keys else {
+            error!("Cannot save the vault: it is locked");
+            return;
+        };
+
+        let res = write_vault(app, keys, &self.config, backup);
+        if let Err(err) = res {
+            error!("Cannot save the vault: {:?}", err);
+        } else {
+            info!("🔒 Vault saved");
+        }
+    }
+
+    /// Log why a change is refused when the vault is opened read-only
+    fn refuse_read_only(&self, change: &str) -> bool {
+        if self.config.read_only {
+            error!("🔏 Cannot {change}: the vault is opened read-only");
+        }
+        self.config.read_only
+    }
+}
+
+/// Show the recovery dialog if the vault is in an inconsistent state, or the
+/// master key prompt otherwise
+fn check_issue(app: &mut App, config: &Config) {
+    let vault = &config.vault;
+    match detect_issue(vault, config.plaintext_path()) {
+        Ok(Some(issue)) => {
+            warn!("⚠️ {issue}");
+            app.recovery(issue, config.plaintext_path());
+            return;
+        }
+        Ok(None) if app.state().recovery_issue().is_some() => app.ask_master_key(),
+        Ok(None) => (),
+        Err(err) => error!("Cannot check the vault: {:?}", err),
+    }
+
+    if let (Some(identity), true) = (&config.identity, Path::new(vault).exists()) {
+        info!(
+            "🪪 Press Enter to open the vault with the identity {}",
+            identity.display()
+        );
+        return;
+    }
+
+    // Without `--keyfile`, the prompt asks for the path of the keyfile
+    if config.keyfile.is_none() && Path::new(vault).exists() {
+        match requires_keyfile(vault) {
+            Ok(true) => {
+                info!("🗝️ The vault may need a keyfile");
+                app.require_keyfile();
+            }
+            Ok(false) => (),
+            Err(err) => error!("Cannot read the vault header: {err}"),
+        }
+    }
+}
+
+/// Read the keyfile at `path`, if any
+fn read_keyfile(path: Option<&Path>) -> Result<Option<Keyfile>, UnlockError> {
+    path.map(Keyfile::read)
+        .transpose()
+        .map_err(UnlockError::Keyfile)
+}
+
+/// The keys of an opened vault, its key slots, whether it has been written in
+/// a format without key slots, and the keyfile the slot opened needs
+type OpenedVault = (Box<dyn KeyRepository>, VaultKeys, bool, Option<Keyfile>);
+
+/// Decrypt the vault in memory with the master key typed by the user, and
+/// the keyfile if the vault is bound to one.
+///
+/// Also tells if the vault has been written in a format without key slots,
+/// and returns the keyfile if the slot opened needs it.
+fn open_vault(
+    config: &Config,
+    password: &SecretString,
+    keyfile: Option<&Path>,
+) -> Result<OpenedVault, UnlockError> {
+    let keyfile = match requires_keyfile(&config.vault)? {
+        true => read_keyfile(keyfile)?,
+        false => None,
+    };
+    let UnlockedVault {
+        plaintext,
+        keys,
+        converted,
+    } = unlock_small_file(&config.vault, password.expose(), keyfile.as_ref())?;
+    let repository = load_vault_database(&plaintext, config.backend)?;
+
+    let keyfile = keyfile.filter(|_| keys.opened().requires_keyfile());
+    Ok((repository, keys, converted, keyfile))
+}
+
+/// Load the database of a vault decrypted with the right key
+fn load_vault_database(
+    plaintext: &[u8],
+    backend: Backend,
+) -> Result<Box<dyn KeyRepository>, UnlockError> {
+    backend
+        .load(plaintext)
+        .map_err(|_| match stored_user_version(plaintext) {
+            Some(version) if version > SCHEMA_VERSION => UnlockError::UnsupportedSchema(version),
+            // Decrypted with the right key, but not a database anymore
+            _ => UnlockError::Corrupted,
+        })
+}
+
+/// Decrypt the vault in memory with the identity of one of its recipients
+fn open_vault_with_identity(config: &Config, identity: &Path) -> Result<OpenedVault, UnlockError> {
+    let identity = Identity::read(identity).map_err(UnlockError::Identity)?;
+    let UnlockedVault {
+        plaintext, keys, ..
+    } = unlock_small_file_with_identity(&config.vault, &identity)?;
+    let repository = load_vault_database(&plaintext, config.backend)?;
+
+    Ok((repository, keys, false, None))
+}
+
+/// Show the decoy as if it were the vault, and destroy the slots of the real
+/// one if the decoy asks for it.
+///
+/// Returns the `slots` sealed with the decoy, or else the ones of the real
+/// vault, to be changed as if they were the ones of the decoy.
+fn show_decoy(
+    app: &mut App,
+    repository: Box<dyn KeyRepository>,
+    decoy: &DuressKey,
+    slots: Option<Vec<KeySlot>>,
+    config: &Config,
+) -> Option<VaultKeys> {
+    // Nothing may tell the real vault is gone, not even the logs
+    if decoy.destroys_slots() && !config.read_only {
+        let _ = destroy_key_slots(&config.vault);
+    }
+
+    info!("🔓 File decrypted with the key slot {DEFAULT_SLOT_LABEL}");
+    app.data.repository = Some(repository);
+    let slots = slots
+        .or_else(|| read_key_slots(&config.vault).ok())
+        .and_then(|slots| VaultKeys::unopened(slots, DEFAULT_SLOT_LABEL));
+    if let Some(slots) = &slots {
+        app.data.set_slots(slots);
+    }
+
+    info!("💾 Retrieve data");
+    app.data.load_key_list();
+    app.initialized();
+    info!("🍾 Application initialized");
+    app.clear_input_buffer();
+    slots
+}
+
+/// Decrypt the decoy in memory, if `password` is the duress password
+fn open_decoy_vault(
+    config: &Config,
+    password: &SecretString,
+) -> Option<(Box<dyn KeyRepository>, Decoy)> {
+    let decoy = open_decoy(&config.vault, password.expose()).ok()??;
+    let repository = config.backend.load(&decoy.plaintext).ok()?;
+    Some((repository, decoy))
+}
+
+/// Seal the in-memory database in the decoy area of the vault, along with
+/// the slots shown with it
+fn write_decoy_vault(
+    app: &App,
+    decoy: &DuressKey,
+    slots: Option<&VaultKeys>,
+    config: &Config,
+    backup: bool,
+) -> Result<(), anyhow::Error> {
+    let repository = app
+        .data
+        .repository
+        .as_ref()
+        .ok_or(anyhow!("The vault is not opened"))?;
+    let plaintext = repository.dump()?;
+    if backup {
+        backup_vault(&config.vault, config.backups)?;
+    }
+    write_decoy(
+        &config.vault,
+        &plaintext,
+        slots.map(VaultKeys::slots),
+        decoy,
+    )
+}
+
+/// Replace the slots of the vault, its body is left as it is.
+///
+/// The slots shown along the `decoy` open nothing: they are sealed with it,
+/// backed up first the same way.
+fn write_key_slots(
+    keys: &VaultKeys,
+    config: &Config,
+    decoy: Option<(&DuressKey, &App)>,
+) -> Result<(), anyhow::Error> {
+    if let Some((decoy, app)) = decoy {
+        return write_decoy_vault(app, decoy, Some(keys), config, true);
+    }
+    backup_vault(&config.vault, config.backups)?;
+    update_key_slots(&config.vault, keys)
+}
+
+/// Encrypt the in-memory database over the vault, backed up first unless
+/// told otherwise
+fn write_vault(
+    app: &App,
+    keys: &VaultKeys,
+    config: &Config,
+    backup: bool,
+) -> Result<(), anyhow::Error> {
+    let repository = app
+        .data
+        .repository
+        .as_ref()
+        .ok_or(anyhow!("The vault is not opened"))?;
+    let plaintext = repository.dump()?;
+    if backup {
+        backup_vault(&config.vault, config.backups)?;
+    }
+    encrypt_small_file(&plaintext, &config.vault, keys)
 }
@@ -1,15 +1,73 @@
 // use std::time::Duration;
 
+use uuid::Uuid;
+
+use crate::crypto::secret::SecretString;
 use crate::models::key::Key;
 
 pub mod handler;
+
+/// Ways out of an inconsistent vault found at startup
+#[derive(Debug, Clone)]
+pub enum Recovery {
+    /// Encrypt the plaintext database with the original master key
+    ReEncrypt(SecretString),
+    /// Put the latest backup back in place of the vault
+    OpenBackup,
+    /// Move the plaintext database aside
+    Quarantine,
+}
+
+/// What becomes of the password of a key being edited
+#[derive(Debug, Clone)]
+pub enum PasswordChange {
+    Keep,
+    /// Replace it with a password typed by the user
+    Set(SecretString),
+    /// Replace it with a new random password
+    Generate,
+}
+
+/// The fields of a key, as edited in its form
+#[derive(Debug, Clone)]
+pub struct KeyEdit {
+    pub id: Uuid,
+    pub name: String,
+    pub username: String,
+    pub urls: Vec<String>,
+    pub notes: String,
+    pub password: PasswordChange,
+}
+
 // For this dummy application we only need two IO event
 #[derive(Debug, Clone)]
 pub enum IoEvent {
+    CheckVault, // Look for what a crash could have left behind
     Initialize, // Launch to initialize the application
     // Sleep(Duration), // Just take a little break
-    Copy(Key), // Copy the key
+    Copy(Uuid), // Copy the password of a key
     RegisterKey(Key),
+    EditKey(KeyEdit), // Save the changes made to a key
+    ChangeMasterKey {
+        current: SecretString,
+        new: SecretString,
+    },
+    Recover(Recovery),
+    AddSlot {
+        label: String,
+        password: SecretString,
+    },
+    RevokeSlot(String), // Remove the key slot with this label
+    NewRecoveryKey,     // Wrap the data key to a new recovery key
+    UnlockWithRecoveryKey {
+        recovery_key: SecretString,
+        password: SecretString,
+    },
+    VerifyVault, // Check the vault and its backups decrypt and hold a healthy database
+    PasswordHistory(Uuid), // Load the previous passwords of a key
+    CopyPreviousPassword(Uuid), // Copy a password of the history
+    RestorePassword(Uuid), // Give a password of the history back to its key
     Refresh,
+    Lock, // Forget the decrypted vault until the master key is typed again
     Close,
 }
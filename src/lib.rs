@@ -10,13 +10,17 @@ use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
 
 use crate::app::ui;
+use crate::io::IoEvent;
 
 pub mod app;
+pub mod cli;
+pub mod config;
 pub mod crypto;
 pub mod inputs;
 pub mod io;
 pub mod models;
 pub mod repository;
+pub mod storage;
 
 pub async fn start_ui(app: &Arc<tokio::sync::Mutex<App>>) -> Result<()> {
     // Configure Crossterm backend for tui
@@ -40,6 +44,7 @@ pub async fn start_ui(app: &Arc<tokio::sync::Mutex<App>>) -> Result<()> {
     {
         let mut app = app.lock().await;
         app.toggle_input_mode();
+        app.dispatch(IoEvent::CheckVault).await;
     }
 
     loop {
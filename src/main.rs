@@ -1,27 +1,60 @@
 use std::sync::Arc;
 
-use eyre::Result;
-use log::LevelFilter;
+use clap::Parser;
+use eyre::{eyre, Result};
+use log::{warn, LevelFilter};
 use rust_password_manager::app::App;
+use rust_password_manager::cli::{self, Cli};
+use rust_password_manager::crypto::memory::disable_core_dumps;
 use rust_password_manager::io::handler::IoAsyncHandler;
 use rust_password_manager::io::IoEvent;
 use rust_password_manager::start_ui;
+use rust_password_manager::storage::lock::VaultLock;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = cli.config();
+
+    // Before any secret is in memory
+    let dumpable = disable_core_dumps().err();
+
+    // Held until the end, so a second instance cannot overwrite our changes
+    let _lock = if config.read_only {
+        None
+    } else {
+        let lock = VaultLock::acquire(&config.vault).map_err(|err| {
+            eyre!("{err}\n💡 Run with `--read-only` to open the vault without modifying it")
+        })?;
+        Some(lock)
+    };
+
+    if let Some(command) = cli.command {
+        if let Some(err) = dumpable {
+            eprintln!("⚠️ Cannot keep the process out of core dumps: {err}");
+        }
+        return cli::run(command, &config).map_err(|err| eyre!(err));
+    }
+
     let (sync_io_tx, mut sync_io_rx) = tokio::sync::mpsc::channel::<IoEvent>(100);
 
     // We need to share the App between thread
-    let app = Arc::new(tokio::sync::Mutex::new(App::new(sync_io_tx.clone())));
+    let app = Arc::new(tokio::sync::Mutex::new(App::new(
+        sync_io_tx.clone(),
+        config.lock_timeout,
+    )));
     let app_ui = Arc::clone(&app);
 
     // Configure log
     tui_logger::init_logger(LevelFilter::Debug).unwrap();
     tui_logger::set_default_level(log::LevelFilter::Debug);
+    if let Some(err) = dumpable {
+        warn!("⚠️ Cannot keep the process out of core dumps: {err}");
+    }
 
     // Handle IO in a specifc thread
     tokio::spawn(async move {
-        let mut handler = IoAsyncHandler::new(app);
+        let mut handler = IoAsyncHandler::new(app, config);
         while let Some(io_event) = sync_io_rx.recv().await {
             handler.handle_io_event(io_event).await;
         }
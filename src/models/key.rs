@@ -1,12 +1,19 @@
 use chrono::prelude::*;
 use passwords::PasswordGenerator;
+use rusqlite::{Connection, OptionalExtension, Row};
 use uuid::Uuid;
 
+use super::password::Password;
+use crate::crypto::secret::SecretString;
+
 #[derive(Debug, Clone)]
 pub struct Key {
     id: Uuid,
     name: String,
-    password: String,
+    username: String,
+    urls: Vec<String>,
+    notes: String,
+    password: SecretString,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
     last_used_at: DateTime<Utc>,
@@ -26,11 +33,14 @@ impl Key {
             .spaces(false)
             .exclude_similar_characters(true)
             .strict(true);
-        let password = pg.generate_one().unwrap();
+        let password = SecretString::new(pg.generate_one().unwrap());
 
         Self {
             id,
             name,
+            username: String::new(),
+            urls: Vec::new(),
+            notes: String::new(),
             password,
             created_at: now,
             updated_at: now,
@@ -39,24 +49,46 @@ impl Key {
         }
     }
 
-    pub fn from_db(
-        id: Uuid,
-        name: String,
-        password: String,
-        created_at: DateTime<Utc>,
-        updated_at: DateTime<Utc>,
-        last_used_at: DateTime<Utc>,
-        last_changed_at: DateTime<Utc>,
-    ) -> Self {
-        Self {
-            id,
-            name,
-            password,
-            created_at,
-            updated_at,
-            last_used_at,
-            last_changed_at,
-        }
+    /// Read a key from a row of the keys table
+    /// ```
+    /// use rust_password_manager::models::key::Key;
+    /// use rust_password_manager::repository::init_database_schemas;
+    ///
+    /// let co = init_database_schemas().unwrap();
+    /// let mut key = Key::new(None, "forge".to_string());
+    /// key.set_username("alice".to_string());
+    /// key.set_urls(vec![
+    ///     "https://git.example.com".to_string(),
+    ///     " ".to_string(),
+    ///     "https://ci.example.com\nhttps://docs.example.com\t".to_string(),
+    /// ]);
+    /// key.persist(&co).unwrap();
+    /// key.set_notes("Second factor on the phone".to_string());
+    /// key.update_in_database(&co).unwrap();
+    ///
+    /// let keys = Key::retrive_keys_from_db(&co).unwrap();
+    /// assert_eq!(keys[0].username(), "alice");
+    /// assert_eq!(keys[0].urls(), key.urls());
+    /// assert_eq!(
+    ///     keys[0].urls(),
+    ///     ["https://git.example.com", "https://ci.example.com", "https://docs.example.com"]
+    /// );
+    /// assert_eq!(keys[0].notes(), "Second factor on the phone");
+    /// ```
+    pub fn from_db(row: &Row) -> rusqlite::Result<Self> {
+        let urls: String = row.get("urls")?;
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            username: row.get("username")?,
+            urls: urls.lines().map(String::from).collect(),
+            notes: row.get("notes")?,
+            password: row.get("password")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
+            last_used_at: row.get("last_used_at")?,
+            last_changed_at: row.get("last_changed_at")?,
+        })
     }
 
     pub fn id(&self) -> Uuid {
@@ -67,8 +99,21 @@ impl Key {
         &self.name
     }
 
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The addresses the key is used on, the first one being the main one
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    pub fn notes(&self) -> &str {
+        &self.notes
+    }
+
     pub fn password(&self) -> &str {
-        &self.password
+        self.password.expose()
     }
 
     pub fn created_at(&self) -> String {
@@ -89,7 +134,6 @@ impl Key {
     }
 
     pub fn update_password(&mut self) {
-        let now = Utc::now();
         let pg = PasswordGenerator::new()
             .length(32)
             .numbers(true)
@@ -99,61 +143,125 @@ impl Key {
             .spaces(false)
             .exclude_similar_characters(true)
             .strict(true);
-        self.password = pg.generate_one().unwrap();
+        self.set_password(SecretString::new(pg.generate_one().unwrap()));
+    }
+
+    /// Replace the password, the previous one is kept in the history once
+    /// saved
+    /// ```
+    /// use rust_password_manager::crypto::secret::SecretString;
+    /// use rust_password_manager::models::key::Key;
+    ///
+    /// let mut key = Key::new(None, "forge".to_string());
+    /// let changed = key.last_changed_at();
+    /// key.set_name("git".to_string());
+    /// assert_eq!(key.last_changed_at(), changed);
+    ///
+    /// key.set_password(SecretString::from("typed by hand"));
+    /// assert_eq!(key.password(), "typed by hand");
+    /// assert_ne!(key.last_changed_at(), changed);
+    /// assert_eq!(key.updated_at(), key.last_changed_at());
+    /// ```
+    pub fn set_password(&mut self, password: SecretString) {
+        let now = Utc::now();
+        self.password = password;
         self.updated_at = now;
         self.last_changed_at = now;
     }
 
+    /// The current password, as kept in the history once replaced
+    pub fn retired_password(&self) -> Password {
+        Password::new(
+            self.id,
+            self.password.clone(),
+            self.last_changed_at,
+            self.last_used_at,
+        )
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+        self.updated_at = Utc::now();
+    }
+
+    pub fn set_username(&mut self, username: String) {
+        self.username = username;
+        self.updated_at = Utc::now();
+    }
+
+    /// Replace the addresses. An address holds no whitespace: an entry
+    /// holding some is split, and the blank ones are dropped.
+    pub fn set_urls(&mut self, urls: Vec<String>) {
+        self.urls = urls
+            .iter()
+            .flat_map(|url| url.split_whitespace())
+            .map(String::from)
+            .collect();
+        self.updated_at = Utc::now();
+    }
+
+    pub fn set_notes(&mut self, notes: String) {
+        self.notes = notes;
+        self.updated_at = Utc::now();
+    }
+
     pub fn update_last_used_at(&mut self) {
         let now = Utc::now();
         self.last_used_at = now;
     }
 
-    pub fn update_in_database(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = rusqlite::Connection::open("keys.db")?;
-        conn.execute(
-            "UPDATE keys SET name = ?2, password = ?3, updated_at = ?4, last_used_at = ?5, last_changed_at = ?6 WHERE id = ?1",
+    /// Save the changes made to the key, the password it replaces goes to
+    /// its history
+    pub fn update_in_database(&self, conn: &Connection) -> rusqlite::Result<()> {
+        let tx = conn.unchecked_transaction()?;
+        let previous = tx
+            .query_row(
+                "SELECT * FROM keys WHERE id = ?1",
+                rusqlite::params![self.id],
+                Key::from_db,
+            )
+            .optional()?;
+        if let Some(previous) = previous.filter(|previous| previous.password() != self.password()) {
+            previous.retired_password().persist(&tx)?;
+        }
+
+        tx.execute(
+            "UPDATE keys SET name = ?2, password = ?3, updated_at = ?4, last_used_at = ?5, last_changed_at = ?6, username = ?7, urls = ?8, notes = ?9 WHERE id = ?1",
             rusqlite::params![
                 self.id,
                 self.name,
                 self.password,
                 self.updated_at,
                 self.last_used_at,
-                self.last_changed_at
+                self.last_changed_at,
+                self.username,
+                self.urls.join("\n"),
+                self.notes
             ],
         )?;
-        Ok(())
+        tx.commit()
     }
 
-    pub fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = rusqlite::Connection::open("keys.db")?;
+    pub fn persist(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
-            "INSERT INTO keys (id, name, password, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            "INSERT INTO keys (id, name, password, created_at, updated_at, username, urls, notes) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             rusqlite::params![
                 self.id,
                 self.name,
                 self.password,
                 self.created_at,
-                self.updated_at
+                self.updated_at,
+                self.username,
+                self.urls.join("\n"),
+                self.notes
             ],
         )?;
         Ok(())
     }
 
-    pub fn retrive_keys_from_db() -> Result<Vec<Self>, Box<dyn std::error::Error>> {
-        let conn = rusqlite::Connection::open("keys.db")?;
-        let mut stmt = conn.prepare("SELECT id, name, password, created_at, updated_at, last_used_at, last_changed_at FROM keys" ).unwrap();
-        let rows = stmt.query_map(rusqlite::params![], |row| {
-            Ok(Key::from_db(
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-                row.get(4)?,
-                row.get(5)?,
-                row.get(6)?,
-            ))
-        })?;
+    pub fn retrive_keys_from_db(conn: &Connection) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = conn.prepare("SELECT id, name, username, urls, notes, password, created_at, updated_at, last_used_at, last_changed_at FROM keys" )?;
+        let rows = stmt.query_map(rusqlite::params![], Key::from_db)?;
 
         let mut keys = Vec::new();
         for key in rows {
@@ -162,11 +270,15 @@ impl Key {
         Ok(keys)
     }
 
+    /// The fields as they are displayed, the password is never part of them
     pub fn to_vec(&self) -> Vec<String> {
         vec![
             self.id.to_string(),
             self.name.clone(),
-            self.password.clone(),
+            self.username.clone(),
+            self.urls.join("\n"),
+            "********".to_string(),
+            self.notes.clone(),
             self.created_at(),
             self.updated_at(),
             self.last_used_at(),
@@ -1,45 +1,67 @@
 use std::fmt::{Display, Formatter};
 
 use chrono::prelude::*;
-use passwords::PasswordGenerator;
+use rusqlite::{Connection, Row};
 use uuid::Uuid;
 
+use crate::crypto::secret::SecretString;
+
+/// A password a key used to have, kept in its history when it is replaced
 #[derive(Debug, Clone)]
 pub struct Password {
     id: Uuid,
-    password: String,
+    /// The key it belonged to
+    key_id: Uuid,
+    password: SecretString,
+    /// When the key started using it
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
+    /// When the key was last used with it
     last_used_at: DateTime<Utc>,
+    /// When it was replaced
     last_changed_at: DateTime<Utc>,
 }
 
 impl Password {
-    pub fn new() -> Self {
+    /// The password `key_id` has used since `created_at`, replaced now
+    pub fn new(
+        key_id: Uuid,
+        password: SecretString,
+        created_at: DateTime<Utc>,
+        last_used_at: DateTime<Utc>,
+    ) -> Self {
         let now = Utc::now();
-        let id = Uuid::new_v4();
-        let pg = PasswordGenerator::new()
-            .length(32)
-            .numbers(true)
-            .lowercase_letters(true)
-            .uppercase_letters(true)
-            .symbols(true)
-            .spaces(false)
-            .exclude_similar_characters(true)
-            .strict(true);
-        let password = pg.generate_one().unwrap();
 
         Self {
-            id,
+            id: Uuid::new_v4(),
+            key_id,
             password,
-            created_at: now,
+            created_at,
             updated_at: now,
-            last_used_at: now,
+            last_used_at,
             last_changed_at: now,
         }
     }
 
+    /// Read a previous password from a row of the passwords table
+    pub fn from_db(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            key_id: row.get("key_id")?,
+            password: row.get("password")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
+            last_used_at: row.get("last_used_at")?,
+            last_changed_at: row.get("last_changed_at")?,
+        })
+    }
+
     pub fn password(&self) -> &str {
+        self.password.expose()
+    }
+
+    /// The password itself, to give it back to its key
+    pub fn secret(&self) -> &SecretString {
         &self.password
     }
 
@@ -63,49 +85,70 @@ impl Password {
         self.id
     }
 
-    pub fn persist(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = rusqlite::Connection::open("keys.db")?;
+    pub fn key_id(&self) -> Uuid {
+        self.key_id
+    }
 
+    pub fn persist(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
-            "INSERT INTO passwords (id, password, created_at, updated_at, last_used_at, last_changed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            rusqlite::params![self.id, self.password, self.created_at, self.updated_at, self.last_used_at, self.last_changed_at],
+            "INSERT INTO passwords (id, key_id, password, created_at, updated_at, last_used_at, last_changed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![self.id, self.key_id, self.password, self.created_at, self.updated_at, self.last_used_at, self.last_changed_at],
         )?;
 
         Ok(())
     }
 
-    pub fn get_password_by_id(id: Uuid) -> Result<Self, Box<dyn std::error::Error>> {
-        let conn = rusqlite::Connection::open("keys.db")?;
-
-        let mut stmt = conn.prepare("SELECT * FROM passwords WHERE id = ?1")?;
-        let rows = stmt.query_map(rusqlite::params![id], |rows| {
-            Ok(Self {
-                id: rows.get(0)?,
-                password: rows.get(1)?,
-                created_at: rows.get(2)?,
-                updated_at: rows.get(3)?,
-                last_used_at: rows.get(4)?,
-                last_changed_at: rows.get(5)?,
-            })
-        })?;
+    pub fn get_password_by_id(conn: &Connection, id: Uuid) -> rusqlite::Result<Self> {
+        conn.query_row(
+            "SELECT * FROM passwords WHERE id = ?1",
+            rusqlite::params![id],
+            Password::from_db,
+        )
+    }
 
-        let mut passwords = Vec::new();
+    /// The previous passwords of the key `key_id`, the most recently replaced
+    /// first
+    /// ```
+    /// use rust_password_manager::models::key::Key;
+    /// use rust_password_manager::models::password::Password;
+    /// use rust_password_manager::repository::init_database_schemas;
+    ///
+    /// let co = init_database_schemas().unwrap();
+    /// let mut key = Key::new(None, "forge".to_string());
+    /// key.persist(&co).unwrap();
+    /// let first = key.password().to_string();
+    ///
+    /// key.update_password();
+    /// key.update_in_database(&co).unwrap();
+    /// let second = key.password().to_string();
+    /// key.update_password();
+    /// key.update_in_database(&co).unwrap();
+    ///
+    /// let history = Password::retrive_history_from_db(&co, key.id()).unwrap();
+    /// let passwords = history.iter().map(|password| password.password());
+    /// assert!(passwords.eq([second.as_str(), first.as_str()]));
+    ///
+    /// // Only a change of password is kept
+    /// key.set_notes("Rotated every year".to_string());
+    /// key.update_in_database(&co).unwrap();
+    /// assert_eq!(Password::retrive_history_from_db(&co, key.id()).unwrap().len(), 2);
+    /// ```
+    pub fn retrive_history_from_db(conn: &Connection, key_id: Uuid) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT * FROM passwords WHERE key_id = ?1 ORDER BY last_changed_at DESC, rowid DESC",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![key_id], Password::from_db)?;
 
-        for row in rows {
-            passwords.push(row?);
+        let mut passwords = Vec::new();
+        for password in rows {
+            passwords.push(password?);
         }
-        Ok(passwords[0].clone())
+        Ok(passwords)
     }
 }
 
 impl Display for Password {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.password)
-    }
-}
-
-impl Default for Password {
-    fn default() -> Self {
-        Self::new()
+        write!(f, "{}", self.password.expose())
     }
 }
@@ -0,0 +1,137 @@
+//! The allocator SQLite keeps the decrypted entries with, in its page cache
+//! among others.
+//!
+//! Every block is locked in memory like the other secrets, and wiped when
+//! SQLite frees it.
+
+use std::alloc::{alloc, dealloc, Layout};
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Once;
+
+use log::warn;
+use rusqlite::ffi;
+use zeroize::Zeroize;
+
+use crate::crypto::memory;
+
+/// Holds the size of the block, and keeps it aligned on 8 bytes at least as
+/// SQLite requires
+const HEADER_LEN: usize = 16;
+
+/// Make SQLite allocate through this module, which has to happen before it
+/// starts. It is too late once a connection has been opened without it: the
+/// limitation is logged then.
+pub(super) fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        // SQLite keeps its own copy of the methods
+        let methods = ffi::sqlite3_mem_methods {
+            xMalloc: Some(malloc),
+            xFree: Some(free),
+            xRealloc: Some(realloc),
+            xSize: Some(size),
+            xRoundup: Some(roundup),
+            xInit: Some(init),
+            xShutdown: Some(shutdown),
+            pAppData: ptr::null_mut(),
+        };
+        let res = unsafe {
+            ffi::sqlite3_config(
+                ffi::SQLITE_CONFIG_MALLOC,
+                &methods as *const ffi::sqlite3_mem_methods,
+            )
+        };
+        if res != ffi::SQLITE_OK {
+            warn!(
+                "⚠️ SQLite started before its memory could be locked: the entries it caches \
+                 may be swapped to disk, and are not wiped once freed"
+            );
+        }
+    });
+}
+
+fn layout(len: usize) -> Option<Layout> {
+    Layout::from_size_align(HEADER_LEN.checked_add(len)?, HEADER_LEN).ok()
+}
+
+unsafe extern "C" fn malloc(len: c_int) -> *mut c_void {
+    let Some(layout) = usize::try_from(len).ok().and_then(layout) else {
+        return ptr::null_mut();
+    };
+    let base = alloc(layout);
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    base.cast::<usize>().write(len as usize);
+    let block = base.add(HEADER_LEN);
+    memory::lock(slice::from_raw_parts(block, len as usize));
+    block.cast()
+}
+
+unsafe extern "C" fn free(block: *mut c_void) {
+    if block.is_null() {
+        return;
+    }
+    let base = block.cast::<u8>().sub(HEADER_LEN);
+    let len = base.cast::<usize>().read();
+    slice::from_raw_parts_mut(block.cast::<u8>(), len).zeroize();
+    if let Some(layout) = layout(len) {
+        dealloc(base, layout);
+    }
+}
+
+/// Always moves the block, so the previous one is wiped
+unsafe extern "C" fn realloc(block: *mut c_void, len: c_int) -> *mut c_void {
+    let moved = malloc(len);
+    if moved.is_null() {
+        return ptr::null_mut();
+    }
+    if !block.is_null() {
+        let kept = (size(block) as usize).min(len as usize);
+        ptr::copy_nonoverlapping(block.cast::<u8>(), moved.cast::<u8>(), kept);
+        free(block);
+    }
+    moved
+}
+
+unsafe extern "C" fn size(block: *mut c_void) -> c_int {
+    if block.is_null() {
+        return 0;
+    }
+    block.cast::<u8>().sub(HEADER_LEN).cast::<usize>().read() as c_int
+}
+
+unsafe extern "C" fn roundup(len: c_int) -> c_int {
+    (len + 7) & !7
+}
+
+unsafe extern "C" fn init(_: *mut c_void) -> c_int {
+    ffi::SQLITE_OK
+}
+
+unsafe extern "C" fn shutdown(_: *mut c_void) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_keep_the_content_of_a_block_when_it_grows_or_shrinks() {
+        unsafe {
+            let block = malloc(10).cast::<u8>();
+            ptr::copy_nonoverlapping(b"0123456789".as_ptr(), block, 10);
+            assert_eq!(size(block.cast()), 10);
+
+            let grown = realloc(block.cast(), 1000).cast::<u8>();
+            assert_eq!(size(grown.cast()), 1000);
+            assert_eq!(slice::from_raw_parts(grown, 10), b"0123456789");
+
+            let shrunk = realloc(grown.cast(), 4).cast::<u8>();
+            assert_eq!(size(shrunk.cast()), 4);
+            assert_eq!(slice::from_raw_parts(shrunk, 4), b"0123");
+            free(shrunk.cast());
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use rusqlite::{ffi, Connection, Error, Result};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::{dump_database, init_database_schemas, load_database};
+use crate::models::key::Key;
+use crate::models::password::Password;
+
+/// Where the keys of an opened vault are kept.
+///
+/// Whatever the backend, a vault is always encrypted as a SQLite database,
+/// so the backends can open each other's vaults.
+pub trait KeyRepository: Send {
+    /// Every key, in the order they have been added
+    fn keys(&self) -> Result<Vec<Key>>;
+
+    /// Add a new key
+    fn insert(&mut self, key: &Key) -> Result<()>;
+
+    /// Save the changes made to a key already added. The password it
+    /// replaces, if any, goes to its history.
+    fn update(&mut self, key: &Key) -> Result<()>;
+
+    /// The previous passwords of a key, the most recently replaced first
+    fn history(&self, key_id: Uuid) -> Result<Vec<Password>>;
+
+    /// Serialize every key, ready to be encrypted.
+    ///
+    /// The copy is wiped from memory once dropped.
+    fn dump(&self) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+/// Kind of [`KeyRepository`] the decrypted vaults are loaded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A SQLite database in memory, with one connection for the session
+    #[default]
+    Sqlite,
+    /// A plain list of keys, for tests
+    Memory,
+}
+
+impl Backend {
+    /// An empty repository, for a new vault
+    pub fn create(self) -> Result<Box<dyn KeyRepository>> {
+        match self {
+            Self::Sqlite => Ok(Box::new(SqliteKeyRepository::new()?)),
+            Self::Memory => Ok(Box::<MemoryKeyRepository>::default()),
+        }
+    }
+
+    /// The repository of a decrypted vault, upgraded to the current schema
+    /// ```
+    /// use rust_password_manager::models::key::Key;
+    /// use rust_password_manager::repository::Backend;
+    ///
+    /// let mut sqlite = Backend::Sqlite.create().unwrap();
+    /// sqlite.insert(&Key::new(None, "forge".to_string())).unwrap();
+    ///
+    /// // Each backend opens what the other one wrote
+    /// let mut memory = Backend::Memory.load(&sqlite.dump().unwrap()).unwrap();
+    /// memory.insert(&Key::new(None, "mail".to_string())).unwrap();
+    /// let sqlite = Backend::Sqlite.load(&memory.dump().unwrap()).unwrap();
+    ///
+    /// let names = sqlite.keys().unwrap().into_iter().map(|key| key.name().to_string());
+    /// assert!(names.eq(["forge", "mail"]));
+    /// ```
+    pub fn load(self, bytes: &[u8]) -> Result<Box<dyn KeyRepository>> {
+        match self {
+            Self::Sqlite => Ok(Box::new(SqliteKeyRepository::load(bytes)?)),
+            Self::Memory => Ok(Box::new(MemoryKeyRepository::load(bytes)?)),
+        }
+    }
+}
+
+/// Keys kept in a SQLite database living only in memory
+pub struct SqliteKeyRepository {
+    co: Connection,
+}
+
+impl SqliteKeyRepository {
+    /// An empty database, at the current schema
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            co: init_database_schemas()?,
+        })
+    }
+
+    /// Load a serialized database, without touching the filesystem
+    pub fn load(bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            co: load_database(bytes)?,
+        })
+    }
+}
+
+impl KeyRepository for SqliteKeyRepository {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Key::retrive_keys_from_db(&self.co)
+    }
+
+    fn insert(&mut self, key: &Key) -> Result<()> {
+        key.persist(&self.co)
+    }
+
+    fn update(&mut self, key: &Key) -> Result<()> {
+        key.update_in_database(&self.co)
+    }
+
+    fn history(&self, key_id: Uuid) -> Result<Vec<Password>> {
+        Password::retrive_history_from_db(&self.co, key_id)
+    }
+
+    fn dump(&self) -> Result<Zeroizing<Vec<u8>>> {
+        dump_database(&self.co)
+    }
+}
+
+/// Keys kept in a list, only turned into a database when dumped
+#[derive(Default)]
+pub struct MemoryKeyRepository {
+    keys: Vec<Key>,
+    /// The previous passwords of every key, the most recently replaced first
+    history: Vec<Password>,
+}
+
+impl MemoryKeyRepository {
+    /// Read the keys of a serialized database, and their history
+    pub fn load(bytes: &[u8]) -> Result<Self> {
+        let repository = SqliteKeyRepository::load(bytes)?;
+        let keys = repository.keys()?;
+        let mut history = Vec::new();
+        for key in &keys {
+            history.extend(repository.history(key.id())?);
+        }
+        Ok(Self { keys, history })
+    }
+}
+
+impl KeyRepository for MemoryKeyRepository {
+    fn keys(&self) -> Result<Vec<Key>> {
+        Ok(self.keys.clone())
+    }
+
+    fn insert(&mut self, key: &Key) -> Result<()> {
+        // The same as the primary key of the database
+        if self.keys.iter().any(|other| other.id() == key.id()) {
+            return Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CONSTRAINT_PRIMARYKEY),
+                Some("UNIQUE constraint failed: keys.id".to_string()),
+            ));
+        }
+        self.keys.push(key.clone());
+        Ok(())
+    }
+
+    fn update(&mut self, key: &Key) -> Result<()> {
+        if let Some(other) = self.keys.iter_mut().find(|other| other.id() == key.id()) {
+            if other.password() != key.password() {
+                self.history.insert(0, other.retired_password());
+            }
+            *other = key.clone();
+        }
+        Ok(())
+    }
+
+    fn history(&self, key_id: Uuid) -> Result<Vec<Password>> {
+        Ok(self
+            .history
+            .iter()
+            .filter(|password| password.key_id() == key_id)
+            .cloned()
+            .collect())
+    }
+
+    fn dump(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let mut repository = SqliteKeyRepository::new()?;
+        for key in &self.keys {
+            repository.insert(key)?;
+        }
+        // The oldest first, the way they have been replaced
+        for password in self.history.iter().rev() {
+            password.persist(&repository.co)?;
+        }
+        repository.dump()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same steps give the same keys, whatever the backend
+    fn keys_after_a_session(backend: Backend) -> Vec<Vec<String>> {
+        let mut repository = backend.create().unwrap();
+        let mut forge = Key::new(None, "forge".to_string());
+        let mail = Key::new(None, "mail".to_string());
+        repository.insert(&forge).unwrap();
+        repository.insert(&mail).unwrap();
+        assert!(repository.insert(&mail).is_err(), "an id is never reused");
+
+        let first = forge.password().to_string();
+        forge.set_username("alice".to_string());
+        repository.update(&forge).unwrap();
+        forge.update_password();
+        repository.update(&forge).unwrap();
+        // Unknown keys are left alone
+        repository
+            .update(&Key::new(None, "unknown".to_string()))
+            .unwrap();
+
+        let repository = backend.load(&repository.dump().unwrap()).unwrap();
+        let keys = repository.keys().unwrap();
+        assert_eq!(keys[0].password(), forge.password());
+        // Only the password replaced is kept, and survives a reload
+        let history = repository.history(forge.id()).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].password(), first);
+        assert!(repository.history(mail.id()).unwrap().is_empty());
+        keys.iter().map(Key::to_vec).collect()
+    }
+
+    #[test]
+    fn should_behave_the_same_in_every_backend() {
+        let sqlite = keys_after_a_session(Backend::Sqlite);
+        let memory = keys_after_a_session(Backend::Memory);
+
+        assert_eq!(sqlite.len(), 2);
+        assert_eq!(sqlite[0][1..3], ["forge", "alice"]);
+        assert_eq!(sqlite[1][1], "mail");
+        // Only the random ids and the dates differ
+        let fields = |keys: &[Vec<String>]| -> Vec<Vec<String>> {
+            keys.iter().map(|key| key[1..6].to_vec()).collect()
+        };
+        assert_eq!(fields(&sqlite), fields(&memory));
+    }
+}
@@ -0,0 +1,268 @@
+use rusqlite::{ffi, Connection, Error, Result};
+
+/// Schema written before its version was recorded, `user_version` 0. Every
+/// database starts from it, so a new vault goes through the same migrations
+/// as an old one.
+const BASELINE: &str = "CREATE TABLE IF NOT EXISTS keys (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    password TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP
+)";
+
+/// Upgrades a database from the version before `version`
+struct Migration {
+    version: i64,
+    sql: &'static str,
+}
+
+/// Every migration, in order. A migration is never changed once released:
+/// change the schema with a new one.
+const MIGRATIONS: [Migration; 2] = [
+    // Logins need more than a name and a password, the URLs are one per line
+    Migration {
+        version: 1,
+        sql: "ALTER TABLE keys ADD COLUMN username TEXT NOT NULL DEFAULT '';
+            ALTER TABLE keys ADD COLUMN urls TEXT NOT NULL DEFAULT '';
+            ALTER TABLE keys ADD COLUMN notes TEXT NOT NULL DEFAULT '';",
+    },
+    // The passwords a key used to have, kept when it gets a new one
+    Migration {
+        version: 2,
+        sql: "CREATE TABLE passwords (
+                id TEXT PRIMARY KEY,
+                key_id TEXT NOT NULL REFERENCES keys (id),
+                password TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                updated_at DATETIME NOT NULL,
+                last_used_at DATETIME NOT NULL,
+                last_changed_at DATETIME NOT NULL
+            );
+            CREATE INDEX passwords_key_id ON passwords (key_id);",
+    },
+];
+
+/// Version of the schema written by this application, kept in the
+/// `user_version` of the database
+pub const SCHEMA_VERSION: i64 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Version of the schema of a database, 0 for the ones written before it was
+/// recorded
+pub fn user_version(co: &Connection) -> Result<i64> {
+    co.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Version of the schema of a serialized database, read from its header
+/// without loading it. `None` if it is not a database.
+pub fn stored_user_version(bytes: &[u8]) -> Option<i64> {
+    if !bytes.starts_with(b"SQLite format 3\0") {
+        return None;
+    }
+    let version = bytes.get(60..64)?;
+    Some(u32::from_be_bytes(version.try_into().ok()?) as i64)
+}
+
+/// Bring a database, empty or written by an older version, to
+/// [`SCHEMA_VERSION`], keeping all its data.
+///
+/// The migrations it misses run in a single transaction: if one fails, the
+/// database is left as it was. A schema more recent than this application is
+/// refused, it could not be written without losing data.
+pub fn migrate(co: &Connection) -> Result<()> {
+    let version = user_version(co)?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CANTOPEN),
+            Some(newer_schema_message(version)),
+        ));
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let tx = co.unchecked_transaction()?;
+    if version == 0 {
+        tx.execute_batch(BASELINE)?;
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+    }
+    tx.commit()
+}
+
+/// Why a database of schema `version` cannot be opened
+pub fn newer_schema_message(version: i64) -> String {
+    format!(
+        "The schema version {version} is more recent than {SCHEMA_VERSION}, upgrade the \
+         application"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::key::Key;
+    use crate::models::password::Password;
+    use crate::repository::{
+        check_database, dump_database, init_connection, init_database_schemas, load_database,
+    };
+
+    /// A database as each past version of the schema wrote it, with a key
+    /// using every column of its time
+    const FIXTURES: [(i64, &str); 2] = [
+        (
+            0,
+            "CREATE TABLE keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                password TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO keys (id, name, password, created_at, updated_at, last_used_at, last_changed_at)
+            VALUES (
+                X'67E5504410B1426F9247BB680E5FE0C8', 'forge', 's3cr3t',
+                '2023-06-01T10:00:00+00:00', '2023-06-02T10:00:00+00:00',
+                '2023-06-03 10:00:00', '2023-06-04 10:00:00'
+            );",
+        ),
+        (
+            1,
+            "CREATE TABLE keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                password TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                username TEXT NOT NULL DEFAULT '',
+                urls TEXT NOT NULL DEFAULT '',
+                notes TEXT NOT NULL DEFAULT ''
+            );
+            INSERT INTO keys (id, name, password, created_at, updated_at, last_used_at, last_changed_at, username, urls, notes)
+            VALUES (
+                X'67E5504410B1426F9247BB680E5FE0C8', 'forge', 's3cr3t',
+                '2023-06-01T10:00:00+00:00', '2023-06-02T10:00:00+00:00',
+                '2023-06-03 10:00:00', '2023-06-04 10:00:00',
+                'alice', 'https://git.example.com', 'Second factor on the phone'
+            );",
+        ),
+    ];
+
+    fn fixture(sql: &str) -> Connection {
+        let co = init_connection().unwrap();
+        co.execute_batch(sql).unwrap();
+        co
+    }
+
+    /// Name, type, default and constraints of every column of every table
+    fn schema(co: &Connection) -> Vec<(String, String, String, bool, Option<String>)> {
+        let mut stmt = co
+            .prepare(
+                "SELECT m.name, c.name, c.type, c.\"notnull\", c.dflt_value
+                FROM sqlite_master m, pragma_table_info(m.name) c
+                WHERE m.type = 'table' ORDER BY m.name, c.cid",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn should_have_a_fixture_for_every_past_version() {
+        let versions = FIXTURES.iter().map(|(version, _)| *version);
+        assert!(versions.eq(0..SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn should_upgrade_every_past_version() {
+        let current = schema(&init_database_schemas().unwrap());
+
+        for (version, sql) in FIXTURES {
+            let old = fixture(sql);
+            old.pragma_update(None, "user_version", version).unwrap();
+            let bytes = dump_database(&old).unwrap();
+            assert_eq!(stored_user_version(&bytes), Some(version));
+
+            let co = load_database(&bytes).unwrap();
+            assert_eq!(user_version(&co).unwrap(), SCHEMA_VERSION);
+            assert_eq!(schema(&co), current, "upgraded from version {version}");
+            assert!(check_database(&bytes).unwrap().is_empty());
+
+            let keys = Key::retrive_keys_from_db(&co).unwrap();
+            assert_eq!(keys.len(), 1);
+            assert_eq!(
+                keys[0].id().to_string(),
+                "67e55044-10b1-426f-9247-bb680e5fe0c8"
+            );
+            assert_eq!(keys[0].name(), "forge");
+            assert_eq!(keys[0].password(), "s3cr3t");
+            assert_eq!(keys[0].created_at(), "2023-06-01T10:00:00+00:00");
+            assert_eq!(keys[0].last_changed_at(), "2023-06-04T10:00:00+00:00");
+            assert!(Password::retrive_history_from_db(&co, keys[0].id())
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn should_create_new_databases_at_the_current_version() {
+        let co = init_database_schemas().unwrap();
+        assert_eq!(user_version(&co).unwrap(), SCHEMA_VERSION);
+
+        // Loading it again changes nothing
+        let bytes = dump_database(&co).unwrap();
+        let co = load_database(&bytes).unwrap();
+        assert_eq!(*dump_database(&co).unwrap(), *bytes);
+    }
+
+    #[test]
+    fn should_refuse_a_newer_schema() {
+        let co = init_database_schemas().unwrap();
+        co.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        let bytes = dump_database(&co).unwrap();
+
+        let err = load_database(&bytes).err().unwrap();
+        assert_eq!(err.to_string(), newer_schema_message(SCHEMA_VERSION + 1));
+        assert_eq!(
+            check_database(&bytes).unwrap(),
+            [newer_schema_message(SCHEMA_VERSION + 1)]
+        );
+    }
+
+    #[test]
+    fn should_leave_the_database_as_it_was_when_a_migration_fails() {
+        // The column the first migration adds is already there
+        let co = fixture(&FIXTURES[0].1.replace(
+            "password TEXT NOT NULL,",
+            "password TEXT NOT NULL, username TEXT,",
+        ));
+        let before = schema(&co);
+
+        assert!(migrate(&co).is_err());
+        assert_eq!(user_version(&co).unwrap(), 0);
+        assert_eq!(schema(&co), before);
+        let count: i64 = co
+            .query_row("SELECT COUNT(*) FROM keys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -1,41 +1,281 @@
-use rusqlite::{Connection, Result};
+use std::ptr::NonNull;
 
-pub fn init_connection(path: &str) -> Result<Connection> {
-    let co = Connection::open(path)?;
+use chrono::{DateTime, Utc};
+use rusqlite::serialize::{Data, OwnedData};
+use rusqlite::types::Value;
+use rusqlite::{ffi, Connection, DatabaseName, Error, Result};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+pub use self::backend::{Backend, KeyRepository, MemoryKeyRepository, SqliteKeyRepository};
+use self::migrations::{migrate, newer_schema_message, user_version};
+pub use self::migrations::{stored_user_version, SCHEMA_VERSION};
+use crate::crypto::memory;
+
+mod allocator;
+mod backend;
+mod migrations;
+
+/// Columns of the keys table
+const KEY_COLUMNS: [&str; 10] = [
+    "id",
+    "name",
+    "password",
+    "created_at",
+    "updated_at",
+    "last_used_at",
+    "last_changed_at",
+    "username",
+    "urls",
+    "notes",
+];
+/// Columns of the keys table holding text, the password aside
+const TEXT_COLUMNS: [&str; 4] = ["name", "username", "urls", "notes"];
+/// Columns of the keys table holding a date
+const DATE_COLUMNS: [&str; 4] = [
+    "created_at",
+    "updated_at",
+    "last_used_at",
+    "last_changed_at",
+];
+
+/// Open an empty database living only in memory
+pub fn init_connection() -> Result<Connection> {
+    allocator::install();
+    let co = Connection::open_in_memory()?;
+    // Temporary tables and indices must not reach the disk either
+    co.pragma_update(None, "temp_store", "MEMORY")?;
+    Ok(co)
+}
+
+/// Create the in-memory database of a new vault
+pub fn init_database_schemas() -> Result<Connection> {
+    let co = init_connection()?;
+    migrate(&co)?;
     Ok(co)
 }
 
-pub fn init_database_schemas(path: &str) -> Result<Connection> {
-    let co = init_connection(path)?;
-    init_databases(&co)?;
+/// Load a serialized database in memory, without touching the filesystem
+/// ```
+/// use rust_password_manager::models::key::Key;
+/// use rust_password_manager::repository::{
+///     dump_database, init_connection, init_database_schemas, load_database,
+/// };
+///
+/// let co = init_database_schemas().unwrap();
+/// co.execute("INSERT INTO keys (id, name, password) VALUES ('1', 'name', 'secret')", [])
+///     .unwrap();
+///
+/// let co = load_database(&dump_database(&co).unwrap()).unwrap();
+/// let name: String = co
+///     .query_row("SELECT name FROM keys WHERE id = '1'", [], |row| row.get(0))
+///     .unwrap();
+/// assert_eq!(name, "name");
+///
+/// // A database written before the usernames existed is upgraded, and keeps
+/// // its keys
+/// let old = init_connection().unwrap();
+/// old.execute_batch(
+///     "CREATE TABLE keys (id TEXT PRIMARY KEY, name TEXT NOT NULL, password TEXT NOT NULL,
+///         created_at DATETIME DEFAULT CURRENT_TIMESTAMP, updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+///         last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP, last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP);
+///     INSERT INTO keys (id, name, password) VALUES (randomblob(16), 'old', 'secret');",
+/// )
+/// .unwrap();
+/// let co = load_database(&dump_database(&old).unwrap()).unwrap();
+/// let keys = Key::retrive_keys_from_db(&co).unwrap();
+/// assert_eq!((keys[0].name(), keys[0].password()), ("old", "secret"));
+/// assert_eq!(keys[0].username(), "");
+/// assert!(keys[0].urls().is_empty());
+/// ```
+pub fn load_database(bytes: &[u8]) -> Result<Connection> {
+    let co = deserialize(bytes)?;
+    migrate(&co)?;
+    lock_database(&co)?;
     Ok(co)
 }
 
-fn init_databases(co: &Connection) -> Result<()> {
-    co.execute(
-        "CREATE TABLE IF NOT EXISTS keys (
-            id TEXT PRIMARY KEY,
-            name TEXT NOT NULL,
-            password TEXT NOT NULL,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP
+/// Look for what would make a serialized database unusable, without changing
+/// it: a damaged file, a schema from a more recent version or that cannot be
+/// upgraded, duplicate ids, or values the keys cannot be read from.
+///
+/// Returns the problems found, the passwords are never part of them.
+/// ```
+/// use rust_password_manager::models::key::Key;
+/// use rust_password_manager::repository::{check_database, dump_database, init_database_schemas};
+///
+/// let co = init_database_schemas().unwrap();
+/// Key::new(None, "name".to_string()).persist(&co).unwrap();
+/// assert!(check_database(&dump_database(&co).unwrap()).unwrap().is_empty());
+///
+/// co.execute("UPDATE keys SET last_used_at = 'yesterday'", []).unwrap();
+/// let problems = check_database(&dump_database(&co).unwrap()).unwrap();
+/// assert_eq!(problems.len(), 1);
+/// assert!(problems[0].contains("last_used_at"));
+///
+/// assert!(check_database(b"not a database").is_err());
+/// ```
+pub fn check_database(bytes: &[u8]) -> Result<Vec<String>> {
+    let co = deserialize(bytes)?;
+    let mut problems = Vec::new();
+
+    let mut stmt = co.prepare("PRAGMA integrity_check")?;
+    let damages = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    problems.extend(
+        damages
+            .into_iter()
+            .filter(|damage| damage != "ok")
+            .map(|damage| format!("The database is damaged: {damage}")),
+    );
+
+    let version = user_version(&co)?;
+    if version > SCHEMA_VERSION {
+        problems.push(newer_schema_message(version));
+        return Ok(problems);
+    }
+
+    let mut stmt = co.prepare("SELECT name FROM pragma_table_info('keys')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    if columns.is_empty() {
+        problems.push("The keys table is missing".to_string());
+        return Ok(problems);
+    }
+
+    // Only the copy in memory is upgraded, the way it is once loaded
+    if let Err(err) = migrate(&co) {
+        problems.push(format!(
+            "The schema version {version} cannot be upgraded to {SCHEMA_VERSION}: {err}"
+        ));
+        return Ok(problems);
+    }
+    let mut stmt = co.prepare("SELECT name FROM pragma_table_info('keys')")?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    let missing = KEY_COLUMNS
+        .iter()
+        .filter(|column| !columns.iter().any(|name| name == *column))
+        .copied()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        problems.push(format!(
+            "The keys table has no column {}",
+            missing.join(", ")
+        ));
+        return Ok(problems);
+    }
+
+    let mut stmt = co.prepare("SELECT id, COUNT(*) FROM keys GROUP BY id HAVING COUNT(*) > 1")?;
+    let duplicates = stmt.query_map([], |row| {
+        Ok(format!(
+            "{} keys share the id {}",
+            row.get::<_, i64>(1)?,
+            describe(row.get(0)?)
+        ))
+    })?;
+    for duplicate in duplicates {
+        problems.push(duplicate?);
+    }
 
-)",
+    let mut stmt = co.prepare("SELECT rowid, * FROM keys")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let key = match row.get::<_, Uuid>("id") {
+            Ok(id) => format!("The key {id}"),
+            Err(_) => {
+                let rowid: i64 = row.get("rowid")?;
+                problems.push(format!(
+                    "The key in row {rowid} has an invalid id {}",
+                    describe(row.get("id")?)
+                ));
+                format!("The key in row {rowid}")
+            }
+        };
+        for column in TEXT_COLUMNS {
+            if row.get::<_, String>(column).is_err() {
+                problems.push(format!(
+                    "{key} has an invalid {column} {}",
+                    describe(row.get(column)?)
+                ));
+            }
+        }
+        // Only its presence is checked, it is never printed
+        if row.get::<_, String>("password").is_err() {
+            problems.push(format!("{key} has an unreadable password"));
+        }
+        for column in DATE_COLUMNS {
+            if row.get::<_, DateTime<Utc>>(column).is_err() {
+                problems.push(format!(
+                    "{key} has an invalid {column} {}",
+                    describe(row.get(column)?)
+                ));
+            }
+        }
+    }
+
+    let orphans: i64 = co.query_row(
+        "SELECT COUNT(*) FROM passwords WHERE key_id NOT IN (SELECT id FROM keys)",
         [],
+        |row| row.get(0),
     )?;
+    if orphans > 0 {
+        problems.push(format!("{orphans} previous password(s) belong to no key"));
+    }
+
+    Ok(problems)
+}
+
+/// A value read from the database, as shown in a report
+fn describe(value: Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(value) => value.to_string(),
+        Value::Real(value) => value.to_string(),
+        Value::Text(value) => format!("{value:?}"),
+        Value::Blob(value) => format!("blob of {} bytes", value.len()),
+    }
+}
+
+/// Load a serialized database as it is, the schemas are left untouched
+fn deserialize(bytes: &[u8]) -> Result<Connection> {
+    let mut co = init_connection()?;
+
+    // SQLite takes ownership of the buffer, so it has to come from its allocator
+    let ptr = unsafe { ffi::sqlite3_malloc64(bytes.len() as u64) };
+    let ptr = NonNull::new(ptr.cast::<u8>()).ok_or(Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_NOMEM),
+        Some("Cannot allocate the database".to_string()),
+    ))?;
+    let data = unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr.as_ptr(), bytes.len());
+        OwnedData::from_raw_nonnull(ptr, bytes.len())
+    };
+    co.deserialize(DatabaseName::Main, data, false)?;
+    Ok(co)
+}
+
+/// Serialize the whole database, ready to be encrypted.
+///
+/// The copy is kept out of the swap, and wiped from memory once dropped.
+pub fn dump_database(co: &Connection) -> Result<Zeroizing<Vec<u8>>> {
+    let bytes = Zeroizing::new(co.serialize(DatabaseName::Main)?.to_vec());
+    memory::lock(&bytes);
+    lock_database(co)?;
+    Ok(bytes)
+}
 
-    //     co.execute(
-    //         "CREATE TABLE IF NOT EXISTS passwords (
-    //             id TEXT PRIMARY KEY,
-    //             password TEXT NOT NULL,
-    //             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-    //             updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-    //             last_used_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-    //             last_changed_at DATETIME DEFAULT CURRENT_TIMESTAMP
-    // )",
-    //         [],
-    //     )?;
+/// Keep the buffer holding a loaded database out of the swap, again after
+/// each change since it moves when it grows. The page cache of SQLite is
+/// locked by its allocator.
+fn lock_database(co: &Connection) -> Result<()> {
+    // Otherwise it is only a copy
+    let data = co.serialize(DatabaseName::Main)?;
+    if matches!(data, Data::Shared(_)) {
+        memory::lock(&data);
+    }
     Ok(())
 }
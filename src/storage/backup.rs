@@ -0,0 +1,113 @@
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::{rename_synced, sync_parent_dir, write_synced};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// A previous generation of the encrypted vault
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Backup {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let created_at = NaiveDateTime::parse_from_str(name, TIMESTAMP_FORMAT)
+            .ok()?
+            .and_utc();
+        Some(Self { path, created_at })
+    }
+}
+
+impl Display for Backup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.created_at.format("%Y-%m-%d %H:%M:%S%.3f UTC"))
+    }
+}
+
+/// Directory holding the backups of `vault`
+pub fn backup_dir(vault: impl AsRef<Path>) -> PathBuf {
+    let mut dir = vault.as_ref().as_os_str().to_owned();
+    dir.push(".backups");
+    PathBuf::from(dir)
+}
+
+/// All the backups of `vault`, the most recent first
+pub fn list_backups(vault: impl AsRef<Path>) -> io::Result<Vec<Backup>> {
+    let dir = backup_dir(vault);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Backup::from_path(entry.path()))
+        .collect::<Vec<_>>();
+    backups.sort_by_key(|backup| Reverse(backup.created_at));
+    Ok(backups)
+}
+
+/// Keep the current generation of `vault` as a timestamped backup, and only
+/// keep the `keep` most recent backups
+/// ```
+/// use rust_password_manager::storage::backup::{backup_vault, list_backups};
+///
+/// let dir = std::env::temp_dir().join(format!("rpm-backup-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let vault = dir.join("keys.db.encrypt");
+///
+/// for generation in 0..4 {
+///     std::fs::write(&vault, [generation]).unwrap();
+///     backup_vault(&vault, 2).unwrap();
+/// }
+///
+/// let backups = list_backups(&vault).unwrap();
+/// assert_eq!(backups.len(), 2);
+/// assert_eq!(std::fs::read(&backups[0].path).unwrap(), [3]);
+/// std::fs::remove_dir_all(dir).unwrap();
+/// ```
+pub fn backup_vault(vault: impl AsRef<Path>, keep: usize) -> io::Result<Option<Backup>> {
+    let vault = vault.as_ref();
+    if !vault.exists() || keep == 0 {
+        return Ok(None);
+    }
+
+    let dir = backup_dir(vault);
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(Utc::now().format(TIMESTAMP_FORMAT).to_string());
+    // The vault is replaced by a rename, so the backup can share its content
+    if fs::hard_link(vault, &path).is_err() {
+        write_synced(&path, &fs::read(vault)?)?;
+    }
+    sync_parent_dir(&path)?;
+
+    for backup in list_backups(vault)?.iter().skip(keep) {
+        fs::remove_file(&backup.path)?;
+    }
+
+    Ok(Backup::from_path(path))
+}
+
+/// Replace `vault` with `backup`.
+///
+/// The current generation is backed up first, so a restore can be undone.
+pub fn restore_backup(vault: impl AsRef<Path>, backup: &Backup, keep: usize) -> io::Result<()> {
+    let vault = vault.as_ref();
+    let contents = fs::read(&backup.path)?;
+
+    backup_vault(vault, keep.max(1))?;
+
+    let mut tmp = vault.as_os_str().to_owned();
+    tmp.push(".tmp");
+    write_synced(&tmp, &contents)?;
+    rename_synced(&tmp, vault)
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::anyhow;
+
+/// Advisory lock held on a vault for as long as it is opened.
+///
+/// The lock is a file next to the vault holding the PID of its owner. On
+/// Unix, the owner also holds an exclusive `flock` on it, which the kernel
+/// releases when the process dies: taking over a lock left behind is as
+/// atomic as taking a new one.
+#[derive(Debug)]
+pub struct VaultLock {
+    path: PathBuf,
+    /// Kept opened, the `flock` lives as long as it
+    #[allow(dead_code)]
+    file: File,
+}
+
+impl VaultLock {
+    /// Take the lock on `vault`, or fail if another running process holds it
+    /// ```
+    /// use rust_password_manager::storage::lock::VaultLock;
+    ///
+    /// let vault = std::env::temp_dir().join(format!("rpm-lock-{}", std::process::id()));
+    /// let lock = VaultLock::acquire(&vault).unwrap();
+    /// assert!(VaultLock::acquire(&vault).is_err());
+    ///
+    /// drop(lock);
+    /// assert!(VaultLock::acquire(&vault).is_ok());
+    /// ```
+    pub fn acquire(vault: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = lock_path(vault);
+
+        // The previous owner may remove the file between our open and our
+        // lock, leaving us alone on a file nobody else sees
+        for _ in 0..3 {
+            let Some(mut file) = try_lock(&path)? else {
+                return Err(already_opened(&path));
+            };
+            if !is_same_file(&file, &path)? {
+                continue;
+            }
+
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(process::id().to_string().as_bytes())?;
+            file.sync_all()?;
+            return Ok(Self { path, file });
+        }
+
+        Err(anyhow!("Cannot take the lock {}", path.display()))
+    }
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        // Still locked: whoever opened the file meanwhile sees it is gone
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Lock file of `vault`
+pub fn lock_path(vault: impl AsRef<Path>) -> PathBuf {
+    let mut path = vault.as_ref().as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Open the lock file, created if needed and only readable by its owner,
+/// and lock it without waiting. `None` if another process holds it.
+fn try_lock(path: &Path) -> io::Result<Option<File>> {
+    #[cfg(unix)]
+    {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res == 0 {
+            return Ok(Some(file));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(None),
+            _ => Err(err),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // Without a way to check its owner is alive, a lock is never taken
+        // over: the error tells how to remove it
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Check `path` still names the file opened
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        let opened = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) => Ok(current.dev() == opened.dev() && current.ino() == opened.ino()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (file, path);
+        Ok(true)
+    }
+}
+
+/// The error of a lock held by another process
+fn already_opened(path: &Path) -> anyhow::Error {
+    let owner = match read_pid(path) {
+        Some(pid) => format!("process {pid}"),
+        None => "another process".to_string(),
+    };
+    // The kernel releases the flock of a dead owner, elsewhere its file stays
+    if cfg!(unix) {
+        anyhow!("The vault is already opened by {owner}")
+    } else {
+        anyhow!(
+            "The vault is already opened by {owner}. If it is not running anymore, the lock {} can be deleted safely",
+            path.display()
+        )
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn should_let_only_one_takeover_of_a_stale_lock_win() {
+        let vault = std::env::temp_dir().join(format!("rpm-lock-race-{}", process::id()));
+        let path = lock_path(&vault);
+
+        for _ in 0..50 {
+            // Left by a process that died without removing it
+            fs::write(&path, "4194305").unwrap();
+
+            let barrier = Arc::new(Barrier::new(4));
+            let takeovers = (0..4)
+                .map(|_| {
+                    let (barrier, vault) = (barrier.clone(), vault.clone());
+                    thread::spawn(move || {
+                        barrier.wait();
+                        VaultLock::acquire(vault)
+                    })
+                })
+                .collect::<Vec<_>>();
+            let locks = takeovers
+                .into_iter()
+                .map(|takeover| takeover.join().unwrap())
+                .collect::<Vec<_>>();
+
+            let taken = locks.iter().filter(|lock| lock.is_ok()).count();
+            assert_eq!(taken, 1, "{locks:?}");
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                process::id().to_string()
+            );
+        }
+        assert!(!path.exists(), "the lock is removed once released");
+    }
+}
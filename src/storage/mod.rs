@@ -0,0 +1,46 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub mod backup;
+pub mod lock;
+pub mod recovery;
+pub mod verify;
+
+/// Write `contents` to `path` and flush it to the disk before returning.
+///
+/// The file is only readable by its owner.
+pub fn write_synced(path: impl AsRef<Path>, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Atomically replace `dist` with `src`, and make the rename durable
+pub fn rename_synced(src: impl AsRef<Path>, dist: impl AsRef<Path>) -> io::Result<()> {
+    fs::rename(src, &dist)?;
+    sync_parent_dir(dist)
+}
+
+/// Flush the directory holding `path`, so a new entry survives a crash
+pub fn sync_parent_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.as_ref().parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
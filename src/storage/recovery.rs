@@ -0,0 +1,110 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use super::backup::list_backups;
+use super::sync_parent_dir;
+
+/// Inconsistent states a crash can leave the vault in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaultIssue {
+    /// A plaintext database was found, but no encrypted vault
+    LeftoverPlaintext,
+    /// A plaintext database was found next to the encrypted vault
+    StalePlaintext,
+    /// The encrypted vault is missing, but some of its backups were found
+    MissingVault,
+}
+
+impl VaultIssue {
+    /// Check if the issue involves a plaintext database
+    pub fn has_plaintext(&self) -> bool {
+        matches!(self, Self::LeftoverPlaintext | Self::StalePlaintext)
+    }
+}
+
+impl Display for VaultIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Self::LeftoverPlaintext => {
+                "A plaintext database has been left by a previous run, and no encrypted vault \
+                 was found. Its content is not protected by any password."
+            }
+            Self::StalePlaintext => {
+                "A plaintext database has been left next to the encrypted vault by a previous \
+                 run. Its content is not protected by any password."
+            }
+            Self::MissingVault => {
+                "The encrypted vault is missing, but backups of it were found. Starting a new \
+                 vault would hide them."
+            }
+        };
+        write!(f, "{str}")
+    }
+}
+
+/// Look for the states a crash can leave `vault` in, `plaintext` being where
+/// it used to be decrypted
+/// ```
+/// use rust_password_manager::storage::recovery::{detect_issue, quarantine, VaultIssue};
+///
+/// let dir = std::env::temp_dir().join(format!("rpm-recovery-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let (vault, plaintext) = (dir.join("keys.db.encrypt"), dir.join("keys.db"));
+///
+/// std::fs::write(&plaintext, b"SQLite format 3").unwrap();
+/// let issue = detect_issue(&vault, &plaintext).unwrap();
+/// assert_eq!(issue, Some(VaultIssue::LeftoverPlaintext));
+///
+/// let moved = quarantine(&plaintext).unwrap();
+/// assert!(moved.exists());
+/// assert_eq!(detect_issue(&vault, &plaintext).unwrap(), None);
+/// std::fs::remove_dir_all(dir).unwrap();
+/// ```
+pub fn detect_issue(
+    vault: impl AsRef<Path>,
+    plaintext: impl AsRef<Path>,
+) -> io::Result<Option<VaultIssue>> {
+    let vault = vault.as_ref();
+
+    let issue = if plaintext.as_ref().exists() {
+        if vault.exists() {
+            Some(VaultIssue::StalePlaintext)
+        } else {
+            Some(VaultIssue::LeftoverPlaintext)
+        }
+    } else if !vault.exists() && !list_backups(vault)?.is_empty() {
+        Some(VaultIssue::MissingVault)
+    } else {
+        None
+    };
+
+    Ok(issue)
+}
+
+/// Move a plaintext database aside, so it is never mistaken for a vault again.
+///
+/// Returns where the file has been moved to.
+pub fn quarantine(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+
+    let mut dist = path.as_os_str().to_owned();
+    dist.push(format!(
+        ".{}.quarantine",
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    let dist = PathBuf::from(dist);
+
+    fs::rename(path, &dist)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dist, fs::Permissions::from_mode(0o600))?;
+    }
+    sync_parent_dir(&dist)?;
+
+    Ok(dist)
+}
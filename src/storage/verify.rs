@@ -0,0 +1,179 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use zeroize::Zeroizing;
+
+use super::backup::list_backups;
+use crate::crypto::error::UnlockError;
+use crate::repository::check_database;
+
+/// Outcome of checking one generation of the vault
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// It decrypts, and holds a healthy database
+    Passed(String),
+    /// It cannot be opened with the keys at hand, so it has not been checked
+    Skipped(String),
+    /// It is damaged, or cannot be used by this version of the application
+    Problem(String),
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Passed(message) => write!(f, "✅ {message}"),
+            Self::Skipped(message) => write!(f, "⏭️ {message}"),
+            Self::Problem(message) => write!(f, "❌ {message}"),
+        }
+    }
+}
+
+/// What checking the vault and its backups found
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub findings: Vec<Finding>,
+}
+
+impl VerifyReport {
+    /// Number of problems found
+    pub fn problems(&self) -> usize {
+        self.count(|finding| matches!(finding, Finding::Problem(_)))
+    }
+
+    /// Number of backups that could not be opened
+    pub fn skipped(&self) -> usize {
+        self.count(|finding| matches!(finding, Finding::Skipped(_)))
+    }
+
+    pub fn has_problems(&self) -> bool {
+        self.problems() > 0
+    }
+
+    /// A line summing the report up
+    pub fn summary(&self) -> String {
+        match (self.problems(), self.skipped()) {
+            (0, 0) => "✅ No problem found".to_string(),
+            (0, skipped) => format!("✅ No problem found, {skipped} backup(s) skipped"),
+            (problems, _) => format!("❌ {problems} problem(s) found"),
+        }
+    }
+
+    fn count(&self, f: impl Fn(&Finding) -> bool) -> usize {
+        self.findings.iter().filter(|finding| f(finding)).count()
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "{finding}")?;
+        }
+        writeln!(f, "{}", self.summary())
+    }
+}
+
+/// Decrypt `vault` and each of its backups in memory with `open`, and check
+/// the databases they hold.
+///
+/// A backup `open` has no key for, for example one written before the master
+/// key changed, is skipped rather than reported as a problem. Nothing is
+/// written to the disk.
+/// ```
+/// use rust_password_manager::crypto::header::KdfParams;
+/// use rust_password_manager::crypto::slot::VaultKeys;
+/// use rust_password_manager::crypto::utils::{decrypt_small_file, encrypt_small_file};
+/// use rust_password_manager::repository::{dump_database, init_database_schemas};
+/// use rust_password_manager::storage::backup::backup_vault;
+/// use rust_password_manager::storage::verify::{verify_vault, Finding};
+///
+/// let dir = std::env::temp_dir().join(format!("rpm-verify-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+/// let vault = dir.join("keys.db.encrypt");
+/// let vault = vault.to_str().unwrap();
+/// let keys = VaultKeys::create("master", "secret", None, &KdfParams::pbkdf2(1000)).unwrap();
+/// let db = dump_database(&init_database_schemas().unwrap()).unwrap();
+///
+/// encrypt_small_file(b"not a database", vault, &keys).unwrap();
+/// backup_vault(vault, 2).unwrap();
+/// encrypt_small_file(&db, vault, &keys).unwrap();
+///
+/// let report = verify_vault(vault, |path| decrypt_small_file(path, keys.data_key()));
+/// assert!(matches!(report.findings[0], Finding::Passed(_)));
+/// assert!(matches!(report.findings[1], Finding::Problem(_)));
+/// assert_eq!(report.problems(), 1);
+///
+/// // Without the data key, nothing can be checked
+/// let report = verify_vault(vault, |path| decrypt_small_file(path, &[0u8; 32]));
+/// assert_eq!(report.skipped(), 1);
+/// assert!(report.has_problems());
+/// std::fs::remove_dir_all(dir).unwrap();
+/// ```
+pub fn verify_vault(
+    vault: &str,
+    mut open: impl FnMut(&str) -> Result<Zeroizing<Vec<u8>>, UnlockError>,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+
+    if Path::new(vault).exists() {
+        match open(vault) {
+            Ok(plaintext) => check(&mut report, &format!("Vault {vault}"), &plaintext),
+            Err(err) => report
+                .findings
+                .push(Finding::Problem(format!("Vault {vault}: {err}"))),
+        }
+    } else {
+        report
+            .findings
+            .push(Finding::Problem(format!("No vault found at {vault}")));
+    }
+
+    let backups = match list_backups(vault) {
+        Ok(backups) => backups,
+        Err(err) => {
+            report
+                .findings
+                .push(Finding::Problem(format!("Cannot list the backups: {err}")));
+            return report;
+        }
+    };
+    for backup in backups {
+        let name = format!("Backup of {backup}");
+        match open(&backup.path.to_string_lossy()) {
+            Ok(plaintext) => check(&mut report, &name, &plaintext),
+            Err(err) if can_open_otherwise(&err) => report
+                .findings
+                .push(Finding::Skipped(format!("{name}: {err}"))),
+            Err(err) => report
+                .findings
+                .push(Finding::Problem(format!("{name}: {err}"))),
+        }
+    }
+
+    report
+}
+
+/// Check the database decrypted from the generation called `name`
+fn check(report: &mut VerifyReport, name: &str, plaintext: &[u8]) {
+    match check_database(plaintext) {
+        Ok(problems) if problems.is_empty() => report.findings.push(Finding::Passed(format!(
+            "{name}: decrypted, database healthy"
+        ))),
+        Ok(problems) => report.findings.extend(
+            problems
+                .into_iter()
+                .map(|problem| Finding::Problem(format!("{name}: {problem}"))),
+        ),
+        Err(err) => report.findings.push(Finding::Problem(format!(
+            "{name}: decrypted, but not a readable database: {err}"
+        ))),
+    }
+}
+
+/// The generation may open with another master key, keyfile or identity
+fn can_open_otherwise(err: &UnlockError) -> bool {
+    err.is_wrong_password()
+        || matches!(
+            err,
+            UnlockError::KeyfileRequired | UnlockError::NotRecipient
+        )
+}